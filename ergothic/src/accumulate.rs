@@ -6,11 +6,226 @@
 /// performance. It is expected that updating `Acc`s is not on the critical path
 /// of the simulation. For Quantum Field Theory on the lattice in 4 spacetime
 /// dimensions that is usually the case.
+/// Samples drawn from Markov chains are correlated, which makes the naive
+/// statistical error underestimated. `Acc` therefore also runs a binning
+/// analysis: the samples are grouped into bins of 2, 4, 8, etc. consecutive
+/// samples, and the error is estimated from the bin means at each level. Once
+/// bins become longer than the autocorrelation time, the estimates reach a
+/// plateau, which is the honest statistical error.
+/// The variance is accumulated as the sum of squared deviations from the mean
+/// (Welford's algorithm), and merged with the parallel formula of Chan et al.,
+/// which stays accurate when the variance is tiny compared to the square of
+/// the mean.
+/// Samples may carry weights, e.g. for reweighting to nearby couplings, see
+/// `consume_weighted`. The errors of the weighted mean are then based on the
+/// effective sample size `(sum w)^2 / sum w^2`.
 #[derive(Clone, Deserialize, Serialize)]
+#[serde(from = "AccRepr")]
 pub struct Acc {
+  #[serde(flatten)]
+  stats: Stats,
+  binning: Binning,
+}
+
+/// Serialized form of `Acc`. Data points exported before the switch to
+/// Welford's algorithm have the mean square `mean2` instead of `m2`, and the
+/// ones exported before weighted samples have no weights, see `StatsRepr`.
+#[derive(Deserialize)]
+struct AccRepr {
+  #[serde(flatten)]
+  stats: Stats,
+  #[serde(default)]
+  binning: Binning,
+}
+
+impl From<AccRepr> for Acc {
+  fn from(repr: AccRepr) -> Acc {
+    Acc {
+      stats: repr.stats,
+      binning: repr.binning,
+    }
+  }
+}
+
+/// Minimum number of bins at a binning level for its error estimate to be
+/// trusted.
+const MIN_BINS: f64 = 32.0;
+
+/// Maximum number of binning levels. Bins at the last level contain 2^48
+/// samples, which is never reached in practice.
+const MAX_BINNING_LEVELS: usize = 48;
+
+/// Online state of the binning analysis. Only the statistics of bin means are
+/// kept at each level, so the memory footprint is logarithmic in the number of
+/// samples.
+#[derive(Clone, Default, Deserialize, Serialize)]
+struct Binning {
+  /// The first half of a bin being formed at each level, waiting for its pair.
+  /// Level 0 corresponds to the samples themselves.
+  pending: Vec<Option<f64>>,
+
+  /// Weights of the `pending` half-bins. Missing weights are 1.
+  #[serde(default)]
+  pending_weights: Vec<f64>,
+
+  /// Statistics of bin means at levels 1, 2, etc. Bins at level `k` contain
+  /// 2^k consecutive samples, and weigh as much as their samples on average.
+  levels: Vec<Stats>,
+}
+
+/// Running statistics of weighted values: the number of values, the sums of
+/// the weights and their squares, the weighted mean and the weighted sum of
+/// squared deviations from the mean `m2`. Updated with Welford's algorithm and
+/// merged with the formula of Chan et al. Unweighted values have weight 1.
+#[derive(Clone, Default, Deserialize, Serialize)]
+#[serde(from = "StatsRepr")]
+struct Stats {
   count: f64,
+  weight: f64,
+  weight2: f64,
   mean: f64,
-  mean2: f64,
+  m2: f64,
+}
+
+/// Serialized form of `Stats`. Missing weights are those of unweighted values,
+/// and a legacy mean square `mean2` is converted to `m2`.
+#[derive(Deserialize)]
+struct StatsRepr {
+  count: f64,
+  mean: f64,
+  #[serde(default)]
+  weight: Option<f64>,
+  #[serde(default)]
+  weight2: Option<f64>,
+  #[serde(default)]
+  m2: Option<f64>,
+  #[serde(default)]
+  mean2: Option<f64>,
+}
+
+impl From<StatsRepr> for Stats {
+  fn from(repr: StatsRepr) -> Stats {
+    let m2 = match (repr.m2, repr.mean2) {
+      (Some(m2), _) => m2,
+      (None, Some(mean2)) => ((mean2 - repr.mean.powi(2)) * repr.count).max(0.0),
+      (None, None) => 0.0,
+    };
+    Stats {
+      count: repr.count,
+      weight: repr.weight.unwrap_or(repr.count),
+      weight2: repr.weight2.unwrap_or(repr.count),
+      mean: repr.mean,
+      m2,
+    }
+  }
+}
+
+impl Stats {
+  fn consume(&mut self, value: f64, weight: f64) {
+    self.count += 1.0;
+    self.weight += weight;
+    self.weight2 += weight.powi(2);
+    let delta = value - self.mean;
+    self.mean += delta * (weight / self.weight);
+    self.m2 += weight * delta * (value - self.mean);
+  }
+
+  fn merge(&mut self, other: &Stats) {
+    if other.weight == 0.0 {
+      // Nothing to merge. Also avoids dividing by zero for empty `self`.
+      return;
+    }
+    let total_weight = self.weight + other.weight;
+    let delta = other.mean - self.mean;
+    self.mean += delta * (other.weight / total_weight);
+    self.m2 += other.m2 + delta.powi(2) * (self.weight * other.weight / total_weight);
+    self.count += other.count;
+    self.weight = total_weight;
+    self.weight2 += other.weight2;
+  }
+
+  /// Gives the effective number of independent values `(sum w)^2 / sum w^2`.
+  /// Equals the number of values if they are unweighted.
+  fn effective_count(&self) -> f64 {
+    self.weight.powi(2) / self.weight2
+  }
+
+  /// Statistical error of the mean, assuming independent values.
+  fn error_of_mean(&self) -> f64 {
+    (self.m2 / (self.weight * (self.effective_count() - 1.0))).sqrt()
+  }
+}
+
+impl Binning {
+  fn consume(&mut self, value: f64, weight: f64) {
+    let mut value = value;
+    let mut weight = weight;
+    for level in 0..MAX_BINNING_LEVELS {
+      if level > 0 {
+        if self.levels.len() < level {
+          self.levels.push(Stats::default());
+        }
+        // Unweighted bins weigh 1 at every level.
+        let bin_size = (1u64 << level) as f64;
+        self.levels[level - 1].consume(value, weight / bin_size);
+      }
+      if self.pending.len() <= level {
+        self.pending.push(None);
+      }
+      if self.pending_weights.len() < self.pending.len() {
+        self.pending_weights.resize(self.pending.len(), 1.0);
+      }
+      match self.pending[level].take() {
+        Some(first) => {
+          let first_weight = self.pending_weights[level];
+          let total_weight = first_weight + weight;
+          value = (first * first_weight + value * weight) / total_weight;
+          weight = total_weight;
+        }
+        None => {
+          self.pending[level] = Some(value);
+          self.pending_weights[level] = weight;
+          return;
+        }
+      }
+    }
+  }
+
+  /// Finds the plateau of the error estimates, starting from the error
+  /// estimate `naive` for bins of one sample. See `Acc::binned_uncertainty`.
+  fn plateau(&self, naive: f64) -> f64 {
+    let mut res = naive;
+    for level in self.levels.iter() {
+      if level.count < MIN_BINS {
+        break;
+      }
+      let next = level.error_of_mean();
+      // Relative standard deviation of the error estimate from `count` bins.
+      let noise = 1.0 / (2.0 * (level.effective_count() - 1.0)).sqrt();
+      if next <= res * (1.0 + noise) {
+        break;
+      }
+      res = next;
+    }
+    res
+  }
+
+  /// Merges the bin statistics of `other`. The incomplete bins of `other` are
+  /// dropped, since they are not adjacent to the incomplete bins of `self`.
+  fn merge(&mut self, other: &Binning) {
+    for (level, other_level) in other.levels.iter().enumerate() {
+      if self.levels.len() <= level {
+        self.levels.push(Stats::default());
+      }
+      self.levels[level].merge(other_level);
+    }
+  }
+}
+
+impl Default for Acc {
+  fn default() -> Acc {
+    Acc::new()
+  }
 }
 
 impl Acc {
@@ -18,31 +233,76 @@ impl Acc {
   /// uncertainty is NaN.
   pub fn new() -> Acc {
     Acc {
-      mean: 0.0,
-      count: 0.0,
-      mean2: 0.0,
+      stats: Stats::default(),
+      binning: Binning::default(),
     }
   }
   
   /// Gives the mean of previously consumed samples. It approximates the
   /// expectation value of the physical observable corresponding to the `Acc`.
+  /// For weighted samples, it is the weighted mean.
   pub fn value(&self) -> f64 {
-    self.mean
+    self.stats.mean
   }
 
   /// Gives the statistical error estimate based on the standard deviation and
   /// size of the distribution of consumed samples.
-  /// The statistical error is equal to the standard deviation divided by the
-  /// square root of the size of the distribution. The intuition for this
-  /// formula can be developed by considering the random walk problem.
+  /// The statistical error is equal to the (unbiased) standard deviation
+  /// divided by the square root of the size of the distribution. The intuition
+  /// for this formula can be developed by considering the random walk problem.
+  /// For weighted samples, the size is the effective sample size. Equals the
+  /// error at the first level of the binning analysis.
   pub fn uncertainty(&self) -> f64 {
-    ((self.mean2 - self.mean.powi(2)) / self.count).sqrt()
+    self.stats.error_of_mean()
+  }
+
+  /// Gives the statistical error estimates from the binning analysis as pairs
+  /// of (bin size, error). The first pair corresponds to bins of one sample,
+  /// which is the estimate for uncorrelated samples, see `uncertainty`.
+  pub fn binned_uncertainties(&self) -> Vec<(f64, f64)> {
+    let mut res = vec![(1.0, self.uncertainty())];
+    let mut bin_size = 1.0;
+    for level in self.binning.levels.iter() {
+      bin_size *= 2.0;
+      res.push((bin_size, level.error_of_mean()));
+    }
+    res
+  }
+
+  /// Gives the statistical error estimate taking the autocorrelations of
+  /// consumed samples into account. It is the plateau of the binning analysis:
+  /// the error at the first bin size, for which doubling the bin size doesn't
+  /// increase the error by more than the statistical noise of the estimate.
+  /// Only bin sizes with at least 32 bins are considered. If the plateau hasn't
+  /// been reached, gives the error at the largest of them, which is likely
+  /// still an underestimate.
+  pub fn binned_uncertainty(&self) -> f64 {
+    self.binning.plateau(self.uncertainty())
+  }
+
+  /// Gives the integrated autocorrelation time of consumed samples, measured
+  /// in samples. It equals 1/2 for uncorrelated samples, and grows as the
+  /// ratio of the binned and the naive variances of the mean.
+  pub fn autocorrelation_time(&self) -> f64 {
+    (self.binned_uncertainty() / self.uncertainty()).powi(2) / 2.0
   }
 
   /// Gives the number of recorded samples. Note that this function returns an
   /// `f64` due to the implementation specifics of `Acc`.
   pub fn num_of_samples(&self) -> f64 {
-    self.count
+    self.stats.count
+  }
+
+  /// Gives the sum of the weights of the consumed samples. Equals the number
+  /// of samples if they are unweighted.
+  pub fn total_weight(&self) -> f64 {
+    self.stats.weight
+  }
+
+  /// Gives the effective sample size `(sum w)^2 / sum w^2` of the weighted
+  /// samples. Equals the number of samples if they are unweighted.
+  pub fn effective_sample_size(&self) -> f64 {
+    self.stats.effective_count()
   }
 
   /// Consumes a sample value. This function should be called every time a new
@@ -54,25 +314,1412 @@ impl Acc {
   /// sample configurations is biased in any way, the `Arc` will not reproduce
   /// the correct expectation value.
   pub fn consume(&mut self, value: f64) {
-    if value.is_nan() {
+    self.consume_weighted(value, 1.0);
+  }
+
+  /// Consumes a sample value with a given `weight`, e.g. the reweighting
+  /// factor to a nearby coupling. The mean becomes the weighted mean, and the
+  /// errors are based on the effective sample size. Samples with NaN value,
+  /// or with a weight that is not positive and finite, are skipped: for sign
+  /// problems, accumulate the sign and the signed observable as separate
+  /// measures, and take their ratio with a derived measure.
+  pub fn consume_weighted(&mut self, value: f64, weight: f64) {
+    if value.is_nan() || !(weight > 0.0 && weight.is_finite()) {
       return;
     }
-    self.count += 1.0;
-    self.mean += (value - self.mean) / self.count;
-    self.mean2 += (value.powi(2) - self.mean2) / self.count;
+    self.stats.consume(value, weight);
+    self.binning.consume(value, weight);
   }
 
   /// Merges another `Acc` into this one. Semantically equivalent to calling
   /// `self.consume(..)` for each of the samples consumed previously by `other`.
   /// Destructs `other` upon completion.
-  pub fn merge(&mut self, mut other: Acc) {
+  pub fn merge(&mut self, other: Acc) {
+    self.stats.merge(&other.stats);
+    self.binning.merge(&other.binning);
+  }
+}
+
+/// A `CovAcc` (short for covariance accumulator) consumes samples of several
+/// observables at once, and keeps track of their means and the full covariance
+/// matrix. It is used for observables whose values are correlated with each
+/// other, e.g. the components of a correlator, so that the quantities derived
+/// from several of them get correct error bars.
+/// Each component also runs a binning analysis, see `Acc`.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct CovAcc {
+  count: f64,
+  mean: Vec<f64>,
+
+  /// Sums of the products of the deviations from the mean, `comoment[i][j] =
+  /// sum (x_i - mean_i) (x_j - mean_j)`.
+  comoment: Vec<Vec<f64>>,
+
+  #[serde(default)]
+  binning: Vec<Binning>,
+}
+
+impl CovAcc {
+  /// Constructs an empty `CovAcc` of observables with `len` components.
+  pub fn new(len: usize) -> CovAcc {
+    CovAcc {
+      count: 0.0,
+      mean: vec![0.0; len],
+      comoment: vec![vec![0.0; len]; len],
+      binning: vec![Binning::default(); len],
+    }
+  }
+
+  /// Gives the number of components.
+  pub fn len(&self) -> usize {
+    self.mean.len()
+  }
+
+  /// Tells whether there are no components.
+  pub fn is_empty(&self) -> bool {
+    self.mean.is_empty()
+  }
+
+  /// Gives the number of recorded samples. Note that this function returns an
+  /// `f64` for consistency with `Acc`.
+  pub fn num_of_samples(&self) -> f64 {
+    self.count
+  }
+
+  /// Gives the mean of the `i`-th component of previously consumed samples.
+  pub fn value(&self, i: usize) -> f64 {
+    self.mean[i]
+  }
+
+  /// Gives the means of all components.
+  pub fn values(&self) -> &[f64] {
+    &self.mean
+  }
+
+  /// Gives the sample covariance of the `i`-th and the `j`-th components.
+  pub fn covariance(&self, i: usize, j: usize) -> f64 {
+    self.comoment[i][j] / (self.count - 1.0)
+  }
+
+  /// Gives the covariance of the means of the `i`-th and the `j`-th
+  /// components, assuming independent samples. Its diagonal contains the
+  /// squared statistical errors.
+  pub fn covariance_of_mean(&self, i: usize, j: usize) -> f64 {
+    self.covariance(i, j) / self.count
+  }
+
+  /// Gives the covariance of the means of the `i`-th and the `j`-th
+  /// components, taking autocorrelations into account. Approximates the
+  /// correlation coefficients of the means by those of the samples, and scales
+  /// them with the binned uncertainties.
+  pub fn binned_covariance_of_mean(&self, i: usize, j: usize) -> f64 {
+    let naive = (self.covariance_of_mean(i, i) * self.covariance_of_mean(j, j)).sqrt();
+    self.covariance_of_mean(i, j) / naive
+      * self.binned_uncertainty(i) * self.binned_uncertainty(j)
+  }
+
+  /// Gives the statistical error of a function `f` of the means of all
+  /// components by linear error propagation, `sqrt(g^T C g)`, where `g` is the
+  /// `gradient` of `f` at the means and `C` is the covariance matrix of the
+  /// means, assuming independent samples.
+  pub fn propagated_uncertainty(&self, gradient: &[f64]) -> f64 {
+    self.propagate(gradient, CovAcc::covariance_of_mean)
+  }
+
+  /// Same as `propagated_uncertainty`, but takes autocorrelations into
+  /// account, see `binned_covariance_of_mean`.
+  pub fn binned_propagated_uncertainty(&self, gradient: &[f64]) -> f64 {
+    self.propagate(gradient, CovAcc::binned_covariance_of_mean)
+  }
+
+  fn propagate<F: Fn(&CovAcc, usize, usize) -> f64>(&self, gradient: &[f64], covariance: F)
+      -> f64 {
+    assert_eq!(gradient.len(), self.len(),
+               "CovAcc::propagate(..): expected {} components, got {}.",
+               self.len(), gradient.len());
+    let mut variance = 0.0;
+    for (i, g_i) in gradient.iter().enumerate() {
+      for (j, g_j) in gradient.iter().enumerate() {
+        if *g_i != 0.0 && *g_j != 0.0 {
+          variance += g_i * g_j * covariance(self, i, j);
+        }
+      }
+    }
+    variance.sqrt()
+  }
+
+  /// Gives the statistical error of the mean of the `i`-th component,
+  /// assuming independent samples.
+  pub fn uncertainty(&self, i: usize) -> f64 {
+    self.covariance_of_mean(i, i).sqrt()
+  }
+
+  /// Gives the statistical error of the mean of the `i`-th component from the
+  /// binning analysis. See `Acc::binned_uncertainty`.
+  pub fn binned_uncertainty(&self, i: usize) -> f64 {
+    self.binning[i].plateau(self.uncertainty(i))
+  }
+
+  /// Gives the integrated autocorrelation time of the `i`-th component,
+  /// measured in samples. See `Acc::autocorrelation_time`.
+  pub fn autocorrelation_time(&self, i: usize) -> f64 {
+    (self.binned_uncertainty(i) / self.uncertainty(i)).powi(2) / 2.0
+  }
+
+  /// Consumes a sample of all components at once. Samples with a NaN
+  /// component are skipped. Panics if the number of components doesn't match.
+  pub fn consume(&mut self, values: &[f64]) {
+    assert_eq!(values.len(), self.len(),
+               "CovAcc::consume(..): expected {} components, got {}.",
+               self.len(), values.len());
+    if values.iter().any(|value| value.is_nan()) {
+      return;
+    }
+    self.count += 1.0;
+    // Welford's algorithm: the deviations from the old and the new means.
+    let delta: Vec<f64> = values.iter().zip(self.mean.iter())
+        .map(|(value, mean)| value - mean).collect();
+    for (mean, delta) in self.mean.iter_mut().zip(delta.iter()) {
+      *mean += delta / self.count;
+    }
+    for (i, row) in self.comoment.iter_mut().enumerate() {
+      for (j, comoment) in row.iter_mut().enumerate() {
+        *comoment += delta[i] * (values[j] - self.mean[j]);
+      }
+    }
+    for (binning, value) in self.binning.iter_mut().zip(values.iter()) {
+      binning.consume(*value, 1.0);
+    }
+  }
+
+  /// Merges another `CovAcc` into this one. Semantically equivalent to
+  /// calling `self.consume(..)` for each of the samples consumed previously by
+  /// `other`. Fails without changing `self` if the number of components
+  /// doesn't match, e.g. for observables of lattices of different sizes.
+  pub fn merge(&mut self, other: &CovAcc) -> Result<(), String> {
+    if other.len() != self.len() {
+      return Err(format!("accumulators have different numbers of components, {} and {}.",
+                         self.len(), other.len()));
+    }
+    if other.count == 0.0 {
+      return Ok(());
+    }
     let total_count = self.count + other.count;
-    self.mean -= self.mean * (other.count / total_count);
-    other.mean -= other.mean * (self.count / total_count);
-    self.mean += other.mean;
-    self.mean2 -= self.mean2 * (other.count / total_count);
-    other.mean2 -= other.mean2 * (self.count / total_count);
-    self.mean2 += other.mean2;
+    let delta: Vec<f64> = other.mean.iter().zip(self.mean.iter())
+        .map(|(other_mean, mean)| other_mean - mean).collect();
+    let weight = self.count * other.count / total_count;
+    for (i, row) in self.comoment.iter_mut().enumerate() {
+      for (j, comoment) in row.iter_mut().enumerate() {
+        *comoment += other.comoment[i][j] + delta[i] * delta[j] * weight;
+      }
+    }
+    for (mean, delta) in self.mean.iter_mut().zip(delta.iter()) {
+      *mean += delta * (other.count / total_count);
+    }
     self.count = total_count;
+    if self.binning.len() < other.binning.len() {
+      self.binning.resize(other.binning.len(), Binning::default());
+    }
+    for (binning, other_binning) in self.binning.iter_mut().zip(other.binning.iter()) {
+      binning.merge(other_binning);
+    }
+    Ok(())
+  }
+}
+
+/// Largest count kept by `CounterAcc` and `RateAcc`. Counts saturate at
+/// `i64::MAX` rather than overflowing, since BSON stores signed integers.
+const MAX_COUNT: u64 = i64::MAX as u64;
+
+/// Adds two counts, saturating at `MAX_COUNT`.
+fn add_counts(a: u64, b: u64) -> u64 {
+  a.saturating_add(b).min(MAX_COUNT)
+}
+
+/// A `CounterAcc` counts integer quantities, e.g. the number of cluster flips
+/// per update. The number of samples and the total count are kept exactly, in
+/// `u64`, saturating at `i64::MAX` rather than overflowing. The mean count per
+/// sample and its errors are also accumulated in an `Acc`.
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct CounterAcc {
+  samples: u64,
+  total: u64,
+  acc: Acc,
+}
+
+impl CounterAcc {
+  /// Constructs an empty `CounterAcc`.
+  pub fn new() -> CounterAcc {
+    CounterAcc::default()
+  }
+
+  /// Gives the number of recorded samples.
+  pub fn num_of_samples(&self) -> u64 {
+    self.samples
+  }
+
+  /// Gives the total count over all samples.
+  pub fn total(&self) -> u64 {
+    self.total
+  }
+
+  /// Gives the mean count per sample.
+  pub fn mean(&self) -> f64 {
+    self.total as f64 / self.samples as f64
+  }
+
+  /// Gives the accumulator of the counts per sample, for the statistical
+  /// errors of the mean.
+  pub fn acc(&self) -> &Acc {
+    &self.acc
+  }
+
+  /// Gives the confidence interval of the mean count at a given
+  /// `confidence_level`, e.g. 0.95, based on its binned uncertainty.
+  pub fn confidence_interval(&self, confidence_level: f64) -> (f64, f64) {
+    let z = normal_quantile((1.0 + confidence_level) / 2.0);
+    let half_width = z * self.acc.binned_uncertainty();
+    (self.mean() - half_width, self.mean() + half_width)
+  }
+
+  /// Records the `count` of a sample.
+  pub fn consume(&mut self, count: u64) {
+    self.samples = add_counts(self.samples, 1);
+    self.total = add_counts(self.total, count);
+    self.acc.consume(count as f64);
+  }
+
+  /// Merges another `CounterAcc` into this one.
+  pub fn merge(&mut self, other: &CounterAcc) {
+    self.samples = add_counts(self.samples, other.samples);
+    self.total = add_counts(self.total, other.total);
+    self.acc.merge(other.acc.clone());
+  }
+}
+
+/// A `RateAcc` counts the outcomes of boolean trials, e.g. accepted
+/// Metropolis updates. The numbers of trials and successes are kept exactly,
+/// in `u64`, saturating at `i64::MAX`. The confidence intervals of the rate are
+/// Wilson score intervals, which stay within `[0, 1]` and behave well for rates
+/// close to 0 or 1. They assume independent trials.
+#[derive(Clone, Default, Deserialize, Serialize)]
+pub struct RateAcc {
+  trials: u64,
+  successes: u64,
+}
+
+impl RateAcc {
+  /// Constructs an empty `RateAcc`.
+  pub fn new() -> RateAcc {
+    RateAcc::default()
+  }
+
+  /// Gives the number of recorded trials.
+  pub fn num_of_trials(&self) -> u64 {
+    self.trials
+  }
+
+  /// Gives the number of successful trials.
+  pub fn num_of_successes(&self) -> u64 {
+    self.successes
+  }
+
+  /// Gives the fraction of successful trials.
+  pub fn rate(&self) -> f64 {
+    self.successes as f64 / self.trials as f64
+  }
+
+  /// Gives the standard error of the rate for independent trials.
+  pub fn uncertainty(&self) -> f64 {
+    let rate = self.rate();
+    (rate * (1.0 - rate) / self.trials as f64).sqrt()
+  }
+
+  /// Gives the Wilson score interval of the rate at a given
+  /// `confidence_level`, e.g. 0.95.
+  pub fn wilson_interval(&self, confidence_level: f64) -> (f64, f64) {
+    let n = self.trials as f64;
+    let rate = self.rate();
+    let z = normal_quantile((1.0 + confidence_level) / 2.0);
+    let z2 = z.powi(2);
+    let center = (rate + z2 / (2.0 * n)) / (1.0 + z2 / n);
+    let half_width = z / (1.0 + z2 / n) * (rate * (1.0 - rate) / n + z2 / (4.0 * n * n)).sqrt();
+    (center - half_width, center + half_width)
+  }
+
+  /// Records the outcome of a trial.
+  pub fn consume(&mut self, success: bool) {
+    self.trials = add_counts(self.trials, 1);
+    if success {
+      self.successes = add_counts(self.successes, 1);
+    }
+  }
+
+  /// Merges another `RateAcc` into this one.
+  pub fn merge(&mut self, other: &RateAcc) {
+    self.trials = add_counts(self.trials, other.trials);
+    self.successes = add_counts(self.successes, other.successes);
+  }
+}
+
+/// Gives the `p`-th quantile of the standard normal distribution, with the
+/// rational approximation of Acklam (relative error below 1.2e-9).
+fn normal_quantile(p: f64) -> f64 {
+  const A: [f64; 6] = [-3.969683028665376e1, 2.209460984245205e2, -2.759285104469687e2,
+                       1.38357751867269e2, -3.066479806614716e1, 2.506628277459239];
+  const B: [f64; 5] = [-5.447609879822406e1, 1.615858368580409e2, -1.556989798598866e2,
+                       6.680131188771972e1, -1.328068155288572e1];
+  const C: [f64; 6] = [-7.784894002430293e-3, -3.223964580411365e-1, -2.400758277161838,
+                       -2.549732539343734, 4.374664141464968, 2.938163982698783];
+  const D: [f64; 4] = [7.784695709041462e-3, 3.224671290700398e-1, 2.445134137142996,
+                       3.754408661907416];
+  const P_LOW: f64 = 0.02425;
+  if !(p > 0.0 && p < 1.0) {
+    return if p == 0.0 { f64::NEG_INFINITY } else if p == 1.0 { f64::INFINITY } else { f64::NAN };
+  }
+  // Tails.
+  let tail = |q: f64| {
+    let q = (-2.0 * q.ln()).sqrt();
+    (((((C[0] * q + C[1]) * q + C[2]) * q + C[3]) * q + C[4]) * q + C[5])
+        / ((((D[0] * q + D[1]) * q + D[2]) * q + D[3]) * q + 1.0)
+  };
+  if p < P_LOW {
+    return tail(p);
+  }
+  if p > 1.0 - P_LOW {
+    return -tail(1.0 - p);
+  }
+  // Central region.
+  let q = p - 0.5;
+  let r = q * q;
+  (((((A[0] * r + A[1]) * r + A[2]) * r + A[3]) * r + A[4]) * r + A[5]) * q
+      / (((((B[0] * r + B[1]) * r + B[2]) * r + B[3]) * r + B[4]) * r + 1.0)
+}
+
+/// A `ComplexAcc` accumulates a complex-valued observable, e.g. a Polyakov
+/// loop. The real and imaginary parts are accumulated together with their
+/// covariance, which the errors of the modulus and the phase of the
+/// expectation value are propagated from.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct ComplexAcc {
+  parts: CovAcc,
+}
+
+impl Default for ComplexAcc {
+  fn default() -> ComplexAcc {
+    ComplexAcc::new()
+  }
+}
+
+impl ComplexAcc {
+  /// Constructs an empty `ComplexAcc`.
+  pub fn new() -> ComplexAcc {
+    ComplexAcc { parts: CovAcc::new(2) }
+  }
+
+  /// Gives the accumulator of the real (component 0) and imaginary
+  /// (component 1) parts.
+  pub fn parts(&self) -> &CovAcc {
+    &self.parts
+  }
+
+  /// Gives the number of recorded samples.
+  pub fn num_of_samples(&self) -> f64 {
+    self.parts.num_of_samples()
+  }
+
+  /// Gives the mean of the real parts.
+  pub fn re(&self) -> f64 {
+    self.parts.value(0)
+  }
+
+  /// Gives the mean of the imaginary parts.
+  pub fn im(&self) -> f64 {
+    self.parts.value(1)
+  }
+
+  /// Gives the modulus of the mean.
+  pub fn modulus(&self) -> f64 {
+    self.re().hypot(self.im())
+  }
+
+  /// Gives the phase of the mean in `(-pi, pi]`.
+  pub fn phase(&self) -> f64 {
+    self.im().atan2(self.re())
+  }
+
+  /// Gives the statistical error of the modulus for uncorrelated samples.
+  pub fn modulus_uncertainty(&self) -> f64 {
+    self.parts.propagated_uncertainty(&self.modulus_gradient())
+  }
+
+  /// Gives the statistical error of the modulus taking autocorrelations into
+  /// account.
+  pub fn binned_modulus_uncertainty(&self) -> f64 {
+    self.parts.binned_propagated_uncertainty(&self.modulus_gradient())
+  }
+
+  /// Gives the statistical error of the phase for uncorrelated samples.
+  pub fn phase_uncertainty(&self) -> f64 {
+    self.parts.propagated_uncertainty(&self.phase_gradient())
+  }
+
+  /// Gives the statistical error of the phase taking autocorrelations into
+  /// account.
+  pub fn binned_phase_uncertainty(&self) -> f64 {
+    self.parts.binned_propagated_uncertainty(&self.phase_gradient())
+  }
+
+  fn modulus_gradient(&self) -> [f64; 2] {
+    let modulus = self.modulus();
+    [self.re() / modulus, self.im() / modulus]
+  }
+
+  fn phase_gradient(&self) -> [f64; 2] {
+    let modulus2 = self.modulus().powi(2);
+    [-self.im() / modulus2, self.re() / modulus2]
+  }
+
+  /// Consumes a sample value `re + i im`. Samples with a NaN part are skipped.
+  pub fn consume(&mut self, re: f64, im: f64) {
+    self.parts.consume(&[re, im]);
+  }
+
+  /// Merges another `ComplexAcc` into this one. Semantically equivalent to
+  /// calling `self.consume(..)` for each of the samples consumed previously by
+  /// `other`.
+  pub fn merge(&mut self, other: &ComplexAcc) {
+    self.parts.merge(&other.parts).expect("Complex accumulators have two parts.");
+  }
+}
+
+/// A `MomentAcc` accumulates the higher central moments of an observable, up
+/// to the fourth, for the skewness, the excess kurtosis and the Binder
+/// cumulant of its distribution. The moments are updated and merged with the
+/// numerically stable formulas of Pébay (2008), so merging is exact.
+/// For the statistical errors of the mean and the Binder cumulant, the sample
+/// powers `x`, `x^2` and `x^4` are also accumulated in a `CovAcc`, and the
+/// errors are propagated from their binned covariance.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct MomentAcc {
+  count: f64,
+  mean: f64,
+
+  /// Sums of the 2nd, 3rd and 4th powers of deviations from the mean.
+  m2: f64,
+  m3: f64,
+  m4: f64,
+
+  /// Accumulator of the powers `x`, `x^2` and `x^4` of the samples.
+  powers: CovAcc,
+}
+
+impl Default for MomentAcc {
+  fn default() -> MomentAcc {
+    MomentAcc::new()
+  }
+}
+
+impl MomentAcc {
+  /// Constructs an empty `MomentAcc`.
+  pub fn new() -> MomentAcc {
+    MomentAcc {
+      count: 0.0,
+      mean: 0.0,
+      m2: 0.0,
+      m3: 0.0,
+      m4: 0.0,
+      powers: CovAcc::new(3),
+    }
+  }
+
+  /// Gives the number of recorded samples.
+  pub fn num_of_samples(&self) -> f64 {
+    self.count
+  }
+
+  /// Gives the mean of the consumed samples.
+  pub fn value(&self) -> f64 {
+    self.mean
+  }
+
+  /// Gives the statistical error of the mean for uncorrelated samples.
+  pub fn uncertainty(&self) -> f64 {
+    self.powers.uncertainty(0)
+  }
+
+  /// Gives the statistical error of the mean taking autocorrelations into
+  /// account, see `Acc::binned_uncertainty`.
+  pub fn binned_uncertainty(&self) -> f64 {
+    self.powers.binned_uncertainty(0)
+  }
+
+  /// Gives the integrated autocorrelation time of the samples.
+  pub fn autocorrelation_time(&self) -> f64 {
+    self.powers.autocorrelation_time(0)
+  }
+
+  /// Gives the unbiased estimate of the variance of the distribution.
+  pub fn variance(&self) -> f64 {
+    self.m2 / (self.count - 1.0)
+  }
+
+  /// Gives the skewness `<(x - <x>)^3> / <(x - <x>)^2>^(3/2)` of the
+  /// distribution.
+  pub fn skewness(&self) -> f64 {
+    self.count.sqrt() * self.m3 / self.m2.powf(1.5)
+  }
+
+  /// Gives the excess kurtosis `<(x - <x>)^4> / <(x - <x>)^2>^2 - 3` of the
+  /// distribution, which vanishes for the normal distribution.
+  pub fn excess_kurtosis(&self) -> f64 {
+    self.count * self.m4 / self.m2.powi(2) - 3.0
+  }
+
+  /// Gives the Binder cumulant `1 - <x^4> / (3 <x^2>^2)` of the distribution.
+  /// Note that it is defined with the moments about zero, not about the mean.
+  pub fn binder_cumulant(&self) -> f64 {
+    let (moment2, moment4) = self.raw_moments();
+    1.0 - moment4 / (3.0 * moment2.powi(2))
+  }
+
+  /// Gives the statistical error of the Binder cumulant for uncorrelated
+  /// samples.
+  pub fn binder_cumulant_uncertainty(&self) -> f64 {
+    self.powers.propagated_uncertainty(&self.binder_cumulant_gradient())
+  }
+
+  /// Gives the statistical error of the Binder cumulant taking autocorrelations
+  /// into account, see `CovAcc::binned_propagated_uncertainty`.
+  pub fn binned_binder_cumulant_uncertainty(&self) -> f64 {
+    self.powers.binned_propagated_uncertainty(&self.binder_cumulant_gradient())
+  }
+
+  /// Gives the moments `<x^2>` and `<x^4>` about zero.
+  fn raw_moments(&self) -> (f64, f64) {
+    let n = self.count;
+    let mean = self.mean;
+    let moment2 = self.m2 / n + mean.powi(2);
+    let moment4 = self.m4 / n + 4.0 * mean * self.m3 / n
+        + 6.0 * mean.powi(2) * self.m2 / n + mean.powi(4);
+    (moment2, moment4)
+  }
+
+  /// Gradient of the Binder cumulant with respect to `<x>`, `<x^2>` and
+  /// `<x^4>`.
+  fn binder_cumulant_gradient(&self) -> [f64; 3] {
+    let (moment2, moment4) = self.raw_moments();
+    [0.0, 2.0 * moment4 / (3.0 * moment2.powi(3)), -1.0 / (3.0 * moment2.powi(2))]
+  }
+
+  /// Consumes a sample value. NaN values are skipped.
+  pub fn consume(&mut self, value: f64) {
+    if value.is_nan() {
+      return;
+    }
+    let n1 = self.count;
+    self.count += 1.0;
+    let n = self.count;
+    let delta = value - self.mean;
+    let delta_n = delta / n;
+    let term = delta * delta_n * n1;
+    self.mean += delta_n;
+    self.m4 += term * delta_n.powi(2) * (n * n - 3.0 * n + 3.0)
+        + 6.0 * delta_n.powi(2) * self.m2 - 4.0 * delta_n * self.m3;
+    self.m3 += term * delta_n * (n - 2.0) - 3.0 * delta_n * self.m2;
+    self.m2 += term;
+    let square = value.powi(2);
+    self.powers.consume(&[value, square, square.powi(2)]);
+  }
+
+  /// Merges another `MomentAcc` into this one. Semantically equivalent to
+  /// calling `self.consume(..)` for each of the samples consumed previously by
+  /// `other`.
+  pub fn merge(&mut self, other: &MomentAcc) {
+    if other.count == 0.0 {
+      return;
+    }
+    let (na, nb) = (self.count, other.count);
+    let n = na + nb;
+    let delta = other.mean - self.mean;
+    self.m4 += other.m4
+        + delta.powi(4) * na * nb * (na * na - na * nb + nb * nb) / n.powi(3)
+        + 6.0 * delta.powi(2) * (na * na * other.m2 + nb * nb * self.m2) / n.powi(2)
+        + 4.0 * delta * (na * other.m3 - nb * self.m3) / n;
+    self.m3 += other.m3
+        + delta.powi(3) * na * nb * (na - nb) / n.powi(2)
+        + 3.0 * delta * (na * other.m2 - nb * self.m2) / n;
+    self.m2 += other.m2 + delta.powi(2) * na * nb / n;
+    self.mean += delta * nb / n;
+    self.count = n;
+    self.powers.merge(&other.powers).expect("Moment accumulators have three powers.");
+  }
+}
+
+/// A `Histogram` counts the samples of an observable falling into bins, giving
+/// its full distribution rather than just its mean. Histograms have either
+/// a fixed range and number of bins, or adapt to the values they consume.
+/// Histograms with the same binning can be merged across flushes and nodes.
+#[derive(Clone, Deserialize, Serialize)]
+pub struct Histogram {
+  binning: HistogramBinning,
+
+  /// Number of samples in consecutive bins, starting from the bin `offset`.
+  counts: Vec<f64>,
+  offset: i64,
+
+  /// Number of samples below and above the range of a fixed histogram, or
+  /// infinite values consumed by an adaptive histogram.
+  underflow: f64,
+  overflow: f64,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+enum HistogramBinning {
+  /// `bins` bins of equal width covering `[min, max]`.
+  Fixed { min: f64, max: f64, bins: usize },
+
+  /// Bins `[j * 2^width_exp, (j + 1) * 2^width_exp)` for integer `j`. The
+  /// width is doubled, merging pairs of adjacent bins, whenever the values
+  /// span more than `max_bins` bins. Aligning the bins to powers of two makes
+  /// any two adaptive histograms mergeable. The width is chosen by the first
+  /// consumed value.
+  Adaptive { max_bins: usize, width_exp: Option<i32> },
+}
+
+impl ::std::fmt::Display for HistogramBinning {
+  fn fmt(&self, f: &mut ::std::fmt::Formatter) -> ::std::fmt::Result {
+    match *self {
+      HistogramBinning::Fixed { min, max, bins } => {
+        write!(f, "{} bins over [{}, {}]", bins, min, max)
+      }
+      HistogramBinning::Adaptive { max_bins, .. } => {
+        write!(f, "at most {} adaptive bins", max_bins)
+      }
+    }
+  }
+}
+
+/// Bins of adaptive histograms are initially 2^40 times narrower than the
+/// magnitude of the first value, so that values spread around a large offset
+/// still get resolved. Bins are coarsened as soon as the values spread.
+const ADAPTIVE_INITIAL_RESOLUTION: i32 = 40;
+
+impl Histogram {
+  /// Constructs an empty histogram with `bins` bins of equal width covering
+  /// `[min, max]`. Values outside of the range are only counted.
+  pub fn fixed(min: f64, max: f64, bins: usize) -> Histogram {
+    assert!(min < max && bins > 0,
+            "Histogram::fixed(..): invalid range [{}, {}] or number of bins {}.",
+            min, max, bins);
+    Histogram {
+      binning: HistogramBinning::Fixed { min, max, bins },
+      counts: vec![0.0; bins],
+      offset: 0,
+      underflow: 0.0,
+      overflow: 0.0,
+    }
+  }
+
+  /// Constructs an empty histogram adapting its range to the consumed values,
+  /// with at most `max_bins` bins. The bin width is always a power of two.
+  pub fn adaptive(max_bins: usize) -> Histogram {
+    assert!(max_bins >= 2, "Histogram::adaptive(..): at least 2 bins required.");
+    Histogram {
+      binning: HistogramBinning::Adaptive { max_bins, width_exp: None },
+      counts: Vec::new(),
+      offset: 0,
+      underflow: 0.0,
+      overflow: 0.0,
+    }
+  }
+
+  /// Constructs an empty histogram with the same binning as `self`. Adaptive
+  /// histograms start over with the width chosen by the first value.
+  pub fn cleared(&self) -> Histogram {
+    match self.binning {
+      HistogramBinning::Fixed { min, max, bins } => Histogram::fixed(min, max, bins),
+      HistogramBinning::Adaptive { max_bins, .. } => Histogram::adaptive(max_bins),
+    }
+  }
+
+  /// Gives the number of recorded samples, including the ones outside of the
+  /// range of a fixed histogram.
+  pub fn num_of_samples(&self) -> f64 {
+    self.counts.iter().sum::<f64>() + self.underflow + self.overflow
+  }
+
+  /// Gives the number of samples below the range of a fixed histogram, or of
+  /// negative infinite values consumed by an adaptive histogram.
+  pub fn underflow(&self) -> f64 {
+    self.underflow
+  }
+
+  /// Gives the number of samples above the range of a fixed histogram, or of
+  /// positive infinite values consumed by an adaptive histogram.
+  pub fn overflow(&self) -> f64 {
+    self.overflow
+  }
+
+  /// Gives the bins as triples of (lower bound, upper bound, number of
+  /// samples).
+  pub fn bins(&self) -> Vec<(f64, f64, f64)> {
+    self.counts.iter().enumerate().map(|(i, count)| {
+      let (lower, upper) = self.bin_bounds(self.offset + i as i64);
+      (lower, upper, *count)
+    }).collect()
+  }
+
+  fn bin_bounds(&self, j: i64) -> (f64, f64) {
+    match self.binning {
+      HistogramBinning::Fixed { min, max, bins } => {
+        let width = (max - min) / bins as f64;
+        (min + j as f64 * width, min + (j + 1) as f64 * width)
+      }
+      HistogramBinning::Adaptive { width_exp, .. } => {
+        let width = 2f64.powi(width_exp.unwrap_or(0));
+        (j as f64 * width, (j + 1) as f64 * width)
+      }
+    }
+  }
+
+  /// Consumes a sample value. NaN values are skipped.
+  pub fn consume(&mut self, value: f64) {
+    if value.is_nan() {
+      return;
+    }
+    let (min, max, bins) = match self.binning {
+      HistogramBinning::Fixed { min, max, bins } => (min, max, bins),
+      HistogramBinning::Adaptive { .. } => return self.consume_adaptive(value),
+    };
+    if value < min {
+      self.underflow += 1.0;
+    } else if value > max {
+      self.overflow += 1.0;
+    } else {
+      // The upper bound belongs to the last bin.
+      let bin = ((value - min) / (max - min) * bins as f64) as usize;
+      self.counts[bin.min(bins - 1)] += 1.0;
+    }
+  }
+
+  fn consume_adaptive(&mut self, value: f64) {
+    if value.is_infinite() {
+      if value < 0.0 { self.underflow += 1.0 } else { self.overflow += 1.0 }
+      return;
+    }
+    loop {
+      let (max_bins, width_exp) = match self.binning {
+        HistogramBinning::Adaptive { max_bins, ref mut width_exp } => {
+          let magnitude = if value == 0.0 { 0 } else { value.abs().log2().floor() as i32 };
+          (max_bins, *width_exp.get_or_insert(magnitude - ADAPTIVE_INITIAL_RESOLUTION))
+        }
+        HistogramBinning::Fixed { .. } => unreachable!(),
+      };
+      let j = (value / 2f64.powi(width_exp)).floor();
+      // Bin indices must stay exactly representable.
+      if j.abs() < 2f64.powi(52) {
+        let j = j as i64;
+        if self.counts.is_empty() {
+          self.offset = j;
+          self.counts.push(1.0);
+          return;
+        }
+        let lower = self.offset.min(j);
+        let upper = (self.offset + self.counts.len() as i64 - 1).max(j);
+        if upper - lower < max_bins as i64 {
+          self.extend_to(lower, upper);
+          self.counts[(j - self.offset) as usize] += 1.0;
+          return;
+        }
+      }
+      self.coarsen();
+    }
+  }
+
+  /// Extends the bins of an adaptive histogram to cover `[lower, upper]`.
+  fn extend_to(&mut self, lower: i64, upper: i64) {
+    if lower < self.offset {
+      let mut counts = vec![0.0; (self.offset - lower) as usize];
+      counts.extend_from_slice(&self.counts);
+      self.counts = counts;
+      self.offset = lower;
+    }
+    let len = (upper - self.offset + 1) as usize;
+    if self.counts.len() < len {
+      self.counts.resize(len, 0.0);
+    }
+  }
+
+  /// Doubles the width of the bins of an adaptive histogram, merging pairs of
+  /// adjacent bins.
+  fn coarsen(&mut self) {
+    if let HistogramBinning::Adaptive { width_exp: Some(ref mut width_exp), .. } = self.binning {
+      *width_exp += 1;
+    }
+    if self.counts.is_empty() {
+      return;
+    }
+    let offset = self.offset.div_euclid(2);
+    let last = (self.offset + self.counts.len() as i64 - 1).div_euclid(2);
+    let mut counts = vec![0.0; (last - offset + 1) as usize];
+    for (i, count) in self.counts.iter().enumerate() {
+      counts[((self.offset + i as i64).div_euclid(2) - offset) as usize] += count;
+    }
+    self.counts = counts;
+    self.offset = offset;
+  }
+
+  /// Merges another histogram into this one. Semantically equivalent to
+  /// calling `self.consume(..)` for each of the samples consumed previously by
+  /// `other`, up to the resolution of the bins. Fails without changing `self`
+  /// if one of the histograms is fixed and their binnings differ.
+  pub fn merge(&mut self, other: &Histogram) -> Result<(), String> {
+    let (max_bins, exp, other_exp) = match (&mut self.binning, &other.binning) {
+      (&mut HistogramBinning::Fixed { min, max, bins },
+       &HistogramBinning::Fixed { min: other_min, max: other_max, bins: other_bins })
+          if min == other_min && max == other_max && bins == other_bins => {
+        for (count, other_count) in self.counts.iter_mut().zip(other.counts.iter()) {
+          *count += other_count;
+        }
+        self.underflow += other.underflow;
+        self.overflow += other.overflow;
+        return Ok(());
+      }
+      (&mut HistogramBinning::Adaptive { max_bins, ref mut width_exp },
+       &HistogramBinning::Adaptive { width_exp: other_width_exp, .. }) => {
+        self.underflow += other.underflow;
+        self.overflow += other.overflow;
+        match (*width_exp, other_width_exp) {
+          (_, None) => return Ok(()),
+          (None, Some(other_exp)) => {
+            // Nothing consumed yet: adopt the bins of `other`.
+            *width_exp = Some(other_exp);
+            self.counts = other.counts.clone();
+            self.offset = other.offset;
+            return Ok(());
+          }
+          (Some(exp), Some(other_exp)) => (max_bins, exp, other_exp),
+        }
+      }
+      (binning, other_binning) => {
+        return Err(format!("histograms have different binnings, {} and {}.",
+                           binning, other_binning));
+      }
+    };
+    let mut other = other.clone();
+    for _ in exp..other_exp {
+      self.coarsen();
+    }
+    for _ in other_exp..exp {
+      other.coarsen();
+    }
+    // Once the width is chosen, a histogram has at least one bin.
+    loop {
+      let lower = self.offset.min(other.offset);
+      let upper = (self.offset + self.counts.len() as i64)
+          .max(other.offset + other.counts.len() as i64) - 1;
+      if upper - lower < max_bins as i64 {
+        self.extend_to(lower, upper);
+        for (i, count) in other.counts.iter().enumerate() {
+          self.counts[(other.offset + i as i64 - self.offset) as usize] += count;
+        }
+        return Ok(());
+      }
+      self.coarsen();
+      other.coarsen();
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use rand::rngs::StdRng;
+  use rand::Rng;
+  use rand::SeedableRng;
+
+  /// Asserts that `actual` agrees with `expected` up to a relative error.
+  fn assert_close(actual: f64, expected: f64, tolerance: f64) {
+    assert!((actual - expected).abs() <= tolerance * expected.abs().max(1e-300),
+            "{} != {}", actual, expected);
+  }
+
+  /// Draws `n` values of the AR(1) process `x' = phi x + noise`, whose
+  /// integrated autocorrelation time is `(1 + phi) / (1 - phi) / 2` and whose
+  /// variance is `1 / (12 (1 - phi^2))` for uniform noise.
+  fn ar1_values(rng: &mut StdRng, n: usize, phi: f64) -> Vec<f64> {
+    let mut x = 0.0;
+    (0..n).map(|_| {
+      x = phi * x + rng.gen::<f64>() - 0.5;
+      x
+    }).collect()
+  }
+
+  /// Draws `n` values from a skewed distribution: an exponential one shifted
+  /// by `offset`.
+  fn skewed_values(rng: &mut StdRng, n: usize, offset: f64) -> Vec<f64> {
+    (0..n).map(|_| offset - (1.0 - rng.gen::<f64>()).ln()).collect()
+  }
+
+  /// Splits `values` into consecutive chunks of random lengths, some empty.
+  fn random_chunks<'a>(rng: &mut StdRng, values: &'a [f64]) -> Vec<&'a [f64]> {
+    let mut chunks = Vec::new();
+    let mut rest = values;
+    while !rest.is_empty() {
+      let len = rng.gen_range(0..rest.len().min(200) + 1);
+      chunks.push(&rest[..len]);
+      rest = &rest[len..];
+    }
+    chunks
+  }
+
+  /// Computes the mean and the sum of squared deviations from the mean of
+  /// `values` in two passes.
+  fn two_pass(values: &[f64]) -> (f64, f64) {
+    let mean = values.iter().sum::<f64>() / values.len() as f64;
+    (mean, values.iter().map(|x| (x - mean).powi(2)).sum())
+  }
+
+  #[test]
+  fn stats_merge_matches_single_pass_and_two_pass() {
+    let mut rng = StdRng::seed_from_u64(16);
+    // Values of order one, and a plaquette close to 1 whose variance is 14
+    // orders of magnitude below the square of its mean.
+    let datasets: Vec<Vec<f64>> = vec![
+      (0..5000).map(|_| rng.gen::<f64>() * 2.0 - 1.0).collect(),
+      (0..5000).map(|_| 1.0 - 1e-7 * rng.gen::<f64>()).collect(),
+    ];
+    for values in datasets.iter() {
+      let mut single = Stats::default();
+      for value in values.iter() {
+        single.consume(*value, 1.0);
+      }
+      let mut merged = Stats::default();
+      for chunk in random_chunks(&mut rng, values) {
+        let mut stats = Stats::default();
+        for value in chunk {
+          stats.consume(*value, 1.0);
+        }
+        merged.merge(&stats);
+      }
+      let n = values.len() as f64;
+      let (mean, m2) = two_pass(values);
+      for stats in &[&single, &merged] {
+        assert_eq!(stats.count, n);
+        assert_eq!(stats.effective_count(), n);
+        assert_close(stats.mean, mean, 1e-14);
+        assert_close(stats.m2, m2, 1e-8);
+        assert_close(stats.error_of_mean(), (m2 / (n * (n - 1.0))).sqrt(), 1e-8);
+      }
+    }
+  }
+
+  #[test]
+  fn consume_weighted_skips_invalid_weights() {
+    let mut acc = Acc::new();
+    acc.consume_weighted(1.0, 2.0);
+    acc.consume_weighted(3.0, 1.0);
+    for weight in &[0.0, -1.0, f64::NAN, f64::INFINITY] {
+      acc.consume_weighted(100.0, *weight);
+    }
+    acc.consume_weighted(f64::NAN, 1.0);
+    assert_eq!(acc.num_of_samples(), 2.0);
+    assert_close(acc.value(), 5.0 / 3.0, 1e-15);
+    assert_close(acc.effective_sample_size(), 9.0 / 5.0, 1e-15);
+  }
+
+  #[test]
+  fn stats_reads_legacy_mean_square() {
+    let values = [1.0, 2.0, 3.0, 4.0];
+    let mut acc = Acc::new();
+    for value in values.iter() {
+      acc.consume(*value);
+    }
+    let legacy: Acc = ::serde_json::from_str(r#"{"count": 4, "mean": 2.5, "mean2": 7.5}"#)
+        .unwrap();
+    assert_eq!(legacy.num_of_samples(), 4.0);
+    assert_eq!(legacy.value(), acc.value());
+    assert_close(legacy.uncertainty(), acc.uncertainty(), 1e-15);
+    assert_eq!(legacy.stats.m2, 5.0);
+    assert_eq!((legacy.stats.weight, legacy.stats.weight2), (4.0, 4.0));
+
+    // Merging a legacy accumulator gives the same as merging a current one.
+    let mut merged = acc.clone();
+    merged.merge(legacy);
+    let mut expected = acc.clone();
+    expected.merge(acc.clone());
+    assert_eq!(merged.value(), expected.value());
+    assert_close(merged.uncertainty(), expected.uncertainty(), 1e-15);
+
+    // Current accumulators are read back as written.
+    let json = ::serde_json::to_string(&acc).unwrap();
+    let restored: Acc = ::serde_json::from_str(&json).unwrap();
+    assert_eq!(restored.value(), acc.value());
+    assert_eq!(restored.uncertainty(), acc.uncertainty());
+  }
+
+  #[test]
+  fn binning_of_uncorrelated_samples() {
+    let mut rng = StdRng::seed_from_u64(5);
+    let mut acc = Acc::new();
+    for value in ar1_values(&mut rng, 1 << 16, 0.0) {
+      acc.consume(value);
+    }
+    let binned = acc.binned_uncertainties();
+    assert_eq!(binned[0], (1.0, acc.uncertainty()));
+    let sizes: Vec<f64> = binned.iter().map(|&(size, _)| size).collect();
+    assert_eq!(sizes, (0..17).map(|k| (1u64 << k) as f64).collect::<Vec<_>>());
+    // The first bin size is already on the plateau.
+    assert_eq!(acc.binned_uncertainty(), acc.uncertainty());
+    assert_close(acc.autocorrelation_time(), 0.5, 1e-15);
+    assert_close(acc.uncertainty(), (1.0 / 12.0 / (1 << 16) as f64).sqrt(), 0.02);
+  }
+
+  #[test]
+  fn binning_estimates_autocorrelation_time_of_ar1() {
+    let mut rng = StdRng::seed_from_u64(5);
+    let (n, phi) = (1 << 18, 0.8);
+    let values = ar1_values(&mut rng, n, phi);
+    let tau = (1.0 + phi) / (1.0 - phi) / 2.0;
+    let variance = 1.0 / (12.0 * (1.0 - phi * phi));
+
+    let mut single = Acc::new();
+    for value in values.iter() {
+      single.consume(*value);
+    }
+    // Merging accumulators of consecutive chunks only drops incomplete bins.
+    let mut merged = Acc::new();
+    for chunk in values.chunks(1 << 12) {
+      let mut acc = Acc::new();
+      for value in chunk {
+        acc.consume(*value);
+      }
+      merged.merge(acc);
+    }
+    for acc in &[&single, &merged] {
+      // The naive error underestimates the error by the factor sqrt(2 tau).
+      assert_close(acc.uncertainty(), (variance / n as f64).sqrt(), 0.02);
+      assert_close(acc.autocorrelation_time(), tau, 0.15);
+      assert_close(acc.binned_uncertainty(), (2.0 * tau * variance / n as f64).sqrt(), 0.08);
+      // The errors grow with the bin size until the plateau.
+      let binned = acc.binned_uncertainties();
+      assert!(binned[1].1 > binned[0].1 && binned[2].1 > binned[1].1);
+    }
+  }
+
+  #[test]
+  fn moment_acc_merge_matches_direct_computation() {
+    let mut rng = StdRng::seed_from_u64(15);
+    for &offset in &[0.0, -1.0, 100.0] {
+      let values = skewed_values(&mut rng, 5000, offset);
+      let mut merged = MomentAcc::new();
+      for chunk in random_chunks(&mut rng, &values) {
+        let mut acc = MomentAcc::new();
+        for value in chunk {
+          acc.consume(*value);
+        }
+        merged.merge(&acc);
+      }
+
+      // Central moments computed directly, around the exact mean.
+      let n = values.len() as f64;
+      let mean = values.iter().sum::<f64>() / n;
+      let central = |k: i32| values.iter().map(|x| (x - mean).powi(k)).sum::<f64>();
+      let (m2, m3, m4) = (central(2), central(3), central(4));
+      let raw = |k: i32| values.iter().map(|x| x.powi(k)).sum::<f64>() / n;
+
+      assert_eq!(merged.num_of_samples(), n);
+      assert_close(merged.value(), mean, 1e-12);
+      assert_close(merged.variance(), m2 / (n - 1.0), 1e-9);
+      assert_close(merged.skewness(), n.sqrt() * m3 / m2.powf(1.5), 1e-9);
+      assert_close(merged.excess_kurtosis(), n * m4 / m2.powi(2) - 3.0, 1e-9);
+      assert_close(merged.binder_cumulant(), 1.0 - raw(4) / (3.0 * raw(2).powi(2)), 1e-9);
+    }
+  }
+
+  #[test]
+  fn moment_acc_merge_of_empty_accumulators() {
+    let mut acc = MomentAcc::new();
+    acc.merge(&MomentAcc::new());
+    assert_eq!(acc.num_of_samples(), 0.0);
+    let mut other = MomentAcc::new();
+    for value in &[1.0, 2.0, 4.0] {
+      other.consume(*value);
+    }
+    acc.merge(&other);
+    acc.merge(&MomentAcc::new());
+    assert_eq!(acc.num_of_samples(), 3.0);
+    assert_close(acc.value(), 7.0 / 3.0, 1e-15);
+    assert_close(acc.variance(), 7.0 / 3.0, 1e-15);
+  }
+
+  #[test]
+  fn cov_acc_merge_matches_direct_computation() {
+    let mut rng = StdRng::seed_from_u64(10);
+    let samples: Vec<[f64; 2]> = (0..2000).map(|_| {
+      let x = rng.gen::<f64>();
+      [x, 2.0 * x + rng.gen::<f64>()]
+    }).collect();
+    let mut merged = CovAcc::new(2);
+    for chunk in samples.chunks(300) {
+      let mut acc = CovAcc::new(2);
+      for sample in chunk {
+        acc.consume(sample);
+      }
+      merged.merge(&acc).unwrap();
+    }
+    let n = samples.len() as f64;
+    let mean = |i: usize| samples.iter().map(|s| s[i]).sum::<f64>() / n;
+    let covariance = |i: usize, j: usize| samples.iter()
+        .map(|s| (s[i] - mean(i)) * (s[j] - mean(j))).sum::<f64>() / (n - 1.0);
+    assert_eq!(merged.num_of_samples(), n);
+    for i in 0..2 {
+      assert_close(merged.value(i), mean(i), 1e-12);
+      for j in 0..2 {
+        assert_close(merged.covariance(i, j), covariance(i, j), 1e-9);
+      }
+    }
+  }
+
+  #[test]
+  fn cov_acc_merge_rejects_different_lengths() {
+    let mut acc = CovAcc::new(2);
+    acc.consume(&[1.0, 2.0]);
+    let mut other = CovAcc::new(3);
+    other.consume(&[1.0, 2.0, 3.0]);
+    assert!(acc.merge(&other).is_err());
+    assert!(acc.merge(&CovAcc::new(1)).is_err());
+    assert_eq!((acc.len(), acc.num_of_samples()), (2, 1.0));
+    assert_eq!(acc.values(), &[1.0, 2.0]);
+  }
+
+  /// Draws `n` samples `3 + 4i` plus independent uniform noise of variance
+  /// `1/12` in each part.
+  fn complex_samples(rng: &mut StdRng, n: usize) -> Vec<(f64, f64)> {
+    (0..n).map(|_| (3.0 + rng.gen::<f64>() - 0.5, 4.0 + rng.gen::<f64>() - 0.5)).collect()
+  }
+
+  #[test]
+  fn complex_acc_propagates_errors_to_modulus_and_phase() {
+    let mut rng = StdRng::seed_from_u64(18);
+    let n = 20000;
+    let mut acc = ComplexAcc::new();
+    for (re, im) in complex_samples(&mut rng, n) {
+      acc.consume(re, im);
+    }
+    acc.consume(f64::NAN, 1.0);
+    assert_eq!(acc.num_of_samples(), n as f64);
+    assert_close(acc.re(), 3.0, 1e-3);
+    assert_close(acc.im(), 4.0, 1e-3);
+    assert_close(acc.modulus(), 5.0, 1e-3);
+    assert_close(acc.phase(), (4.0f64).atan2(3.0), 1e-3);
+
+    // The modulus changes along (3, 4) / 5, and the phase across it, scaled
+    // by 1 / 5.
+    let error = (1.0 / 12.0 / n as f64).sqrt();
+    assert_close(acc.modulus_uncertainty(), error, 0.03);
+    assert_close(acc.phase_uncertainty(), error / 5.0, 0.03);
+    // Linear propagation through the sample covariance of the parts.
+    let parts = acc.parts();
+    let (c, s) = (acc.re() / acc.modulus(), acc.im() / acc.modulus());
+    let variance = c * c * parts.covariance_of_mean(0, 0) + s * s * parts.covariance_of_mean(1, 1)
+        + 2.0 * c * s * parts.covariance_of_mean(0, 1);
+    assert_close(acc.modulus_uncertainty(), variance.sqrt(), 1e-12);
+    // The samples are uncorrelated.
+    assert_close(acc.binned_modulus_uncertainty(), acc.modulus_uncertainty(), 0.1);
+    assert_close(acc.binned_phase_uncertainty(), acc.phase_uncertainty(), 0.1);
+  }
+
+  #[test]
+  fn complex_acc_merge_matches_direct_computation() {
+    let mut rng = StdRng::seed_from_u64(18);
+    let samples = complex_samples(&mut rng, 3000);
+    let mut direct = ComplexAcc::new();
+    for &(re, im) in samples.iter() {
+      direct.consume(re, im);
+    }
+    let mut merged = ComplexAcc::new();
+    for chunk in samples.chunks(700) {
+      let mut acc = ComplexAcc::new();
+      for &(re, im) in chunk {
+        acc.consume(re, im);
+      }
+      merged.merge(&acc);
+    }
+    merged.merge(&ComplexAcc::new());
+    assert_eq!(merged.num_of_samples(), direct.num_of_samples());
+    assert_close(merged.re(), direct.re(), 1e-12);
+    assert_close(merged.im(), direct.im(), 1e-12);
+    assert_close(merged.modulus_uncertainty(), direct.modulus_uncertainty(), 1e-9);
+    assert_close(merged.phase_uncertainty(), direct.phase_uncertainty(), 1e-9);
+  }
+
+  #[test]
+  fn complex_acc_phase_on_negative_real_axis() {
+    let mut acc = ComplexAcc::new();
+    acc.consume(-2.0, 0.0);
+    assert_eq!((acc.modulus(), acc.phase()), (2.0, ::std::f64::consts::PI));
+  }
+
+  #[test]
+  fn counter_acc_saturates() {
+    let mut acc = CounterAcc::new();
+    acc.consume(i64::MAX as u64 - 1);
+    let mut other = CounterAcc::new();
+    other.consume(2);
+    acc.merge(&other);
+    assert_eq!((acc.num_of_samples(), acc.total()), (2, i64::MAX as u64));
+    acc.consume(u64::MAX);
+    assert_eq!(acc.total(), i64::MAX as u64);
+  }
+
+  #[test]
+  fn rate_acc_saturates() {
+    let mut acc = RateAcc::new();
+    acc.consume(true);
+    let mut other = RateAcc::new();
+    other.trials = i64::MAX as u64;
+    other.successes = i64::MAX as u64;
+    acc.merge(&other);
+    acc.consume(true);
+    assert_eq!((acc.num_of_trials(), acc.num_of_successes()), (i64::MAX as u64, i64::MAX as u64));
+  }
+
+  #[test]
+  fn rate_acc_wilson_interval() {
+    let mut acc = RateAcc::new();
+    for trial in 0..100 {
+      acc.consume(trial % 5 == 0);
+    }
+    assert_eq!(acc.rate(), 0.2);
+    assert_close(acc.uncertainty(), 0.04, 1e-12);
+    let (lower, upper) = acc.wilson_interval(0.95);
+    assert_close(lower, 0.13336693333103253, 1e-8);
+    assert_close(upper, 0.28882916559315885, 1e-8);
+
+    // The interval stays within [0, 1] when all trials fail or succeed.
+    let mut failures = RateAcc::new();
+    let mut successes = RateAcc::new();
+    for _ in 0..10 {
+      failures.consume(false);
+      successes.consume(true);
+    }
+    let (lower, upper) = failures.wilson_interval(0.95);
+    assert_eq!(lower, 0.0);
+    assert_close(upper, 0.2775327998628892, 1e-8);
+    let (lower, upper) = successes.wilson_interval(0.95);
+    assert_close(lower, 0.7224672001371107, 1e-8);
+    assert_close(upper, 1.0, 1e-12);
+  }
+
+  #[test]
+  fn counter_acc_confidence_interval() {
+    let mut acc = CounterAcc::new();
+    for count in 0..1000u64 {
+      acc.consume(count % 7);
+    }
+    let (lower, upper) = acc.confidence_interval(0.95);
+    assert_close((lower + upper) / 2.0, acc.mean(), 1e-12);
+    assert_close((upper - lower) / 2.0, 1.959963984540054 * acc.acc().binned_uncertainty(),
+                 1e-8);
+  }
+
+  #[test]
+  fn adaptive_histogram_grows_range_and_rebins() {
+    let mut histogram = Histogram::adaptive(8);
+    histogram.consume(1.0);
+    // The first value gets a narrow bin of its own.
+    let bins = histogram.bins();
+    assert_eq!(bins.len(), 1);
+    assert_eq!((bins[0].0, bins[0].2), (1.0, 1.0));
+    assert_eq!(bins[0].1 - bins[0].0, 2f64.powi(-40));
+
+    // Bins are widened until both values fit into 8 of them.
+    histogram.consume(1.5);
+    assert_eq!(histogram.bins(), vec![
+      (1.0, 1.125, 1.0), (1.125, 1.25, 0.0), (1.25, 1.375, 0.0), (1.375, 1.5, 0.0),
+      (1.5, 1.625, 1.0),
+    ]);
+
+    // The range grows downwards, merging adjacent bins.
+    histogram.consume(-1.0);
+    histogram.consume(1.2);
+    histogram.consume(f64::NEG_INFINITY);
+    histogram.consume(f64::NAN);
+    assert_eq!(histogram.bins(), vec![
+      (-1.0, -0.5, 1.0), (-0.5, 0.0, 0.0), (0.0, 0.5, 0.0), (0.5, 1.0, 0.0),
+      (1.0, 1.5, 2.0), (1.5, 2.0, 1.0),
+    ]);
+    assert_eq!((histogram.underflow(), histogram.overflow()), (1.0, 0.0));
+    assert_eq!(histogram.num_of_samples(), 5.0);
+
+    let cleared = histogram.cleared();
+    assert_eq!(cleared.num_of_samples(), 0.0);
+    assert!(cleared.bins().is_empty());
+  }
+
+  #[test]
+  fn adaptive_histogram_merge_matches_direct_consumption() {
+    let mut rng = StdRng::seed_from_u64(14);
+    let values = skewed_values(&mut rng, 2000, -3.0);
+    let mut direct = Histogram::adaptive(16);
+    for value in values.iter() {
+      direct.consume(*value);
+    }
+    // Chunks of narrow ranges, whose bins are narrower than the merged ones.
+    let mut sorted = values.clone();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let mut merged = Histogram::adaptive(16);
+    for chunk in sorted.chunks(300) {
+      let mut histogram = Histogram::adaptive(16);
+      for value in chunk {
+        histogram.consume(*value);
+      }
+      merged.merge(&histogram).unwrap();
+    }
+    assert_eq!(merged.num_of_samples(), 2000.0);
+    assert!(merged.bins().len() <= 16);
+    assert_eq!(merged.bins(), direct.bins());
+
+    // An empty histogram adopts the bins of the merged one.
+    let mut empty = Histogram::adaptive(16);
+    empty.merge(&direct).unwrap();
+    assert_eq!(empty.bins(), direct.bins());
+  }
+
+  #[test]
+  fn fixed_histogram_counts_bins_and_outliers() {
+    let mut histogram = Histogram::fixed(0.0, 1.0, 4);
+    for value in &[0.0, 0.1, 0.3, 0.5, 1.0, -0.1, 1.1, f64::NAN] {
+      histogram.consume(*value);
+    }
+    let counts: Vec<f64> = histogram.bins().iter().map(|bin| bin.2).collect();
+    assert_eq!(counts, vec![2.0, 1.0, 1.0, 1.0]);
+    assert_eq!((histogram.underflow(), histogram.overflow()), (1.0, 1.0));
+    assert_eq!(histogram.bins()[1].0, 0.25);
+  }
+
+  #[test]
+  fn histogram_merge_rejects_different_binnings() {
+    let mut fixed = Histogram::fixed(0.0, 1.0, 10);
+    fixed.consume(0.5);
+    let mut other = Histogram::fixed(0.0, 2.0, 10);
+    other.consume(0.5);
+    other.consume(3.0);
+    assert!(fixed.merge(&other).is_err());
+    assert!(fixed.merge(&Histogram::adaptive(10)).is_err());
+    assert!(Histogram::adaptive(10).merge(&fixed).is_err());
+    // The failed merges leave the histogram intact.
+    assert_eq!(fixed.num_of_samples(), 1.0);
+    assert_eq!(fixed.overflow(), 0.0);
+
+    let mut same = Histogram::fixed(0.0, 1.0, 10);
+    same.consume(0.55);
+    same.consume(-1.0);
+    fixed.merge(&same).unwrap();
+    assert_eq!(fixed.num_of_samples(), 3.0);
+    assert_eq!(fixed.underflow(), 1.0);
+    assert_eq!(fixed.bins()[5].2, 2.0);
   }
 }
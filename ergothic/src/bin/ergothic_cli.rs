@@ -0,0 +1,319 @@
+//! Command line tool for querying and aggregating the data points exported by
+//! *ergothic* simulations. Reads data points from files written with
+//! `--output_file` and from MongoDB collections written with `--mongo`, merges
+//! them per simulation and physics parameters, and outputs the aggregate
+//! expectation values and statistical uncertainties. For simulations run at
+//! several points of the parameter space, e.g. in a sweep, also outputs a table
+//! of the expectation values versus the parameters.
+//!
+//! Example:
+//! $ ergothic_cli --file run1.jsonl --file run2.jsonl --measures 'G*'
+//!     --derived 'm_eff(1) = log({G[1]} / {G[2]})' --bootstrap 1000
+
+extern crate ergothic;
+#[macro_use]
+extern crate log;
+extern crate serde_json;
+extern crate simple_logger;
+extern crate structopt;
+
+use ergothic::DataPoint;
+use ergothic::Measures;
+use serde_json::Value;
+use std::cmp::Ordering;
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::sync::Arc;
+use structopt::StructOpt;
+
+#[derive(Debug, StructOpt)]
+#[structopt(
+    name = "ergothic_cli",
+    about = "Queries and aggregates data points exported by ergothic simulations."
+)]
+struct CmdArgs {
+    /// File written by a simulation run with --output_file. Can be given
+    /// multiple times.
+    /// Example: --file /shared/ergothic/my_simulation.jsonl
+    #[structopt(long = "file")]
+    files: Vec<String>,
+
+    /// MongoDB connection string to read data points from. Child arguments:
+    /// [--mongo_db, --mongo_coll].
+    /// Example: --mongo mongodb://localhost:27017/
+    #[structopt(long = "mongo")]
+    mongo: Option<String>,
+
+    /// MongoDB database name. Parent argument: --mongo.
+    /// Example: --mongo_db ergothic_data
+    #[structopt(long = "mongo_db")]
+    mongo_db: Option<String>,
+
+    /// MongoDB collection name. Parent argument: --mongo.
+    /// Example: --mongo_coll my_simulation
+    #[structopt(long = "mongo_coll")]
+    mongo_coll: Option<String>,
+
+    /// Only aggregate data points of the simulation with this name.
+    /// Example: --simulation Oscillator
+    #[structopt(long = "simulation")]
+    simulation: Option<String>,
+
+    /// Only aggregate data points flushed at or after this time, in seconds
+    /// since the UNIX epoch.
+    /// Example: --since 1530000000
+    #[structopt(long = "since")]
+    since: Option<i64>,
+
+    /// Only aggregate data points flushed at or before this time, in seconds
+    /// since the UNIX epoch.
+    /// Example: --until 1540000000
+    #[structopt(long = "until")]
+    until: Option<i64>,
+
+    /// Only output the measures with names matching this glob pattern.
+    /// Example: --measures 'G(*)'
+    #[structopt(long = "measures", default_value = "*")]
+    measures: String,
+
+    /// Derived measure computed from the expectation values of other measures,
+    /// defined as `name = expression`. Measures are referred to by their names
+    /// in curly braces, components of vector measures as `{name[i]}`. The
+    /// uncertainty is estimated with the jackknife method over the data points.
+    /// Can be given multiple times.
+    /// Example: --derived 'm_eff(1) = log({G[1]} / {G[2]})'
+    #[structopt(long = "derived")]
+    derived: Vec<String>,
+
+    /// Also estimate the errors with the bootstrap method, resampling the data
+    /// points with this many replicas. Child arguments: [--bootstrap_seed,
+    /// --confidence_level].
+    /// Example: --bootstrap 1000
+    #[structopt(long = "bootstrap")]
+    bootstrap: Option<usize>,
+
+    /// Seed of the bootstrap replicas. Parent argument: --bootstrap.
+    /// Example: --bootstrap_seed 42
+    #[structopt(long = "bootstrap_seed", default_value = "0")]
+    bootstrap_seed: u64,
+
+    /// Confidence level of the bootstrap percentile confidence intervals.
+    /// Parent argument: --bootstrap.
+    /// Example: --confidence_level 0.68
+    #[structopt(long = "confidence_level", default_value = "0.95")]
+    confidence_level: f64,
+}
+
+/// Parses the definition of a derived measure of the form `name = expression`.
+fn parse_derived(definition: &str) -> Result<(String, ergothic::Expression), String> {
+    let (name, expression) = match definition.split_once('=') {
+        Some((name, expression)) if !name.trim().is_empty() => (name.trim(), expression),
+        _ => {
+            return Err(format!(
+                "Invalid derived measure '{}', expected 'name = expression'.",
+                definition
+            ))
+        }
+    };
+    Ok((name.to_string(), ergothic::Expression::parse(expression)?))
+}
+
+/// Reads the data points from all sources given in the command line.
+fn read_data_points(args: &CmdArgs) -> Result<Vec<DataPoint>, String> {
+    let filter = ergothic::Filter {
+        simulation: args.simulation.clone(),
+        since: args.since,
+        until: args.until,
+    };
+    let mut data_points = Vec::new();
+    for file in args.files.iter() {
+        data_points.extend(ergothic::read_file(file, &filter).map_err(|err| err.0)?);
+    }
+    if let Some(ref mongo) = args.mongo {
+        let mongo_db = args
+            .mongo_db
+            .as_ref()
+            .ok_or("Child argument --mongo_db is required.")?;
+        let mongo_coll = args
+            .mongo_coll
+            .as_ref()
+            .ok_or("Child argument --mongo_coll is required.")?;
+        data_points.extend(
+            ergothic::read_mongo(mongo, mongo_db, mongo_coll, &filter).map_err(|err| err.0)?,
+        );
+    }
+    Ok(data_points)
+}
+
+/// Data points of a simulation with the same physics parameters.
+struct Group<'a> {
+    params: &'a BTreeMap<String, Value>,
+    aggregated: Measures,
+    data_points: Vec<&'a DataPoint>,
+    hosts: BTreeSet<String>,
+}
+
+/// Orders the physics parameters by their values, numbers numerically.
+fn compare_params(a: &BTreeMap<String, Value>, b: &BTreeMap<String, Value>) -> Ordering {
+    let compare_values = |a: &Value, b: &Value| match (a.as_f64(), b.as_f64()) {
+        (Some(a), Some(b)) => a.partial_cmp(&b).unwrap_or(Ordering::Equal),
+        _ => a.to_string().cmp(&b.to_string()),
+    };
+    for ((name_a, a), (name_b, b)) in a.iter().zip(b.iter()) {
+        let ordering = name_a.cmp(name_b).then_with(|| compare_values(a, b));
+        if ordering != Ordering::Equal {
+            return ordering;
+        }
+    }
+    a.len().cmp(&b.len())
+}
+
+fn run(args: CmdArgs) -> Result<(), String> {
+    if args.files.is_empty() && args.mongo.is_none() {
+        return Err("At least one of --file or --mongo is required.".to_string());
+    }
+    let derived = args
+        .derived
+        .iter()
+        .map(|definition| parse_derived(definition))
+        .collect::<Result<Vec<_>, _>>()?;
+    // Every group of data points starts with the derived measures.
+    let mut template = Measures::new_empty();
+    for (name, expression) in derived {
+        template.add_derived_measure(
+            name,
+            expression.inputs().to_vec(),
+            Arc::new(move |inputs: &[f64]| expression.eval(inputs)),
+        )?;
+    }
+    if args.confidence_level <= 0.0 || args.confidence_level >= 1.0 {
+        return Err("Argument --confidence_level should lie within (0, 1).".to_string());
+    }
+    if args.bootstrap == Some(0) {
+        return Err("Argument --bootstrap should be positive.".to_string());
+    }
+    let data_points = read_data_points(&args)?;
+    if data_points.is_empty() {
+        println!("No data points found.");
+        return Ok(());
+    }
+
+    // Data points of different simulations, or with different parameters, are
+    // never merged together. Each data point is a jackknife block for the
+    // derived measures.
+    let new_measures = || template.clone();
+    let mut simulations: BTreeMap<String, Vec<Group>> = BTreeMap::new();
+    for data_point in data_points.iter() {
+        let groups = simulations
+            .entry(data_point.simulation.clone())
+            .or_default();
+        let position = match groups
+            .iter()
+            .position(|group| *group.params == data_point.params)
+        {
+            Some(position) => position,
+            None => {
+                groups.push(Group {
+                    params: &data_point.params,
+                    aggregated: new_measures(),
+                    data_points: Vec::new(),
+                    hosts: BTreeSet::new(),
+                });
+                groups.len() - 1
+            }
+        };
+        let group = &mut groups[position];
+        if let Err(err) = group.aggregated.merge_block(&data_point.measures) {
+            warn!(
+                "Data point of {} with seed {}: {}",
+                data_point.host, data_point.seed, err
+            );
+        }
+        group.data_points.push(data_point);
+        group.hosts.insert(data_point.host.clone());
+    }
+
+    for (simulation, mut groups) in simulations {
+        groups.sort_by(|a, b| compare_params(a.params, b.params));
+        for group in groups.iter_mut() {
+            print_group(&args, &simulation, group);
+        }
+        if groups.len() > 1 {
+            println!();
+            println!("Simulation: {}", simulation);
+            println!("Expectation values by parameters:");
+            let points: Vec<_> = groups
+                .iter()
+                .map(|group| (group.params, &group.aggregated))
+                .collect();
+            ergothic::DebugExporter::sweep_table(&points).printstd();
+        }
+    }
+    Ok(())
+}
+
+/// Outputs the aggregate values of a group of data points. Only keeps the
+/// measures selected with --measures in the group.
+fn print_group(args: &CmdArgs, simulation: &str, group: &mut Group) {
+    let aggregated = &mut group.aggregated;
+    let data_points = &group.data_points;
+    let samples_processed = aggregated.num_of_samples();
+    // Resampling needs all measures, since derived measures depend on them.
+    let estimates = args.bootstrap.map(|replicas| {
+        let params = ergothic::BootstrapParams {
+            replicas,
+            seed: args.bootstrap_seed,
+            confidence_level: args.confidence_level,
+        };
+        ergothic::bootstrap(aggregated, data_points, &params)
+    });
+    aggregated.retain(|name| ergothic::glob_matches(&args.measures, name));
+    println!();
+    println!("Simulation: {}", simulation);
+    if !group.params.is_empty() {
+        println!(
+            "Parameters: {}",
+            ergothic::DebugExporter::format_params(group.params)
+        );
+    }
+    println!(
+        "Data points: {} from {} hosts",
+        data_points.len(),
+        group.hosts.len()
+    );
+    println!("Samples processed: {}", samples_processed);
+    println!("Aggregate values:");
+    ergothic::DebugExporter::pretty_table(aggregated).printstd();
+    if let Some(table) = ergothic::DebugExporter::counter_table(aggregated) {
+        table.printstd();
+    }
+    if let (Some(replicas), Some(mut estimates)) = (args.bootstrap, estimates) {
+        println!(
+            "Bootstrap estimates ({} replicas, {}% confidence intervals):",
+            replicas,
+            args.confidence_level * 100.0
+        );
+        // Components of vector measures are selected by the name of the
+        // vector measure.
+        estimates.retain(|estimate| {
+            let name = &estimate.name;
+            let base = name.rfind('[').map_or(&name[..], |open| &name[..open]);
+            ergothic::glob_matches(&args.measures, name)
+                || ergothic::glob_matches(&args.measures, base)
+        });
+        ergothic::BootstrapEstimate::pretty_table(&estimates).printstd();
+    }
+    for measure in aggregated.histogram_slice() {
+        println!();
+        print!("{}", ergothic::DebugExporter::histogram_chart(measure));
+    }
+}
+
+fn main() {
+    // Reports skipped incomplete data points, among other warnings.
+    ::simple_logger::init_with_level(::log::Level::Warn).expect("Failed to initialize logger");
+    if let Err(err) = run(CmdArgs::from_args()) {
+        eprintln!("ergothic_cli: {}", err);
+        ::std::process::exit(1);
+    }
+}
@@ -0,0 +1,320 @@
+use export::DataPoint;
+use measure::Measures;
+use rand::rngs::StdRng;
+use rand::Rng;
+use rand::SeedableRng;
+
+/// Parameters of the bootstrap resampling.
+#[derive(Clone, Debug)]
+pub struct BootstrapParams {
+    /// Number of bootstrap replicas.
+    pub replicas: usize,
+
+    /// Seed of the random number generator drawing the replicas.
+    pub seed: u64,
+
+    /// Confidence level of the percentile confidence intervals, e.g. 0.95.
+    pub confidence_level: f64,
+}
+
+impl Default for BootstrapParams {
+    fn default() -> BootstrapParams {
+        BootstrapParams {
+            replicas: 1000,
+            seed: 0,
+            confidence_level: 0.95,
+        }
+    }
+}
+
+/// Bootstrap estimates for a single measure.
+#[derive(Clone, Debug)]
+pub struct BootstrapEstimate {
+    /// Name of the measure. Components of vector measures are named `name[i]`.
+    pub name: String,
+
+    /// Mean of the values over the replicas.
+    pub mean: f64,
+
+    /// Standard deviation of the values over the replicas.
+    pub standard_error: f64,
+
+    /// Lower bound of the percentile confidence interval.
+    pub lower: f64,
+
+    /// Upper bound of the percentile confidence interval.
+    pub upper: f64,
+}
+
+impl BootstrapEstimate {
+    /// Format the estimates in a pretty table.
+    pub fn pretty_table(estimates: &[BootstrapEstimate]) -> ::prettytable::Table {
+        use prettytable::format::Alignment;
+        use prettytable::Cell;
+        use prettytable::Row;
+        use prettytable::Table;
+        let mut table = Table::new();
+        table.set_format(*::prettytable::format::consts::FORMAT_NO_LINESEP_WITH_TITLE);
+        table.set_titles(Row::new(vec![
+            Cell::new_align("MEASURE", Alignment::CENTER),
+            Cell::new_align("BOOTSTRAP MEAN", Alignment::CENTER),
+            Cell::new_align("STANDARD ERROR", Alignment::CENTER),
+            Cell::new_align("CONFIDENCE INTERVAL", Alignment::CENTER),
+        ]));
+        for estimate in estimates {
+            table.add_row(Row::new(vec![
+                Cell::new_align(&estimate.name, Alignment::RIGHT),
+                Cell::new(&format!("{}", estimate.mean)),
+                Cell::new(&format!("{}", estimate.standard_error)),
+                Cell::new(&format!("[{}, {}]", estimate.lower, estimate.upper)),
+            ]));
+        }
+        table
+    }
+}
+
+/// Estimates the statistical errors of the measures with the bootstrap method.
+/// The data points are independent blocks of samples: every replica draws as
+/// many data points as there are, with replacement, and merges them. The
+/// spread of the values over the replicas gives the errors, and does not
+/// assume that all nodes produce data of equal quality.
+/// The estimates are given for the means of all scalar, moment and counter
+/// measures, rates of rate measures, parts of complex measures, components of
+/// vector measures and derived measures of `measures`, which are usually the
+/// measures aggregated over the `data_points`.
+pub fn bootstrap(
+    measures: &Measures,
+    data_points: &[&DataPoint],
+    params: &BootstrapParams,
+) -> Vec<BootstrapEstimate> {
+    // Names of the measures merged in the replicas.
+    let mut names: Vec<String> = measures
+        .slice()
+        .iter()
+        .map(|measure| measure.name.clone())
+        .collect();
+    names.extend(
+        measures
+            .moment_slice()
+            .iter()
+            .map(|measure| measure.name.clone()),
+    );
+    names.extend(
+        measures
+            .counter_slice()
+            .iter()
+            .map(|measure| measure.name.clone()),
+    );
+    names.extend(
+        measures
+            .rate_slice()
+            .iter()
+            .map(|measure| measure.name.clone()),
+    );
+    for measure in measures.complex_slice() {
+        names.push(format!("Re({})", measure.name));
+        names.push(format!("Im({})", measure.name));
+    }
+    for measure in measures.vector_slice() {
+        for i in 0..measure.acc.len() {
+            names.push(format!("{}[{}]", measure.name, i));
+        }
+    }
+    // Weight and mean of every measure in every data point.
+    let stats: Vec<Vec<Option<(f64, f64)>>> = data_points
+        .iter()
+        .map(|data_point| {
+            names
+                .iter()
+                .map(|name| data_point.measures.stats(name))
+                .collect()
+        })
+        .collect();
+    let derived = measures.derived_slice();
+    // Positions of the inputs of derived measures among `names`.
+    let derived_inputs: Vec<Vec<Option<usize>>> = derived
+        .iter()
+        .map(|derived| {
+            derived
+                .inputs
+                .iter()
+                .map(|input| names.iter().position(|name| name == input))
+                .collect()
+        })
+        .collect();
+
+    let mut rng = StdRng::seed_from_u64(params.seed);
+    let mut values = vec![Vec::with_capacity(params.replicas); names.len() + derived.len()];
+    let mut totals = vec![(0.0, 0.0); names.len()];
+    for _ in 0..params.replicas {
+        for total in totals.iter_mut() {
+            *total = (0.0, 0.0);
+        }
+        for _ in 0..data_points.len() {
+            let drawn = &stats[rng.gen_range(0..data_points.len())];
+            for (total, stats) in totals.iter_mut().zip(drawn.iter()) {
+                if let Some((weight, mean)) = *stats {
+                    total.0 += weight;
+                    total.1 += weight * mean;
+                }
+            }
+        }
+        let means: Vec<f64> = totals.iter().map(|&(weight, sum)| sum / weight).collect();
+        for (values, mean) in values.iter_mut().zip(means.iter()) {
+            values.push(*mean);
+        }
+        for (i, derived) in derived.iter().enumerate() {
+            let inputs: Vec<f64> = derived_inputs[i]
+                .iter()
+                .map(|pos| pos.map_or(f64::NAN, |pos| means[pos]))
+                .collect();
+            values[names.len() + i].push(derived.eval(&inputs));
+        }
+    }
+
+    names.extend(derived.iter().map(|derived| derived.name.clone()));
+    names
+        .into_iter()
+        .zip(values)
+        .map(|(name, values)| estimate(name, values, params.confidence_level))
+        .collect()
+}
+
+/// Computes the estimates from the values of a measure over the replicas.
+/// Replicas in which the measure is undefined are ignored.
+fn estimate(name: String, mut values: Vec<f64>, confidence_level: f64) -> BootstrapEstimate {
+    values.retain(|value| !value.is_nan());
+    values.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let n = values.len() as f64;
+    let mean = values.iter().sum::<f64>() / n;
+    let variance = values
+        .iter()
+        .map(|value| (value - mean).powi(2))
+        .sum::<f64>()
+        / (n - 1.0);
+    BootstrapEstimate {
+        name,
+        mean,
+        standard_error: variance.sqrt(),
+        lower: percentile(&values, (1.0 - confidence_level) / 2.0),
+        upper: percentile(&values, (1.0 + confidence_level) / 2.0),
+    }
+}
+
+/// Gives the `p`-th quantile of `sorted` values, interpolating linearly
+/// between the closest ranks.
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    if sorted.is_empty() {
+        return f64::NAN;
+    }
+    let rank = p * (sorted.len() - 1) as f64;
+    let below = rank.floor() as usize;
+    let above = rank.ceil() as usize;
+    sorted[below] + (sorted[above] - sorted[below]) * (rank - below as f64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use measure::MeasureRegistry;
+    use std::collections::BTreeMap;
+    use std::sync::Arc;
+
+    /// Data points with a single measure `X`, each holding one of `values`.
+    fn data_points(values: &[f64]) -> Vec<DataPoint> {
+        values
+            .iter()
+            .map(|value| {
+                let mut reg = MeasureRegistry::new();
+                let x = reg.register("X".to_string());
+                let mut measures = reg.freeze();
+                measures.accumulate(x, *value);
+                DataPoint::new("Sim", "node1", 0, &BTreeMap::new(), &measures)
+            })
+            .collect()
+    }
+
+    /// Runs the bootstrap of `X` and of the derived measure `2X`.
+    fn run(values: &[f64], seed: u64) -> Vec<BootstrapEstimate> {
+        let data_points = data_points(values);
+        let mut measures = Measures::new_empty();
+        for data_point in data_points.iter() {
+            measures.merge(&data_point.measures).unwrap();
+        }
+        measures
+            .add_derived_measure(
+                "2X".to_string(),
+                vec!["X".to_string()],
+                Arc::new(|inputs: &[f64]| 2.0 * inputs[0]),
+            )
+            .unwrap();
+        let params = BootstrapParams {
+            replicas: 2000,
+            seed,
+            confidence_level: 0.9,
+        };
+        bootstrap(&measures, &data_points.iter().collect::<Vec<_>>(), &params)
+    }
+
+    #[test]
+    fn bootstrap_estimates_error_of_mean() {
+        // The standard error of the mean of 1..=20 is the standard deviation
+        // of the values over the square root of their number.
+        let values: Vec<f64> = (1..=20).map(f64::from).collect();
+        let estimates = run(&values, 7);
+        let names: Vec<&str> = estimates.iter().map(|e| &e.name[..]).collect();
+        assert_eq!(names, vec!["X", "2X"]);
+
+        let x = &estimates[0];
+        let expected = (399.0_f64 / 12.0).sqrt() / 20.0_f64.sqrt();
+        assert!((x.mean - 10.5).abs() < 0.1, "{}", x.mean);
+        assert!(
+            (x.standard_error / expected - 1.0).abs() < 0.1,
+            "{}",
+            x.standard_error
+        );
+        // The 90% interval spans about 1.645 standard errors on each side.
+        assert!(x.lower < x.mean && x.mean < x.upper);
+        let half_width = (x.upper - x.lower) / 2.0;
+        assert!(
+            (half_width / (1.645 * expected) - 1.0).abs() < 0.15,
+            "{}",
+            half_width
+        );
+
+        let derived = &estimates[1];
+        assert!((derived.mean - 2.0 * x.mean).abs() < 1e-9);
+        assert!((derived.standard_error - 2.0 * x.standard_error).abs() < 1e-9);
+    }
+
+    #[test]
+    fn bootstrap_is_reproducible() {
+        let values = [0.3, 1.2, -0.7, 2.5, 0.1, 0.9];
+        let estimates = |seed| -> Vec<(f64, f64, f64, f64)> {
+            run(&values, seed)
+                .iter()
+                .map(|e| (e.mean, e.standard_error, e.lower, e.upper))
+                .collect()
+        };
+        assert_eq!(estimates(1), estimates(1));
+        assert_ne!(estimates(1), estimates(2));
+    }
+
+    #[test]
+    fn bootstrap_of_constant_data_has_no_spread() {
+        let x = &run(&[4.0; 5], 0)[0];
+        assert_eq!(
+            (x.mean, x.standard_error, x.lower, x.upper),
+            (4.0, 0.0, 4.0, 4.0)
+        );
+    }
+
+    #[test]
+    fn percentile_interpolates_between_ranks() {
+        let sorted = [1.0, 2.0, 3.0, 4.0];
+        assert_eq!(percentile(&sorted, 0.0), 1.0);
+        assert_eq!(percentile(&sorted, 0.5), 2.5);
+        assert_eq!(percentile(&sorted, 1.0), 4.0);
+        assert!(percentile(&[], 0.5).is_nan());
+    }
+}
@@ -0,0 +1,118 @@
+use measure::Measures;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use simulation::Sample;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+
+/// Random number generator of a chain. The same generator as
+/// `rand::rngs::StdRng`, but its state can be saved to checkpoints.
+pub type ChainRng = ::rand_chacha::ChaCha12Rng;
+
+/// An optional extension of `Sample` for configurations that can be saved to
+/// disk and restored later, so that an expensive thermalization is not thrown
+/// away when the simulation restarts. Samples are saved with `serde`, so
+/// implementing this trait is usually as easy as deriving `Serialize` and
+/// `Deserialize` and writing `impl Checkpointable for MySample {}`.
+pub trait Checkpointable: Sample + Serialize + DeserializeOwned {}
+
+/// Contents of a checkpoint file.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct Checkpoint {
+    /// The name of the simulation that wrote the checkpoint.
+    pub simulation: String,
+
+    /// The physics parameters of the simulation that wrote the checkpoint, see
+    /// `Sample::Params`. Missing in checkpoints written by older versions.
+    #[serde(default)]
+    pub params: Option<BTreeMap<String, Value>>,
+
+    /// Time of writing the checkpoint in seconds since the UNIX epoch.
+    pub timestamp: i64,
+
+    /// The configuration samples of all chains. Chains which haven't finished
+    /// thermalizing yet are saved as `null`.
+    pub samples: Vec<Value>,
+
+    /// The random number generators of the chains with saved samples, so that
+    /// resumed chains continue their random number streams instead of
+    /// replaying them. Missing in checkpoints written by older versions.
+    #[serde(default)]
+    pub rngs: Vec<Option<ChainRng>>,
+
+    /// The generator seeding the chains which have to prepare new samples, so
+    /// that they don't replay the streams of the chains of the previous run.
+    #[serde(default)]
+    pub seeder: Option<ChainRng>,
+
+    /// Measured values which haven't been exported yet.
+    pub measures: Measures,
+}
+
+impl Checkpoint {
+    /// Reads a checkpoint from the file at `path`.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Checkpoint, String> {
+        let path = path.as_ref();
+        let file = File::open(path)
+            .map_err(|err| format!("Failed to open {}: {}", path.display(), err))?;
+        ::serde_json::from_reader(BufReader::new(file))
+            .map_err(|err| format!("Failed to read {}: {}", path.display(), err))
+    }
+
+    /// Writes the checkpoint to the file at `path`. The file is replaced
+    /// atomically, so that a node killed mid-write leaves the previous
+    /// checkpoint intact.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), String> {
+        let path = path.as_ref();
+        let tmp_path = PathBuf::from(format!("{}.tmp", path.display()));
+        let write = || -> ::std::io::Result<()> {
+            let mut writer = BufWriter::new(File::create(&tmp_path)?);
+            ::serde_json::to_writer(&mut writer, self)?;
+            writer.flush()?;
+            writer.get_ref().sync_all()?;
+            ::std::fs::rename(&tmp_path, path)?;
+            if let Some(dir) = path.parent() {
+                let dir = if dir.as_os_str().is_empty() {
+                    Path::new(".")
+                } else {
+                    dir
+                };
+                File::open(dir)?.sync_all()?;
+            }
+            Ok(())
+        };
+        write().map_err(|err| format!("Failed to write {}: {}", path.display(), err))
+    }
+}
+
+/// Converts configuration samples to and from their representation in
+/// checkpoints. Keeps the `serde` bounds out of the simulation engine, which
+/// also runs samples that are not `Checkpointable`.
+pub struct Codec<S> {
+    pub encode: fn(&S) -> Result<Value, String>,
+    pub decode: fn(Value) -> Result<S, String>,
+}
+
+impl<S: Checkpointable> Codec<S> {
+    /// Constructs a codec for a checkpointable sample type.
+    pub fn new() -> Codec<S> {
+        Codec {
+            encode: encode::<S>,
+            decode: decode::<S>,
+        }
+    }
+}
+
+fn encode<S: Checkpointable>(sample: &S) -> Result<Value, String> {
+    ::serde_json::to_value(sample).map_err(|err| format!("Failed to save a sample: {}", err))
+}
+
+fn decode<S: Checkpointable>(value: Value) -> Result<S, String> {
+    ::serde_json::from_value(value).map_err(|err| format!("Failed to restore a sample: {}", err))
+}
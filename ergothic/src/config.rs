@@ -0,0 +1,369 @@
+use error::Error;
+use serde::de::value::StringDeserializer;
+use serde::de::DeserializeOwned;
+use serde::de::DeserializeSeed;
+use serde::de::Deserializer;
+use serde::de::IntoDeserializer;
+use serde::de::MapAccess;
+use serde::de::Visitor;
+use serde_json::Value;
+use std::cell::RefCell;
+use std::collections::btree_map;
+use std::collections::BTreeMap;
+use std::path::Path;
+
+/// Contents of a configuration file given with `--config`. The keys are named
+/// after the command line arguments, e.g. `flush_interval_secs = 600`, and
+/// command line arguments override the values from the file. Unknown keys are
+/// rejected, so that typos don't go unnoticed. The physics parameters of the
+/// simulation go into the `params` table, and the swept ones into the `sweep`
+/// table.
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigFile {
+    pub production: Option<bool>,
+    pub mongo: Option<String>,
+    pub mongo_db: Option<String>,
+    pub mongo_coll: Option<String>,
+    pub output_file: Option<String>,
+    pub output_file_max_mb: Option<u64>,
+    pub flush_interval_secs: Option<u64>,
+    pub flush_interval_randomization: Option<f64>,
+    pub threads: Option<usize>,
+    pub seed: Option<u64>,
+    pub max_samples: Option<u64>,
+    pub max_time_secs: Option<u64>,
+    pub target_relative_uncertainty: Option<f64>,
+    #[serde(default)]
+    pub target_measure: Vec<String>,
+    pub checkpoint: Option<String>,
+    pub checkpoint_interval_secs: Option<u64>,
+    pub resume: Option<String>,
+    pub final_export_deadline_secs: Option<u64>,
+    pub max_errors_in_row: Option<usize>,
+    pub node_index: Option<usize>,
+
+    /// User-defined physics parameters, e.g. the coupling and the lattice
+    /// size, see `Sample::Params`.
+    #[serde(default)]
+    pub params: BTreeMap<String, Value>,
+
+    /// Physics parameters swept over grids of values, e.g.
+    /// `beta = [5.6, 5.7, 5.8]`, see `RunConfig::sweep`.
+    #[serde(default)]
+    pub sweep: BTreeMap<String, Vec<Value>>,
+}
+
+impl ConfigFile {
+    /// Reads a configuration file in the TOML (`.toml`) or YAML (`.yaml`,
+    /// `.yml`) format, depending on the extension.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<ConfigFile, Error> {
+        let path = path.as_ref();
+        let contents = ::std::fs::read_to_string(path).map_err(|err| {
+            Error::Config(format!(
+                "Failed to read {} (--config): {}",
+                path.display(),
+                err
+            ))
+        })?;
+        let extension = path.extension().and_then(|extension| extension.to_str());
+        let parsed = match extension {
+            Some("toml") => ::toml::from_str(&contents).map_err(|err| err.to_string()),
+            Some("yaml") | Some("yml") => {
+                ::serde_yaml::from_str(&contents).map_err(|err| err.to_string())
+            }
+            _ => {
+                return Err(Error::Config(format!(
+                    "Unknown format of {} (--config), expected a .toml, .yaml or .yml file.",
+                    path.display()
+                )))
+            }
+        };
+        parsed.map_err(|err| Error::Config(format!("{} (--config): {}", path.display(), err)))
+    }
+}
+
+/// Deserializes the physics parameters of the simulation into `P`. Rejects the
+/// parameters which `P` ignores, even if it doesn't deny unknown fields, so that
+/// misspelled parameters don't silently take their default values.
+pub fn deserialize_params<P: DeserializeOwned>(
+    params: BTreeMap<String, Value>,
+) -> Result<P, String> {
+    let ignored = RefCell::new(Vec::new());
+    let typed = P::deserialize(ParamsDeserializer {
+        params,
+        ignored: &ignored,
+    })
+    .map_err(|err| err.to_string())?;
+    let ignored = ignored.into_inner();
+    if !ignored.is_empty() {
+        return Err(format!("unknown parameters {}", ignored.join(", ")));
+    }
+    Ok(typed)
+}
+
+/// Deserializes a table of parameters as a map, recording the names of the
+/// parameters whose values are ignored.
+struct ParamsDeserializer<'a> {
+    params: BTreeMap<String, Value>,
+    ignored: &'a RefCell<Vec<String>>,
+}
+
+impl<'de, 'a> Deserializer<'de> for ParamsDeserializer<'a> {
+    type Error = ::serde_json::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_map(ParamsAccess {
+            params: self.params.into_iter(),
+            value: None,
+            ignored: self.ignored,
+        })
+    }
+
+    ::serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+}
+
+/// Gives the parameters of a `ParamsDeserializer` one by one.
+struct ParamsAccess<'a> {
+    params: btree_map::IntoIter<String, Value>,
+    /// The parameter whose name has been given last.
+    value: Option<(String, Value)>,
+    ignored: &'a RefCell<Vec<String>>,
+}
+
+impl<'de, 'a> MapAccess<'de> for ParamsAccess<'a> {
+    type Error = ::serde_json::Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, Self::Error> {
+        match self.params.next() {
+            Some((name, value)) => {
+                let deserializer: StringDeserializer<Self::Error> =
+                    name.clone().into_deserializer();
+                let key = seed.deserialize(deserializer)?;
+                self.value = Some((name, value));
+                Ok(Some(key))
+            }
+            None => Ok(None),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(
+        &mut self,
+        seed: V,
+    ) -> Result<V::Value, Self::Error> {
+        let (name, value) = self
+            .value
+            .take()
+            .expect("MapAccess::next_value called before next_key");
+        seed.deserialize(ParamDeserializer {
+            name,
+            value,
+            ignored: self.ignored,
+        })
+    }
+}
+
+/// Deserializes the value of the parameter `name`, recording the name if the
+/// value is ignored. Otherwise, the same as deserializing `value`.
+struct ParamDeserializer<'a> {
+    name: String,
+    value: Value,
+    ignored: &'a RefCell<Vec<String>>,
+}
+
+/// Implements the methods of `Deserializer` taking no arguments but the
+/// visitor by deserializing the value of the parameter.
+macro_rules! forward_to_value {
+    ($($method:ident)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+                self.value.$method(visitor)
+            }
+        )*
+    };
+}
+
+impl<'de, 'a> Deserializer<'de> for ParamDeserializer<'a> {
+    type Error = ::serde_json::Error;
+
+    forward_to_value! {
+        deserialize_any deserialize_bool deserialize_i8 deserialize_i16
+        deserialize_i32 deserialize_i64 deserialize_i128 deserialize_u8
+        deserialize_u16 deserialize_u32 deserialize_u64 deserialize_u128
+        deserialize_f32 deserialize_f64 deserialize_char deserialize_str
+        deserialize_string deserialize_bytes deserialize_byte_buf
+        deserialize_option deserialize_unit deserialize_seq deserialize_map
+        deserialize_identifier
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.value.deserialize_unit_struct(name, visitor)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.value.deserialize_newtype_struct(name, visitor)
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.value.deserialize_tuple(len, visitor)
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.value.deserialize_tuple_struct(name, len, visitor)
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.value.deserialize_struct(name, fields, visitor)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        name: &'static str,
+        variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        self.value.deserialize_enum(name, variants, visitor)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        self.ignored.borrow_mut().push(self.name);
+        self.value.deserialize_ignored_any(visitor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Writes `contents` to a temporary file named `name`, and gives its path.
+    fn config_file(name: &str, contents: &str) -> ::std::path::PathBuf {
+        let path = ::std::env::temp_dir()
+            .join(format!("ergothic-config-{}", ::std::process::id()))
+            .join(name);
+        ::std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        ::std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    #[test]
+    fn loads_toml_and_yaml_files() {
+        let toml = config_file(
+            "run.toml",
+            "production = true\nthreads = 4\ntarget_measure = [\"E\"]\n\n\
+             [params]\nsize = 16\n\n[sweep]\nbeta = [5.6, 5.7]\n",
+        );
+        let yaml = config_file(
+            "run.yml",
+            "production: true\nthreads: 4\ntarget_measure: [E]\n\
+             params:\n  size: 16\nsweep:\n  beta: [5.6, 5.7]\n",
+        );
+        for path in &[toml, yaml] {
+            let file = ConfigFile::load(path).unwrap();
+            assert_eq!(file.production, Some(true));
+            assert_eq!(file.threads, Some(4));
+            assert_eq!(file.seed, None);
+            assert_eq!(file.target_measure, vec!["E".to_string()]);
+            assert_eq!(file.params["size"], ::serde_json::json!(16));
+            assert_eq!(
+                file.sweep["beta"],
+                vec![::serde_json::json!(5.6), ::serde_json::json!(5.7)]
+            );
+        }
+    }
+
+    #[test]
+    fn rejects_unknown_keys_and_formats() {
+        let path = config_file("typo.toml", "thread = 4\n");
+        match ConfigFile::load(&path) {
+            Err(Error::Config(err)) => assert!(err.contains("thread"), "{}", err),
+            _ => panic!("Accepted an unknown key."),
+        }
+        let path = config_file("run.json", "{\"threads\": 4}");
+        match ConfigFile::load(&path) {
+            Err(Error::Config(err)) => assert!(err.contains("Unknown format"), "{}", err),
+            _ => panic!("Accepted an unknown format."),
+        }
+        assert!(ConfigFile::load(path.with_file_name("missing.toml")).is_err());
+    }
+
+    /// Parameters which don't deny unknown fields.
+    #[derive(Debug, Deserialize, PartialEq)]
+    struct Params {
+        size: usize,
+        #[serde(default)]
+        beta: Option<f64>,
+        #[serde(default)]
+        boundary: Boundary,
+    }
+
+    #[derive(Debug, Default, Deserialize, PartialEq)]
+    enum Boundary {
+        #[default]
+        Periodic,
+        Open,
+    }
+
+    /// Parses `json` into a table of parameters.
+    fn params(json: &str) -> BTreeMap<String, Value> {
+        ::serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn deserializes_params() {
+        assert_eq!(
+            deserialize_params::<Params>(params(r#"{"size": 8}"#)),
+            Ok(Params {
+                size: 8,
+                beta: None,
+                boundary: Boundary::Periodic,
+            })
+        );
+        assert_eq!(
+            deserialize_params::<Params>(params(r#"{"size": 8, "beta": 5.7, "boundary": "Open"}"#)),
+            Ok(Params {
+                size: 8,
+                beta: Some(5.7),
+                boundary: Boundary::Open,
+            })
+        );
+        let err = deserialize_params::<Params>(params(r#"{"beta": 5.7}"#)).unwrap_err();
+        assert!(err.contains("size"), "{}", err);
+    }
+
+    #[test]
+    fn rejects_params_ignored_by_deserializer() {
+        assert_eq!(
+            deserialize_params::<Params>(params(r#"{"size": 8, "sise": 9, "bta": 5.7}"#)),
+            Err("unknown parameters bta, sise".to_string())
+        );
+    }
+}
@@ -0,0 +1,43 @@
+use export::ExportError;
+use std::fmt;
+
+/// Errors of setting up and running a simulation.
+#[derive(Debug)]
+pub enum Error {
+    /// The simulation is misconfigured, e.g. by conflicting command line
+    /// arguments. Contains a message telling how to fix the configuration.
+    Config(String),
+
+    /// The measured values couldn't be exported to the data sink. Some of them
+    /// have been lost, unless they have been saved to a checkpoint.
+    Export(ExportError),
+
+    /// A configuration sample couldn't be saved to or restored from a
+    /// checkpoint.
+    Sample(String),
+
+    /// A thread running a grid point of a sweep has panicked, e.g. in
+    /// `Sample::mutate` or in the measure function. Contains the panic message.
+    Panic(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Error::Config(ref err) => write!(f, "Invalid configuration: {}", err),
+            Error::Export(ExportError(ref err)) => {
+                write!(f, "Failed to export the measured values: {}", err)
+            }
+            Error::Sample(ref err) => write!(f, "{}", err),
+            Error::Panic(ref err) => write!(f, "A grid point of the sweep has panicked: {}", err),
+        }
+    }
+}
+
+impl ::std::error::Error for Error {}
+
+impl From<ExportError> for Error {
+    fn from(err: ExportError) -> Error {
+        Error::Export(err)
+    }
+}
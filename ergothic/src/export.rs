@@ -1,40 +1,135 @@
-use measure::MeasureRegistry;
+use measure::HistogramMeasure;
 use measure::Measures;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::fs::OpenOptions;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::path::Path;
+use std::path::PathBuf;
+use std::sync::mpsc::channel;
+use std::sync::mpsc::Sender;
 use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
 
 /// Errors returned by the exporter. Contain a string describing the cause of
 /// the error.
 #[derive(Debug)]
 pub struct ExportError(pub String);
 
+/// A data point is a snapshot of the measures accumulated during one flush
+/// interval, together with the information identifying its origin. Data points
+/// are the unit of exchange between the simulation engine and the data sinks.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct DataPoint {
+    /// The name of the simulation that produced the data point.
+    pub simulation: String,
+
+    /// Time of the flush in seconds since the UNIX epoch.
+    pub timestamp: i64,
+
+    /// Identifies the node that produced the data point.
+    pub host: String,
+
+    /// Seed of the random number generators of the node. Running the
+    /// simulation with `--seed` set to this value and the same number of
+    /// threads reproduces the chains of the node. Zero for data points exported
+    /// before seeds were recorded.
+    #[serde(default)]
+    pub seed: u64,
+
+    /// Physics parameters of the simulation, e.g. the coupling, as seen by the
+    /// samples (see `Sample::Params`). Empty if there are none.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub params: BTreeMap<String, Value>,
+
+    /// The accumulated values of the measures.
+    pub measures: Measures,
+}
+
+impl DataPoint {
+    /// Constructs a data point timestamped with the current time.
+    pub fn new(
+        simulation: &str,
+        host: &str,
+        seed: u64,
+        params: &BTreeMap<String, Value>,
+        measures: &Measures,
+    ) -> DataPoint {
+        DataPoint {
+            simulation: simulation.to_string(),
+            timestamp: unix_timestamp(),
+            host: host.to_string(),
+            seed,
+            params: params.clone(),
+            measures: measures.clone(),
+        }
+    }
+}
+
+/// Formats the value of a physics parameter, strings without the quotes.
+fn format_value(value: &Value) -> String {
+    match *value {
+        Value::String(ref value) => value.clone(),
+        ref value => value.to_string(),
+    }
+}
+
+/// Returns the current time in seconds since the UNIX epoch.
+pub fn unix_timestamp() -> i64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(since_epoch) => since_epoch.as_secs() as i64,
+        Err(_) => 0,
+    }
+}
+
 /// An interface to a data sink accepting accumulated expectation values.
 pub trait Exporter {
     /// Performs a single export operation. Note that it does not reset the
     /// accumulated values, which is the job of the simulation engine.
-    fn export(&mut self, measures: &Measures) -> Result<(), ExportError>;
+    fn export(&mut self, data_point: &DataPoint) -> Result<(), ExportError>;
 }
 
 /// Keeps a copy of measures. On `export(..)`, merges the reported data and
-/// outputs the accumulated values to stdout.
+/// outputs the accumulated values to stdout. Data points with different physics
+/// parameters, e.g. from the grid points of a sweep, are accumulated
+/// separately.
 pub struct DebugExporter {
-    aggregated: MeasureRegistry,
+    aggregated: Vec<(BTreeMap<String, Value>, Measures)>,
     creation_timestamp: SystemTime,
 }
 
+impl Default for DebugExporter {
+    fn default() -> DebugExporter {
+        DebugExporter::new()
+    }
+}
+
 impl DebugExporter {
     /// Constructs a new DebugExporter.
     pub fn new() -> DebugExporter {
         DebugExporter {
-            aggregated: MeasureRegistry::new(),
+            aggregated: Vec::new(),
             creation_timestamp: SystemTime::now(),
         }
     }
 
-    /// Format the results in a pretty table.
-    fn pretty_table(measures: &Measures) -> ::prettytable::Table {
-        use prettytable::cell::Cell;
+    /// Format the results in a pretty table. Vector measures are listed
+    /// component by component, as `name[i]`. Moment measures are listed with
+    /// their mean, skewness, excess kurtosis and Binder cumulant. Complex
+    /// measures are listed with the real and imaginary parts, the modulus and
+    /// the phase of their expectation value, as `Re(name)`, `Im(name)`,
+    /// `|name|` and `arg(name)`. Quantile measures are listed with the
+    /// estimates of their median and their 5% and 95% quantiles. Derived
+    /// measures are listed with their jackknife uncertainty, which already
+    /// accounts for autocorrelations if the data points are long enough.
+    pub fn pretty_table(measures: &Measures) -> ::prettytable::Table {
         use prettytable::format::Alignment;
-        use prettytable::row::Row;
+        use prettytable::Cell;
+        use prettytable::Row;
         use prettytable::Table;
         let mut table = Table::new();
         table.set_format(*::prettytable::format::consts::FORMAT_NO_LINESEP_WITH_TITLE);
@@ -43,47 +138,739 @@ impl DebugExporter {
             Cell::new_align("EXPECTATION", Alignment::CENTER),
             Cell::new_align("UNCERTAINTY", Alignment::CENTER),
             Cell::new_align("RELATIVE UNCERTAINTY", Alignment::CENTER),
+            Cell::new_align("BINNED UNCERTAINTY", Alignment::CENTER),
+            Cell::new_align("AUTOCORRELATION TIME", Alignment::CENTER),
         ]));
-        for measure in measures.slice() {
-            let expectation = format!("{}", measure.acc.value());
-            let uncertainty = format!("{}", measure.acc.uncertainty());
+        let mut add_row = |name: &str,
+                           value: f64,
+                           uncertainty: Option<f64>,
+                           binned_uncertainty: Option<f64>,
+                           autocorrelation_time: Option<f64>| {
+            // Not applicable to derived measures and some of the moments.
             let relative_uncertainty =
-                format!("{}", measure.acc.uncertainty() / measure.acc.value().abs());
+                uncertainty.map_or("-".to_string(), |x| (x / value.abs()).to_string());
+            let uncertainty = uncertainty.map_or("-".to_string(), |x| x.to_string());
+            let binned_uncertainty = binned_uncertainty.map_or("-".to_string(), |x| x.to_string());
+            let autocorrelation_time =
+                autocorrelation_time.map_or("-".to_string(), |x| format!("{:.1}", x));
             table.add_row(Row::new(vec![
-                Cell::new_align(&measure.name, Alignment::RIGHT),
-                Cell::new(&expectation),
+                Cell::new_align(name, Alignment::RIGHT),
+                Cell::new(&format!("{}", value)),
                 Cell::new(&uncertainty),
                 Cell::new(&relative_uncertainty),
+                Cell::new(&binned_uncertainty),
+                Cell::new(&autocorrelation_time),
             ]));
+        };
+        for measure in measures.slice() {
+            add_row(
+                &measure.name,
+                measure.acc.value(),
+                Some(measure.acc.uncertainty()),
+                Some(measure.acc.binned_uncertainty()),
+                Some(measure.acc.autocorrelation_time()),
+            );
+        }
+        for measure in measures.vector_slice() {
+            for i in 0..measure.acc.len() {
+                add_row(
+                    &format!("{}[{}]", measure.name, i),
+                    measure.acc.value(i),
+                    Some(measure.acc.uncertainty(i)),
+                    Some(measure.acc.binned_uncertainty(i)),
+                    Some(measure.acc.autocorrelation_time(i)),
+                );
+            }
+        }
+        for measure in measures.moment_slice() {
+            let acc = &measure.acc;
+            add_row(
+                &measure.name,
+                acc.value(),
+                Some(acc.uncertainty()),
+                Some(acc.binned_uncertainty()),
+                Some(acc.autocorrelation_time()),
+            );
+            let name = &measure.name;
+            add_row(
+                &format!("{} (skewness)", name),
+                acc.skewness(),
+                None,
+                None,
+                None,
+            );
+            add_row(
+                &format!("{} (excess kurtosis)", name),
+                acc.excess_kurtosis(),
+                None,
+                None,
+                None,
+            );
+            add_row(
+                &format!("{} (Binder cumulant)", name),
+                acc.binder_cumulant(),
+                Some(acc.binder_cumulant_uncertainty()),
+                Some(acc.binned_binder_cumulant_uncertainty()),
+                None,
+            );
+        }
+        for measure in measures.complex_slice() {
+            let acc = &measure.acc;
+            let parts = acc.parts();
+            for (i, part) in ["Re", "Im"].iter().enumerate() {
+                add_row(
+                    &format!("{}({})", part, measure.name),
+                    parts.value(i),
+                    Some(parts.uncertainty(i)),
+                    Some(parts.binned_uncertainty(i)),
+                    Some(parts.autocorrelation_time(i)),
+                );
+            }
+            add_row(
+                &format!("|{}|", measure.name),
+                acc.modulus(),
+                Some(acc.modulus_uncertainty()),
+                Some(acc.binned_modulus_uncertainty()),
+                None,
+            );
+            add_row(
+                &format!("arg({})", measure.name),
+                acc.phase(),
+                Some(acc.phase_uncertainty()),
+                Some(acc.binned_phase_uncertainty()),
+                None,
+            );
+        }
+        for measure in measures.quantile_slice() {
+            let quantiles = measure.sketch.quantiles(&[0.05, 0.5, 0.95]);
+            let labels = ["5% quantile", "median", "95% quantile"];
+            for (label, quantile) in labels.iter().zip(quantiles) {
+                let name = format!("{} ({})", measure.name, label);
+                add_row(&name, quantile, None, None, None);
+            }
+        }
+        for derived in measures.derived_slice() {
+            add_row(
+                &derived.name,
+                derived.value(),
+                Some(derived.uncertainty()),
+                None,
+                None,
+            );
+        }
+        table
+    }
+
+    /// Format the counter and rate measures in a pretty table, with their exact
+    /// counts and 95% confidence intervals. For counters, the interval is based
+    /// on the binned uncertainty of the mean count; for rates, it is the Wilson
+    /// score interval. Gives `None` if there are no such measures.
+    pub fn counter_table(measures: &Measures) -> Option<::prettytable::Table> {
+        use prettytable::format::Alignment;
+        use prettytable::Cell;
+        use prettytable::Row;
+        use prettytable::Table;
+        const CONFIDENCE_LEVEL: f64 = 0.95;
+        if measures.counter_slice().is_empty() && measures.rate_slice().is_empty() {
+            return None;
+        }
+        let mut table = Table::new();
+        table.set_format(*::prettytable::format::consts::FORMAT_NO_LINESEP_WITH_TITLE);
+        table.set_titles(Row::new(vec![
+            Cell::new_align("COUNTER", Alignment::CENTER),
+            Cell::new_align("TOTAL", Alignment::CENTER),
+            Cell::new_align("SAMPLES", Alignment::CENTER),
+            Cell::new_align("MEAN / RATE", Alignment::CENTER),
+            Cell::new_align(
+                &format!("{}% CONFIDENCE INTERVAL", CONFIDENCE_LEVEL * 100.0),
+                Alignment::CENTER,
+            ),
+        ]));
+        let mut add_row =
+            |name: &str, total: u64, samples: u64, value: f64, interval: (f64, f64)| {
+                table.add_row(Row::new(vec![
+                    Cell::new_align(name, Alignment::RIGHT),
+                    Cell::new(&total.to_string()),
+                    Cell::new(&samples.to_string()),
+                    Cell::new(&value.to_string()),
+                    Cell::new(&format!("[{}, {}]", interval.0, interval.1)),
+                ]));
+            };
+        for measure in measures.counter_slice() {
+            let acc = &measure.acc;
+            add_row(
+                &measure.name,
+                acc.total(),
+                acc.num_of_samples(),
+                acc.mean(),
+                acc.confidence_interval(CONFIDENCE_LEVEL),
+            );
+        }
+        for measure in measures.rate_slice() {
+            let acc = &measure.acc;
+            add_row(
+                &measure.name,
+                acc.num_of_successes(),
+                acc.num_of_trials(),
+                acc.rate(),
+                acc.wilson_interval(CONFIDENCE_LEVEL),
+            );
+        }
+        Some(table)
+    }
+
+    /// Format the expectation values at several points of the parameter space,
+    /// e.g. at the grid points of a sweep, in a pretty table with a row per
+    /// measure and point. The parameters which differ between the points get a
+    /// column each. Lists the scalar measures and the components of the vector
+    /// measures with their binned uncertainty, and the derived measures with
+    /// their jackknife uncertainty.
+    pub fn sweep_table(points: &[(&BTreeMap<String, Value>, &Measures)]) -> ::prettytable::Table {
+        use prettytable::format::Alignment;
+        use prettytable::Cell;
+        use prettytable::Row;
+        use prettytable::Table;
+        let mut swept: Vec<&String> = Vec::new();
+        for (params, _) in points.iter() {
+            for (name, value) in params.iter() {
+                let differs = points.iter().any(|other| other.0.get(name) != Some(value));
+                if differs && !swept.contains(&name) {
+                    swept.push(name);
+                }
+            }
+        }
+        swept.sort();
+        let mut table = Table::new();
+        table.set_format(*::prettytable::format::consts::FORMAT_NO_LINESEP_WITH_TITLE);
+        let mut titles = vec![Cell::new_align("MEASURE", Alignment::CENTER)];
+        titles.extend(
+            swept
+                .iter()
+                .map(|name| Cell::new_align(name, Alignment::CENTER)),
+        );
+        titles.push(Cell::new_align("EXPECTATION", Alignment::CENTER));
+        titles.push(Cell::new_align("UNCERTAINTY", Alignment::CENTER));
+        table.set_titles(Row::new(titles));
+
+        let expectation_values = |measures: &Measures| {
+            let mut values: Vec<(String, f64, f64)> = Vec::new();
+            for measure in measures.slice() {
+                let acc = &measure.acc;
+                values.push((measure.name.clone(), acc.value(), acc.binned_uncertainty()));
+            }
+            for measure in measures.vector_slice() {
+                for i in 0..measure.acc.len() {
+                    let name = format!("{}[{}]", measure.name, i);
+                    values.push((
+                        name,
+                        measure.acc.value(i),
+                        measure.acc.binned_uncertainty(i),
+                    ));
+                }
+            }
+            for derived in measures.derived_slice() {
+                values.push((derived.name.clone(), derived.value(), derived.uncertainty()));
+            }
+            values
+        };
+        let values: Vec<Vec<(String, f64, f64)>> = points
+            .iter()
+            .map(|(_, measures)| expectation_values(measures))
+            .collect();
+        let names: Vec<&String> = values.iter().flatten().map(|value| &value.0).collect();
+        for (i, name) in names.iter().enumerate() {
+            if names[..i].contains(name) {
+                continue;
+            }
+            for ((params, _), values) in points.iter().zip(values.iter()) {
+                let (_, value, uncertainty) = match values.iter().find(|value| value.0 == **name) {
+                    Some(value) => value,
+                    None => continue,
+                };
+                let mut cells = vec![Cell::new_align(name, Alignment::RIGHT)];
+                cells.extend(swept.iter().map(|swept| {
+                    Cell::new(&params.get(*swept).map_or("-".to_string(), format_value))
+                }));
+                cells.push(Cell::new(&value.to_string()));
+                cells.push(Cell::new(&uncertainty.to_string()));
+                table.add_row(Row::new(cells));
+            }
         }
         table
     }
+
+    /// Formats the physics parameters as `name = value`, separated by commas.
+    pub fn format_params(params: &BTreeMap<String, Value>) -> String {
+        params
+            .iter()
+            .map(|(name, value)| format!("{} = {}", name, format_value(value)))
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+
+    /// Draws a histogram measure as a chart of horizontal bars, one per bin,
+    /// scaled to the most populated bin.
+    pub fn histogram_chart(measure: &HistogramMeasure) -> String {
+        const MAX_BAR_WIDTH: usize = 50;
+        let histogram = &measure.histogram;
+        let bins = histogram.bins();
+        let mut chart = format!("{} ({} samples", measure.name, histogram.num_of_samples());
+        if histogram.underflow() > 0.0 || histogram.overflow() > 0.0 {
+            chart += &format!(
+                ", {} below and {} above the range",
+                histogram.underflow(),
+                histogram.overflow()
+            );
+        }
+        chart += "):\n";
+        let labels: Vec<String> = bins
+            .iter()
+            .map(|&(lower, upper, _)| format!("[{}, {})", lower, upper))
+            .collect();
+        let label_width = labels.iter().map(|label| label.len()).max().unwrap_or(0);
+        let max_count = bins.iter().map(|bin| bin.2).fold(0.0, f64::max);
+        for (label, &(_, _, count)) in labels.iter().zip(bins.iter()) {
+            let bar_width = if max_count > 0.0 {
+                (count / max_count * MAX_BAR_WIDTH as f64).round() as usize
+            } else {
+                0
+            };
+            chart += &format!(
+                "{:>label_width$} |{:<bar_width$}| {}\n",
+                label,
+                "#".repeat(bar_width),
+                count,
+                label_width = label_width,
+                bar_width = MAX_BAR_WIDTH
+            );
+        }
+        chart
+    }
 }
 
 impl Exporter for DebugExporter {
-    fn export(&mut self, measures: &Measures) -> Result<(), ExportError> {
-        let mut samples_processed: usize = 0;
-        // Merge the reported values to the global accumulated values.
-        for measure in measures.slice() {
-            let measure_idx = match self.aggregated.find(&measure.name) {
-                Some(idx) => idx,
-                None => self.aggregated.register(measure.name.clone()),
-            };
-            self.aggregated
-                .accumulator(measure_idx)
-                .merge(measure.acc.clone());
-            samples_processed = self.aggregated.accumulator(measure_idx).num_of_samples() as usize;
+    fn export(&mut self, data_point: &DataPoint) -> Result<(), ExportError> {
+        // Merge the reported values to the accumulated values with the same
+        // parameters.
+        let position = match self
+            .aggregated
+            .iter()
+            .position(|(params, _)| *params == data_point.params)
+        {
+            Some(position) => position,
+            None => {
+                let params = data_point.params.clone();
+                self.aggregated.push((params, Measures::new_empty()));
+                self.aggregated.len() - 1
+            }
+        };
+        let (ref params, ref mut aggregated) = self.aggregated[position];
+        if let Err(err) = aggregated.merge_block(&data_point.measures) {
+            warn!("{}", err);
         }
 
-        // Output the global accumulated values to stdout.
+        // Output the accumulated values to stdout.
         println!();
         println!(
             "Simulation uptime: {} secs",
             self.creation_timestamp.elapsed().unwrap().as_secs()
         );
-        println!("Samples processed: {}", samples_processed);
+        if !params.is_empty() {
+            println!("Parameters: {}", DebugExporter::format_params(params));
+        }
+        println!("Samples processed: {}", aggregated.num_of_samples());
         println!("Aggregate values:");
-        DebugExporter::pretty_table(self.aggregated.measures()).printstd();
+        DebugExporter::pretty_table(aggregated).printstd();
+        if let Some(table) = DebugExporter::counter_table(aggregated) {
+            table.printstd();
+        }
+        for measure in aggregated.histogram_slice() {
+            println!();
+            print!("{}", DebugExporter::histogram_chart(measure));
+        }
+        Ok(())
+    }
+}
+
+/// Sends the data points to another thread, which exports them with an exporter
+/// of its own and replies with the result. Lets several simulations running in
+/// parallel, e.g. the grid points of a sweep, share a single exporter.
+pub struct ForwardingExporter {
+    requests: Sender<ExportRequest>,
+}
+
+/// A data point to export, and the channel for replying with the result.
+pub type ExportRequest = (DataPoint, Sender<Result<(), ExportError>>);
+
+impl ForwardingExporter {
+    /// Constructs an exporter sending the data points to `requests`.
+    pub fn new(requests: Sender<ExportRequest>) -> ForwardingExporter {
+        ForwardingExporter { requests }
+    }
+}
+
+impl Exporter for ForwardingExporter {
+    fn export(&mut self, data_point: &DataPoint) -> Result<(), ExportError> {
+        const DISCONNECTED: &str = "The exporting thread has stopped.";
+        let (reply, result) = channel();
+        self.requests
+            .send((data_point.clone(), reply))
+            .map_err(|_| ExportError(DISCONNECTED.to_string()))?;
+        result
+            .recv()
+            .map_err(|_| ExportError(DISCONNECTED.to_string()))?
+    }
+}
+
+/// Sends every data point as a separate document to a MongoDB collection.
+/// Nothing is kept between exports, so a failed export can simply be retried
+/// by the simulation engine with the next data point.
+pub struct MongoExporter {
+    client: ::mongo::Client,
+    db: String,
+    coll: String,
+}
+
+impl MongoExporter {
+    /// Constructs a new MongoExporter writing to the collection `coll` of the
+    /// database `db`. See `mongo::Client::new` for the supported format of the
+    /// connection string `uri`. The connection is established on the first
+    /// export.
+    pub fn new(uri: &str, db: &str, coll: &str) -> Result<MongoExporter, ExportError> {
+        Ok(MongoExporter {
+            client: ::mongo::Client::new(uri).map_err(ExportError)?,
+            db: db.to_string(),
+            coll: coll.to_string(),
+        })
+    }
+}
+
+impl Exporter for MongoExporter {
+    fn export(&mut self, data_point: &DataPoint) -> Result<(), ExportError> {
+        let document = match ::bson::to_bson(data_point) {
+            Ok(::bson::Bson::Document(document)) => document,
+            Ok(_) => unreachable!("Data points are serialized as BSON documents."),
+            Err(err) => return Err(ExportError(format!("{}", err))),
+        };
+        self.client
+            .insert(&self.db, &self.coll, document)
+            .map_err(ExportError)
+    }
+}
+
+/// Appends every data point as a single line of JSON to a local file (the JSON
+/// Lines format). Every line is synced to disk before the export is reported
+/// as successful. A line torn by a node killed mid-write is discarded the next
+/// time the file is opened, so that earlier data points stay readable.
+/// Optionally, the file is rotated when it grows beyond a given size: the full
+/// file is renamed to `<path>.1`, `<path>.2`, etc., and a new file is started.
+pub struct FileExporter {
+    path: PathBuf,
+    file: File,
+    len: u64,
+    max_len: Option<u64>,
+}
+
+impl FileExporter {
+    /// Opens (or creates) the file at `path` for appending data points. If
+    /// `max_len` is given, the file is rotated before it would grow beyond
+    /// `max_len` bytes.
+    pub fn new<P: AsRef<Path>>(path: P, max_len: Option<u64>) -> Result<FileExporter, ExportError> {
+        let path = path.as_ref().to_path_buf();
+        let (file, len) = open_for_append(&path)
+            .map_err(|err| ExportError(format!("Failed to open {}: {}", path.display(), err)))?;
+        Ok(FileExporter {
+            path,
+            file,
+            len,
+            max_len,
+        })
+    }
+
+    /// Renames the current file to the first free `<path>.N` and starts a new
+    /// one.
+    fn rotate(&mut self) -> ::std::io::Result<()> {
+        let mut n = 1;
+        let rotated = loop {
+            let candidate = PathBuf::from(format!("{}.{}", self.path.display(), n));
+            if !candidate.exists() {
+                break candidate;
+            }
+            n += 1;
+        };
+        ::std::fs::rename(&self.path, &rotated)?;
+        let (file, len) = open_for_append(&self.path)?;
+        self.file = file;
+        self.len = len;
+        info!("Rotated {} to {}.", self.path.display(), rotated.display());
         Ok(())
     }
 }
+
+impl Exporter for FileExporter {
+    fn export(&mut self, data_point: &DataPoint) -> Result<(), ExportError> {
+        let mut line =
+            ::serde_json::to_string(data_point).map_err(|err| ExportError(format!("{}", err)))?;
+        line.push('\n');
+        if let Some(max_len) = self.max_len {
+            if self.len > 0 && self.len + line.len() as u64 > max_len {
+                self.rotate().map_err(|err| {
+                    ExportError(format!("Failed to rotate {}: {}", self.path.display(), err))
+                })?;
+            }
+        }
+        append_line(&mut self.file, self.len, line.as_bytes()).map_err(|err| {
+            ExportError(format!(
+                "Failed to write to {}: {}",
+                self.path.display(),
+                err
+            ))
+        })?;
+        self.len += line.len() as u64;
+        Ok(())
+    }
+}
+
+/// The file `FileExporter` appends data points to. Implemented by `File`, and
+/// by failing files in tests.
+trait AppendFile: Write {
+    fn sync_data(&self) -> ::std::io::Result<()>;
+    fn set_len(&self, len: u64) -> ::std::io::Result<()>;
+}
+
+impl AppendFile for File {
+    fn sync_data(&self) -> ::std::io::Result<()> {
+        File::sync_data(self)
+    }
+
+    fn set_len(&self, len: u64) -> ::std::io::Result<()> {
+        File::set_len(self, len)
+    }
+}
+
+/// Appends `line` to `file` of length `len` and syncs it to disk. If either
+/// fails, truncates the file back to `len`, so that no torn line is left
+/// behind. Should the truncation fail too, the torn line is removed when the
+/// file is opened next time.
+fn append_line<F: AppendFile>(file: &mut F, len: u64, line: &[u8]) -> ::std::io::Result<()> {
+    let result = file.write_all(line).and_then(|()| file.sync_data());
+    if result.is_err() {
+        let _ = file.set_len(len);
+    }
+    result
+}
+
+/// Opens the file at `path` for appending, creating it if necessary. Truncates
+/// an incomplete last line left by an interrupted write. Returns the file and
+/// its length.
+fn open_for_append(path: &Path) -> ::std::io::Result<(File, u64)> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(path)?;
+    let len = file.metadata()?.len();
+    // Scan backwards for the last newline. Everything after it is a torn line.
+    let mut complete_len = len;
+    let mut buf = [0u8; 4096];
+    while complete_len > 0 {
+        let chunk_start = complete_len.saturating_sub(buf.len() as u64);
+        let chunk = &mut buf[..(complete_len - chunk_start) as usize];
+        file.seek(SeekFrom::Start(chunk_start))?;
+        file.read_exact(chunk)?;
+        if let Some(pos) = chunk.iter().rposition(|&b| b == b'\n') {
+            complete_len = chunk_start + pos as u64 + 1;
+            break;
+        }
+        complete_len = chunk_start;
+    }
+    if complete_len < len {
+        warn!(
+            "Discarding {} bytes of an incomplete data point at the end of {}.",
+            len - complete_len,
+            path.display()
+        );
+        file.set_len(complete_len)?;
+        file.sync_data()?;
+    }
+    // Make sure that the directory entry of a newly created file is durable.
+    if let Some(dir) = path.parent() {
+        let dir = if dir.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            dir
+        };
+        File::open(dir)?.sync_all()?;
+    }
+    Ok((file, complete_len))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use measure::MeasureRegistry;
+    use std::fs;
+
+    /// Creates an empty directory for the files of a test.
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = ::std::env::temp_dir().join(format!(
+            "ergothic-export-{}-{}",
+            name,
+            ::std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    /// A data point with a single measure `X` holding `value`.
+    fn data_point(value: f64) -> DataPoint {
+        let mut reg = MeasureRegistry::new();
+        let x = reg.register("X".to_string());
+        let mut measures = reg.freeze();
+        measures.accumulate(x, value);
+        DataPoint::new("Sim", "node1", 0, &BTreeMap::new(), &measures)
+    }
+
+    /// Reads the values of `X` from the data points in the file at `path`.
+    fn read_values(path: &Path) -> Vec<f64> {
+        fs::read_to_string(path)
+            .unwrap()
+            .lines()
+            .map(|line| {
+                let data_point: DataPoint = ::serde_json::from_str(line).unwrap();
+                data_point.measures.slice()[0].acc.value()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn appends_data_points_across_reopens() {
+        let path = temp_dir("append").join("data.jsonl");
+        FileExporter::new(&path, None)
+            .unwrap()
+            .export(&data_point(1.0))
+            .unwrap();
+        let mut exporter = FileExporter::new(&path, None).unwrap();
+        exporter.export(&data_point(2.0)).unwrap();
+        assert_eq!(read_values(&path), vec![1.0, 2.0]);
+    }
+
+    #[test]
+    fn discards_torn_line_on_open() {
+        let path = temp_dir("torn").join("data.jsonl");
+        FileExporter::new(&path, None)
+            .unwrap()
+            .export(&data_point(1.0))
+            .unwrap();
+        let complete = fs::read(&path).unwrap();
+        // Longer than the buffer used for scanning backwards for the newline.
+        let mut torn = complete.clone();
+        torn.extend(::std::iter::repeat_n(b'x', 10000));
+        fs::write(&path, &torn).unwrap();
+
+        let mut exporter = FileExporter::new(&path, None).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), complete);
+        exporter.export(&data_point(2.0)).unwrap();
+        assert_eq!(read_values(&path), vec![1.0, 2.0]);
+    }
+
+    #[test]
+    fn discards_file_without_complete_lines() {
+        let path = temp_dir("torn-only").join("data.jsonl");
+        fs::write(&path, b"{\"simulation\":").unwrap();
+        let mut exporter = FileExporter::new(&path, None).unwrap();
+        exporter.export(&data_point(1.0)).unwrap();
+        assert_eq!(read_values(&path), vec![1.0]);
+    }
+
+    #[test]
+    fn rotates_to_first_free_suffix() {
+        let dir = temp_dir("rotate");
+        let path = dir.join("data.jsonl");
+        let rotated = |n: usize| dir.join(format!("data.jsonl.{}", n));
+        fs::write(rotated(1), b"taken\n").unwrap();
+        // The longest of the lines exported below.
+        let line_len = ::serde_json::to_string(&data_point(5.0)).unwrap().len() as u64 + 1;
+
+        // Room for two lines per file.
+        let mut exporter = FileExporter::new(&path, Some(2 * line_len)).unwrap();
+        for value in 1..6 {
+            exporter.export(&data_point(value as f64)).unwrap();
+        }
+        assert_eq!(fs::read(rotated(1)).unwrap(), b"taken\n");
+        assert_eq!(read_values(&rotated(2)), vec![1.0, 2.0]);
+        assert_eq!(read_values(&rotated(3)), vec![3.0, 4.0]);
+        assert_eq!(read_values(&path), vec![5.0]);
+        assert!(!rotated(4).exists());
+    }
+
+    #[test]
+    fn fails_to_open_in_missing_directory() {
+        let path = temp_dir("missing").join("no_such_dir").join("data.jsonl");
+        assert!(FileExporter::new(&path, None).is_err());
+    }
+
+    /// A file failing to write after `budget` bytes, or failing to sync.
+    struct FailingFile {
+        file: File,
+        budget: usize,
+        fail_sync: bool,
+    }
+
+    impl Write for FailingFile {
+        fn write(&mut self, buf: &[u8]) -> ::std::io::Result<usize> {
+            if self.budget == 0 {
+                return Err(::std::io::Error::other("No space left on device"));
+            }
+            let written = self.file.write(&buf[..buf.len().min(self.budget)])?;
+            self.budget -= written;
+            Ok(written)
+        }
+
+        fn flush(&mut self) -> ::std::io::Result<()> {
+            self.file.flush()
+        }
+    }
+
+    impl AppendFile for FailingFile {
+        fn sync_data(&self) -> ::std::io::Result<()> {
+            if self.fail_sync {
+                return Err(::std::io::Error::other("Input/output error"));
+            }
+            self.file.sync_data()
+        }
+
+        fn set_len(&self, len: u64) -> ::std::io::Result<()> {
+            self.file.set_len(len)
+        }
+    }
+
+    #[test]
+    fn failed_write_leaves_no_torn_line() {
+        let path = temp_dir("failed-write").join("data.jsonl");
+        FileExporter::new(&path, None)
+            .unwrap()
+            .export(&data_point(1.0))
+            .unwrap();
+        let contents = fs::read(&path).unwrap();
+        let mut line = ::serde_json::to_string(&data_point(2.0)).unwrap();
+        line.push('\n');
+
+        // Fail in the middle of the line, and after writing it in full.
+        for &(budget, fail_sync) in &[(10, false), (usize::MAX, true)] {
+            let mut file = FailingFile {
+                file: OpenOptions::new().append(true).open(&path).unwrap(),
+                budget,
+                fail_sync,
+            };
+            let result = append_line(&mut file, contents.len() as u64, line.as_bytes());
+            assert!(result.is_err());
+            assert_eq!(fs::read(&path).unwrap(), contents);
+        }
+
+        let mut exporter = FileExporter::new(&path, None).unwrap();
+        exporter.export(&data_point(3.0)).unwrap();
+        assert_eq!(read_values(&path), vec![1.0, 3.0]);
+    }
+}
@@ -0,0 +1,288 @@
+/// An arithmetic expression over the expectation values of measures, e.g.
+/// `log({G[1]} / {G[2]})`. Measures are referred to by their names in curly
+/// braces, components of vector measures as `{name[i]}`. Supports numbers,
+/// `+`, `-`, `*`, `/`, `^` (power), parentheses and the functions `abs`, `sqrt`,
+/// `exp`, `log`, `sin`, `cos`, `tan`, `asin`, `acos`, `atan`, `sinh`, `cosh`,
+/// `tanh`, `asinh`, `acosh` and `atanh`.
+#[derive(Clone, Debug)]
+pub struct Expression {
+    root: Node,
+    inputs: Vec<String>,
+}
+
+#[derive(Clone, Debug)]
+enum Node {
+    Number(f64),
+    Input(usize),
+    Neg(Box<Node>),
+    Binary(char, Box<Node>, Box<Node>),
+    Call(fn(f64) -> f64, Box<Node>),
+}
+
+impl Expression {
+    /// Parses an expression. Returns an error describing the position of the
+    /// first syntax error.
+    pub fn parse(text: &str) -> Result<Expression, String> {
+        let mut parser = Parser {
+            text,
+            pos: 0,
+            inputs: Vec::new(),
+        };
+        let root = parser.sum()?;
+        parser.skip_whitespace();
+        if parser.pos < text.len() {
+            return Err(parser.error("unexpected character"));
+        }
+        Ok(Expression {
+            root,
+            inputs: parser.inputs,
+        })
+    }
+
+    /// Names of the measures the expression refers to, in the order of their
+    /// first appearance.
+    pub fn inputs(&self) -> &[String] {
+        &self.inputs
+    }
+
+    /// Evaluates the expression for the given values of its `inputs`.
+    pub fn eval(&self, inputs: &[f64]) -> f64 {
+        eval(&self.root, inputs)
+    }
+}
+
+fn eval(node: &Node, inputs: &[f64]) -> f64 {
+    match *node {
+        Node::Number(value) => value,
+        Node::Input(i) => inputs[i],
+        Node::Neg(ref arg) => -eval(arg, inputs),
+        Node::Binary(op, ref lhs, ref rhs) => {
+            let (lhs, rhs) = (eval(lhs, inputs), eval(rhs, inputs));
+            match op {
+                '+' => lhs + rhs,
+                '-' => lhs - rhs,
+                '*' => lhs * rhs,
+                '/' => lhs / rhs,
+                _ => lhs.powf(rhs),
+            }
+        }
+        Node::Call(function, ref arg) => function(eval(arg, inputs)),
+    }
+}
+
+/// Looks up a function by its name.
+fn function(name: &str) -> Option<fn(f64) -> f64> {
+    Some(match name {
+        "abs" => f64::abs,
+        "sqrt" => f64::sqrt,
+        "exp" => f64::exp,
+        "log" => f64::ln,
+        "sin" => f64::sin,
+        "cos" => f64::cos,
+        "tan" => f64::tan,
+        "asin" => f64::asin,
+        "acos" => f64::acos,
+        "atan" => f64::atan,
+        "sinh" => f64::sinh,
+        "cosh" => f64::cosh,
+        "tanh" => f64::tanh,
+        "asinh" => f64::asinh,
+        "acosh" => f64::acosh,
+        "atanh" => f64::atanh,
+        _ => return None,
+    })
+}
+
+/// Recursive descent parser. Each method parses one level of precedence.
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize,
+    inputs: Vec<String>,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, message: &str) -> String {
+        format!(
+            "Invalid expression '{}': {} at position {}.",
+            self.text, message, self.pos
+        )
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = &self.text[self.pos..];
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    /// Skips whitespace and returns the next character without consuming it.
+    fn peek(&mut self) -> Option<char> {
+        self.skip_whitespace();
+        self.text[self.pos..].chars().next()
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        if self.peek() != Some(expected) {
+            return Err(self.error(&format!("expected '{}'", expected)));
+        }
+        self.pos += expected.len_utf8();
+        Ok(())
+    }
+
+    /// sum := product (('+' | '-') product)*
+    fn sum(&mut self) -> Result<Node, String> {
+        let mut node = self.product()?;
+        while let Some(op) = self.peek().filter(|c| *c == '+' || *c == '-') {
+            self.pos += 1;
+            node = Node::Binary(op, Box::new(node), Box::new(self.product()?));
+        }
+        Ok(node)
+    }
+
+    /// product := unary (('*' | '/') unary)*
+    fn product(&mut self) -> Result<Node, String> {
+        let mut node = self.unary()?;
+        while let Some(op) = self.peek().filter(|c| *c == '*' || *c == '/') {
+            self.pos += 1;
+            node = Node::Binary(op, Box::new(node), Box::new(self.unary()?));
+        }
+        Ok(node)
+    }
+
+    /// unary := '-' unary | power
+    fn unary(&mut self) -> Result<Node, String> {
+        if self.peek() == Some('-') {
+            self.pos += 1;
+            return Ok(Node::Neg(Box::new(self.unary()?)));
+        }
+        self.power()
+    }
+
+    /// power := primary ('^' unary)?
+    fn power(&mut self) -> Result<Node, String> {
+        let base = self.primary()?;
+        if self.peek() == Some('^') {
+            self.pos += 1;
+            return Ok(Node::Binary('^', Box::new(base), Box::new(self.unary()?)));
+        }
+        Ok(base)
+    }
+
+    /// primary := number | '{' name '}' | function '(' sum ')' | '(' sum ')'
+    fn primary(&mut self) -> Result<Node, String> {
+        match self.peek() {
+            Some('(') => {
+                self.pos += 1;
+                let node = self.sum()?;
+                self.expect(')')?;
+                Ok(node)
+            }
+            Some('{') => {
+                self.pos += 1;
+                let len = match self.text[self.pos..].find('}') {
+                    Some(len) => len,
+                    None => return Err(self.error("unterminated measure name")),
+                };
+                let name = self.text[self.pos..self.pos + len].trim().to_string();
+                self.pos += len + 1;
+                let i = match self.inputs.iter().position(|input| *input == name) {
+                    Some(i) => i,
+                    None => {
+                        self.inputs.push(name);
+                        self.inputs.len() - 1
+                    }
+                };
+                Ok(Node::Input(i))
+            }
+            Some(c) if c.is_ascii_digit() || c == '.' => {
+                let rest = &self.text[self.pos..];
+                let mut len = rest
+                    .find(|c: char| !(c.is_ascii_digit() || c == '.'))
+                    .unwrap_or(rest.len());
+                // Exponent, e.g. 1.5e-3.
+                if rest[len..].starts_with(['e', 'E']) {
+                    let exponent = &rest[len + 1..];
+                    let sign = if exponent.starts_with(['+', '-']) {
+                        1
+                    } else {
+                        0
+                    };
+                    let digits = exponent[sign..]
+                        .find(|c: char| !c.is_ascii_digit())
+                        .unwrap_or(exponent.len() - sign);
+                    if digits > 0 {
+                        len += 1 + sign + digits;
+                    }
+                }
+                match rest[..len].parse() {
+                    Ok(value) => {
+                        self.pos += len;
+                        Ok(Node::Number(value))
+                    }
+                    Err(_) => Err(self.error("invalid number")),
+                }
+            }
+            Some(c) if c.is_ascii_alphabetic() => {
+                let rest = &self.text[self.pos..];
+                let len = rest
+                    .find(|c: char| !c.is_ascii_alphanumeric())
+                    .unwrap_or(rest.len());
+                let name = &rest[..len];
+                let function = match function(name) {
+                    Some(function) => function,
+                    None => return Err(self.error(&format!("unknown function '{}'", name))),
+                };
+                self.pos += len;
+                self.expect('(')?;
+                let arg = self.sum()?;
+                self.expect(')')?;
+                Ok(Node::Call(function, Box::new(arg)))
+            }
+            Some(_) => Err(self.error("unexpected character")),
+            None => Err(self.error("unexpected end")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses `text` and evaluates it for given values of the inputs.
+    fn eval(text: &str, inputs: &[f64]) -> f64 {
+        Expression::parse(text).unwrap().eval(inputs)
+    }
+
+    #[test]
+    fn parse_respects_precedence() {
+        assert_eq!(eval("1 + 2 * 3", &[]), 7.0);
+        assert_eq!(eval("(1 + 2) * 3", &[]), 9.0);
+        assert_eq!(eval("8 / 4 / 2", &[]), 1.0);
+        assert_eq!(eval("1 - 2 - 3", &[]), -4.0);
+        assert_eq!(eval("2 ^ 3 ^ 2", &[]), 512.0);
+        assert_eq!(eval("-2 ^ 2", &[]), -4.0);
+        assert_eq!(eval("2 ^ -1", &[]), 0.5);
+        assert_eq!(eval("1.5e-3 * 2E3", &[]), 3.0);
+    }
+
+    #[test]
+    fn parse_collects_inputs_in_order_of_appearance() {
+        let expression = Expression::parse("log({G[1]} / { G[2] }) + {G[1]}").unwrap();
+        assert_eq!(expression.inputs(), &["G[1]", "G[2]"]);
+        let e = ::std::f64::consts::E;
+        assert_eq!(expression.eval(&[e, 1.0]), 1.0 + e);
+    }
+
+    #[test]
+    fn parse_calls_functions() {
+        assert_eq!(eval("sqrt({X}) + abs(-1)", &[16.0]), 5.0);
+        assert_eq!(eval("exp(log(3))", &[]), 3.0_f64.ln().exp());
+    }
+
+    #[test]
+    fn parse_reports_syntax_errors() {
+        for text in &["", "1 +", "(1", "{X", "1 2", "foo(1)", "sqrt 1"] {
+            assert!(Expression::parse(text).is_err(), "{}", text);
+        }
+        let err = Expression::parse("1 + )").unwrap_err();
+        assert!(err.contains("position 4"), "{}", err);
+    }
+}
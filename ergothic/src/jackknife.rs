@@ -0,0 +1,135 @@
+/// Statistics of the inputs of a derived quantity over a single block of
+/// samples: the weight and the mean of each input. The weight is the number of
+/// samples, or the sum of their weights for weighted samples.
+pub type Block = Vec<(f64, f64)>;
+
+/// Estimates the value of a function `f` of the expectation values of several
+/// inputs and its statistical error with the jackknife method. Each of the
+/// `blocks` contains the weight and the mean of every input.
+/// The value is `f` of the means over all blocks. The error is computed from
+/// the values of `f` with one block left out at a time, which takes both the
+/// non-linearity of `f` and the correlations between the inputs into account.
+/// If the blocks are longer than the autocorrelation time, so are the
+/// autocorrelations.
+/// Blocks may differ in size, e.g. the short block of the final flush, so the
+/// error is given by the delete-m jackknife for unequal blocks (Busing, Meijer
+/// and van der Leeden, 1999), which weighs every block by its share of the
+/// samples, and reduces to the usual jackknife for equal blocks.
+/// Returns NaN as the error if there are less than two blocks.
+pub fn jackknife<F: Fn(&[f64]) -> f64>(blocks: &[Block], f: F) -> (f64, f64) {
+    let num_inputs = match blocks.first() {
+        Some(block) => block.len(),
+        None => return (f64::NAN, f64::NAN),
+    };
+    // Total weight and weighted sum of values of each input.
+    let mut totals = vec![(0.0, 0.0); num_inputs];
+    for block in blocks.iter() {
+        for (total, &(weight, mean)) in totals.iter_mut().zip(block.iter()) {
+            total.0 += weight;
+            total.1 += weight * mean;
+        }
+    }
+    let means: Vec<f64> = totals.iter().map(|&(weight, sum)| sum / weight).collect();
+    let value = f(&means);
+    if blocks.len() < 2 {
+        return (value, f64::NAN);
+    }
+
+    let mut left_out_means = vec![0.0; num_inputs];
+    // The share of the samples in every block, averaged over the inputs, and
+    // the value of `f` with the block left out.
+    let left_out: Vec<(f64, f64)> = blocks
+        .iter()
+        .map(|block| {
+            let mut share = 0.0;
+            for (i, &(weight, mean)) in block.iter().enumerate() {
+                let (total_weight, total_sum) = totals[i];
+                left_out_means[i] = (total_sum - weight * mean) / (total_weight - weight);
+                share += weight / total_weight;
+            }
+            (share / num_inputs as f64, f(&left_out_means))
+        })
+        .collect();
+    let n = blocks.len() as f64;
+    let estimate = n * value
+        - left_out
+            .iter()
+            .map(|&(share, left_out_value)| (1.0 - share) * left_out_value)
+            .sum::<f64>();
+    let variance = left_out
+        .iter()
+        .map(|&(share, left_out_value)| {
+            let h = 1.0 / share;
+            let pseudo_value = h * value - (h - 1.0) * left_out_value;
+            (pseudo_value - estimate).powi(2) / (h - 1.0)
+        })
+        .sum::<f64>()
+        / n;
+    (value, variance.sqrt())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::Rng;
+    use rand::SeedableRng;
+
+    /// Blocks of the given sizes of a single input drawn uniformly from [0, 1).
+    fn uniform_blocks(rng: &mut StdRng, sizes: &[usize]) -> Vec<Block> {
+        sizes
+            .iter()
+            .map(|&size| {
+                let sum: f64 = (0..size).map(|_| rng.gen::<f64>()).sum();
+                vec![(size as f64, sum / size as f64)]
+            })
+            .collect()
+    }
+
+    #[test]
+    fn equal_blocks_give_usual_jackknife() {
+        let mut rng = StdRng::seed_from_u64(12);
+        let blocks = uniform_blocks(&mut rng, &[100; 20]);
+        let f = |means: &[f64]| means[0].powi(2);
+        let (value, error) = jackknife(&blocks, f);
+        let n = blocks.len() as f64;
+        let mean = blocks.iter().map(|block| block[0].1).sum::<f64>() / n;
+        assert!((value - mean.powi(2)).abs() < 1e-12);
+        let left_out: Vec<f64> = blocks
+            .iter()
+            .map(|block| f(&[(mean * n - block[0].1) / (n - 1.0)]))
+            .collect();
+        let average = left_out.iter().sum::<f64>() / n;
+        let variance = left_out.iter().map(|v| (v - average).powi(2)).sum::<f64>() * (n - 1.0) / n;
+        assert!((error - variance.sqrt()).abs() < 1e-12 * error);
+    }
+
+    #[test]
+    fn unequal_blocks_give_error_of_mean() {
+        let mut rng = StdRng::seed_from_u64(12);
+        // Long blocks interleaved with short ones, like those of final flushes.
+        let sizes: Vec<usize> = (0..400)
+            .map(|i| if i % 2 == 0 { 1000 } else { 3 })
+            .collect();
+        let blocks = uniform_blocks(&mut rng, &sizes);
+        let total = sizes.iter().sum::<usize>() as f64;
+        let (value, error) = jackknife(&blocks, |means| means[0]);
+        assert!((value - 0.5).abs() < 0.005);
+        // The variance of the uniform distribution is 1/12.
+        let expected = (1.0 / 12.0 / total).sqrt();
+        assert!(
+            (error / expected - 1.0).abs() < 0.15,
+            "{} != {}",
+            error,
+            expected
+        );
+    }
+
+    #[test]
+    fn single_block_has_no_error() {
+        let (value, error) = jackknife(&[vec![(10.0, 2.0)]], |means| means[0]);
+        assert_eq!(value, 2.0);
+        assert!(error.is_nan());
+        assert!(jackknife(&[], |means| means[0]).0.is_nan());
+    }
+}
@@ -11,10 +11,14 @@
 //! to any number, that technical part has already been taking care for you!
 
 extern crate argparse;
+#[macro_use]
 extern crate bson;
 extern crate prettytable;
-extern crate rand;
+extern crate rand_chacha;
 extern crate serde;
+extern crate serde_json;
+extern crate serde_yaml;
+extern crate signal_hook;
 extern crate simple_logger;
 
 #[macro_use]
@@ -24,19 +28,49 @@ extern crate log;
 extern crate serde_derive;
 
 extern crate structopt;
+extern crate toml;
 
 /// Utilities related to accumulating mean values and statistical errors for
 /// physical observables measured on sample configurations drawn from the
 /// ergodic distribution.
 mod accumulate;
 
+/// Bootstrap resampling of the exported data points.
+mod bootstrap;
+
+/// Configuration files of simulations.
+mod config;
+
+/// Saving and restoring configuration samples, so that simulations can resume
+/// after restarts without thermalizing again.
+mod checkpoint;
+
+/// Arithmetic expressions over measures, used for defining derived measures in
+/// the analyzer.
+mod expression;
+
+/// Errors of setting up and running simulations.
+mod error;
+
 /// Exporters provide interfaces for sending the measured expectation values to
 /// different types of data sinks.
 mod export;
 
+/// Jackknife estimates of functions of expectation values.
+mod jackknife;
+
 /// Helper classes for measures and measure registries.
 mod measure;
 
+/// A minimal MongoDB client used by the MongoDB exporter.
+mod mongo;
+
+/// Mergeable sketches of distributions for estimating quantiles.
+mod quantile;
+
+/// Reading the exported data points back from the data sinks for analysis.
+mod query;
+
 /// The simulation orchestration engine is the core part of *ergothic*.
 mod simulation;
 
@@ -44,19 +78,90 @@ mod simulation;
 /// development and production modes.
 mod startup;
 
+/// Sweeps of the physics parameters over grids of values.
+mod sweep;
+
 // Following are the elements of the public API.
 
+/// The version of `rand` used by the simulation engine. Samples receive their
+/// random number generators as `&mut R` where `R: ergothic::rand::Rng`.
+pub extern crate rand;
+
 /// Sample trait defines an object acting as a statistical sample.
 pub use simulation::Sample;
 
+/// Parameters of samples which have none.
+pub use simulation::NoParams;
+
+/// Optional extension of the sample trait for samples that can be saved to
+/// checkpoints.
+pub use checkpoint::Checkpointable;
+
 /// Positional index of a measure in the measure registry. Indices are wrapped
 /// in `MeasureIdx` type for type safety.
 pub use measure::MeasureIdx;
 
+/// Conditions for stopping the simulation.
+pub use simulation::Termination;
+
+/// Configuration of simulations run from code, see `Simulation::run_with`.
+pub use startup::RunConfig;
+
+/// Measures aggregated over a run at a point of the parameter space, see
+/// `Simulation::run`.
+pub use simulation::RunResult;
+
+/// Errors of setting up and running simulations.
+pub use error::Error;
+
+/// Positional indices of the other kinds of measures, and of covariance
+/// groups.
+pub use measure::{
+    ComplexMeasureIdx, CounterMeasureIdx, CovarianceGroupIdx, HistogramMeasureIdx,
+    MomentMeasureIdx, QuantileMeasureIdx, RateMeasureIdx, VectorMeasureIdx,
+};
+
+/// Accumulator of the mean value and the statistical uncertainty of an
+/// observable, its counterpart for several correlated observables, and
+/// accumulators of higher moments and of complex-valued observables.
+pub use accumulate::{Acc, ComplexAcc, CovAcc, MomentAcc};
+
+/// Exact counters of integer quantities and of outcomes of boolean trials.
+pub use accumulate::{CounterAcc, RateAcc};
+
+/// Histogram of the values of an observable, with fixed or adaptive binning.
+pub use accumulate::Histogram;
+
+/// Mergeable sketch of the distribution of an observable, estimating its
+/// quantiles.
+pub use quantile::QuantileSketch;
+
+/// Measures are named accumulators, and `Measures` is a collection of them.
+pub use measure::{
+    ComplexMeasure, CounterMeasure, CovarianceGroup, DerivedMeasure, HistogramMeasure, Measure,
+    Measures, MomentMeasure, QuantileMeasure, RateMeasure, VectorMeasure,
+};
+
+/// An input of a derived measure, see `Simulation::add_derived_measure`.
+pub use measure::DerivedInput;
+
+/// Expressions defining derived measures outside of the simulation code.
+pub use expression::Expression;
+
+/// Data points, and exporters sending them to the data sinks.
+pub use export::{DataPoint, DebugExporter, ExportError, Exporter, FileExporter, MongoExporter};
+
+/// Bootstrap error estimates over the exported data points.
+pub use bootstrap::{bootstrap, BootstrapEstimate, BootstrapParams};
+
+/// Helpers for reading the exported data points back for analysis.
+pub use query::{glob_matches, read_file, read_mongo, Filter, QueryError};
+
 /// Public interface to measure registry and the entry point function.
 pub struct Simulation {
     name: String,
     measure_registry: measure::MeasureRegistry,
+    termination: simulation::Termination,
 }
 
 impl Simulation {
@@ -65,6 +170,7 @@ impl Simulation {
         Simulation {
             name: name.to_string(),
             measure_registry: measure::MeasureRegistry::new(),
+            termination: simulation::Termination::default(),
         }
     }
 
@@ -74,13 +180,254 @@ impl Simulation {
         self.measure_registry.register(name.to_string())
     }
 
+    /// Registers a vector measure with `len` components, e.g. a correlator, and
+    /// returns its positional index. The components are accumulated together,
+    /// keeping the full covariance between them, and exported as one entry.
+    /// Use `Measures::accumulate_at` or `Measures::accumulate_slice` to record
+    /// their values.
+    pub fn add_vector_measure<N: ToString>(&mut self, name: N, len: usize) -> VectorMeasureIdx {
+        self.measure_registry.register_vector(name.to_string(), len)
+    }
+
+    /// Registers a vector measure whose number of components depends on the
+    /// physics parameters, e.g. a correlator with a component per lattice site,
+    /// and returns its positional index. The number of components is given by
+    /// `len` from the parameters of the run, see `Sample::Params`, so that
+    /// every grid point of a sweep gets a measure of its own length.
+    pub fn add_vector_measure_with<N, P, L>(&mut self, name: N, len: L) -> VectorMeasureIdx
+    where
+        N: ToString,
+        P: ::serde::Serialize + ::serde::de::DeserializeOwned,
+        L: Fn(&P) -> usize + Send + Sync + 'static,
+    {
+        let len = move |params: &::std::collections::BTreeMap<_, _>| {
+            simulation::resolve_params::<P>(params.clone()).map(|(params, _)| len(&params))
+        };
+        self.measure_registry
+            .register_vector_with(name.to_string(), ::std::sync::Arc::new(len))
+    }
+
+    /// Registers a histogram measure recording the distribution of the values
+    /// of an observable in `histogram`, e.g. `Histogram::adaptive(64)`, and
+    /// returns its positional index. Values are recorded with
+    /// `Measures::fill`. Histograms are exported alongside the measures and
+    /// drawn by the debug exporter.
+    pub fn add_histogram_measure<N: ToString>(
+        &mut self,
+        name: N,
+        histogram: Histogram,
+    ) -> HistogramMeasureIdx {
+        self.measure_registry
+            .register_histogram(name.to_string(), histogram)
+    }
+
+    /// Registers a moment measure, and returns its positional index. Besides
+    /// the mean, moment measures accumulate the higher moments of the
+    /// observable, giving its skewness, excess kurtosis and Binder cumulant
+    /// (see `MomentAcc`). Values are recorded with
+    /// `Measures::accumulate_moments`.
+    pub fn add_moment_measure<N: ToString>(&mut self, name: N) -> MomentMeasureIdx {
+        self.measure_registry.register_moments(name.to_string())
+    }
+
+    /// Registers a complex-valued measure, e.g. a Polyakov loop, and returns
+    /// its positional index. Values are recorded with
+    /// `Measures::accumulate_complex`. The real and imaginary parts are
+    /// accumulated with their covariance, and the debug table shows the
+    /// modulus and the phase of the expectation value with propagated errors
+    /// (see `ComplexAcc`).
+    pub fn add_complex_measure<N: ToString>(&mut self, name: N) -> ComplexMeasureIdx {
+        self.measure_registry.register_complex(name.to_string())
+    }
+
+    /// Registers a quantile measure, and returns its positional index. The
+    /// distribution of the values of the observable is summarized by a
+    /// mergeable sketch (see `QuantileSketch`), which gives estimates of
+    /// arbitrary quantiles, e.g. the median. Values are recorded with
+    /// `Measures::accumulate_quantiles`.
+    pub fn add_quantile_measure<N: ToString>(&mut self, name: N) -> QuantileMeasureIdx {
+        self.measure_registry
+            .register_quantiles(name.to_string(), QuantileSketch::default())
+    }
+
+    /// Registers a counter measure for an integer quantity counted on every
+    /// sample, e.g. the number of cluster flips, and returns its positional
+    /// index. Counts are recorded with `Measures::accumulate_count`, and kept
+    /// exactly (see `CounterAcc`).
+    pub fn add_counter_measure<N: ToString>(&mut self, name: N) -> CounterMeasureIdx {
+        self.measure_registry.register_counter(name.to_string())
+    }
+
+    /// Registers a rate measure for the outcomes of boolean trials, e.g. the
+    /// acceptance rate of Metropolis updates, and returns its positional
+    /// index. Outcomes are recorded with `Measures::accumulate_outcome`. The
+    /// debug table shows the rate with its Wilson score interval (see
+    /// `RateAcc`).
+    pub fn add_rate_measure<N: ToString>(&mut self, name: N) -> RateMeasureIdx {
+        self.measure_registry.register_rate(name.to_string())
+    }
+
+    /// Registers a covariance group over the `measures`, and returns its
+    /// positional index. The group accumulates the covariance matrix of the
+    /// values of its measures, which is exported alongside the measures and
+    /// can be used for error propagation, e.g. with
+    /// `CovAcc::binned_propagated_uncertainty`. Values must be recorded with
+    /// `Measures::accumulate` to be seen by the group.
+    pub fn add_covariance_group<N: ToString>(
+        &mut self,
+        name: N,
+        measures: &[MeasureIdx],
+    ) -> CovarianceGroupIdx {
+        self.measure_registry
+            .register_covariance_group(name.to_string(), measures)
+    }
+
+    /// Registers a derived measure computing `f` of the expectation values of
+    /// the `inputs`, e.g. an effective mass from two points of a correlator.
+    /// The inputs are scalar measures, or components of vector measures given
+    /// as `(idx, i)`; use `DerivedInput::from` to mix both kinds. `f` receives
+    /// the values of the inputs in the same order. The value and the
+    /// uncertainty of the derived measure are estimated with the jackknife
+    /// method over the flushed data points, and shown in the debug table.
+    pub fn add_derived_measure<N, I, F>(&mut self, name: N, inputs: &[I], f: F)
+    where
+        N: ToString,
+        I: Clone + Into<DerivedInput>,
+        F: Fn(&[f64]) -> f64 + Send + Sync + 'static,
+    {
+        let inputs: Vec<DerivedInput> = inputs.iter().cloned().map(Into::into).collect();
+        self.measure_registry
+            .register_derived(name.to_string(), &inputs, ::std::sync::Arc::new(f));
+    }
+
+    /// Gives the physics parameters of every grid point of the sweep run by the
+    /// node, in the order of the results of `run`, or of the single point of
+    /// the parameter space if there is no sweep. They are resolved from the
+    /// command line arguments, the configuration file and the environment in
+    /// the same way as `run` resolves the parameters of the samples (see
+    /// `Sample::Params`). If the parameters are invalid, prints the error and
+    /// exits the process.
+    pub fn params<P: ::serde::Serialize + ::serde::de::DeserializeOwned>(&self) -> Vec<P> {
+        startup::cmd_params()
+    }
+
+    /// Same as `params`, but resolved from `config` instead of the command line
+    /// arguments, for simulations run with `run_with`. Returns an error instead
+    /// of exiting the process if the parameters are invalid.
+    pub fn params_with<P: ::serde::Serialize + ::serde::de::DeserializeOwned>(
+        &self,
+        config: &RunConfig,
+    ) -> Result<Vec<P>, Error> {
+        startup::config_params(config)
+    }
+
+    /// Stops the simulation after drawing `max_samples` samples in total
+    /// across all threads. Can be overridden with `--max_samples`.
+    pub fn stop_after_samples(&mut self, max_samples: u64) -> &mut Simulation {
+        self.termination.max_samples = Some(max_samples);
+        self
+    }
+
+    /// Stops the simulation after running for `max_time`. Can be overridden
+    /// with `--max_time_secs`.
+    pub fn stop_after_time(&mut self, max_time: ::std::time::Duration) -> &mut Simulation {
+        self.termination.max_time = Some(max_time);
+        self
+    }
+
+    /// Stops the simulation when the relative uncertainty of each of the
+    /// `measures` drops below `target`. If `measures` is empty, all measures are
+    /// checked. Can be overridden with `--target_relative_uncertainty` and
+    /// `--target_measure`.
+    pub fn stop_at_relative_uncertainty(
+        &mut self,
+        target: f64,
+        measures: &[MeasureIdx],
+    ) -> &mut Simulation {
+        self.termination.target_relative_uncertainty = Some(target);
+        self.termination.target_measures = measures
+            .iter()
+            .map(|idx| self.measure_registry.measures().get(*idx).name.clone())
+            .collect();
+        self
+    }
+
     /// Entry point function. All ergothic simulations should call this function.
-    /// Consumes `self` to indicate that the simulation runs in an infinite loop
-    /// and never returns.
-    pub fn run<S: simulation::Sample, F>(self, f: F)
+    /// Consumes `self`. Runs the simulation until one of the termination
+    /// conditions is met, or in the infinite loop if there are none. Returns the
+    /// measures aggregated over the whole run, one result per grid point of the
+    /// sweep run by the node, or a single one without a sweep. SIGTERM, SIGINT
+    /// and SIGQUIT stop the simulation gracefully. If the simulation fails,
+    /// e.g. the command line arguments are invalid or the remaining values
+    /// can't be exported on termination, prints the error and exits the
+    /// process with a non-zero code.
+    pub fn run<S: simulation::Sample, F>(self, f: F) -> Vec<RunResult>
+    where
+        F: Fn(&S, &mut measure::Measures) + Sync,
+    {
+        startup::run_simulation(&self.name, self.measure_registry, self.termination, None, f)
+    }
+
+    /// Same as `run`, but for samples that can be saved to checkpoints. Enables
+    /// the `--checkpoint` and `--resume` command line arguments.
+    pub fn run_checkpointable<S: Checkpointable, F>(self, f: F) -> Vec<RunResult>
+    where
+        F: Fn(&S, &mut measure::Measures) + Sync,
+    {
+        startup::run_simulation(
+            &self.name,
+            self.measure_registry,
+            self.termination,
+            Some(checkpoint::Codec::new()),
+            f,
+        )
+    }
+
+    /// Same as `run`, but configured by `config` instead of the command line
+    /// arguments, and exporting the data points to `exporter`. For embedding
+    /// simulations in binaries with command line interfaces of their own, and
+    /// for tests. Doesn't install signal handlers; set `config.stop` to stop
+    /// the simulation early. Returns an error instead of exiting the process
+    /// if the simulation fails.
+    pub fn run_with<S: simulation::Sample, F>(
+        self,
+        config: RunConfig,
+        exporter: Box<dyn Exporter>,
+        f: F,
+    ) -> Result<Vec<RunResult>, Error>
+    where
+        F: Fn(&S, &mut measure::Measures) + Sync,
+    {
+        startup::run_with_config(
+            &self.name,
+            self.measure_registry,
+            self.termination,
+            None,
+            config,
+            exporter,
+            f,
+        )
+    }
+
+    /// Same as `run_with`, but for samples that can be saved to checkpoints.
+    /// Enables `config.checkpoint_path` and `config.resume`.
+    pub fn run_checkpointable_with<S: Checkpointable, F>(
+        self,
+        config: RunConfig,
+        exporter: Box<dyn Exporter>,
+        f: F,
+    ) -> Result<Vec<RunResult>, Error>
     where
-        F: Fn(&S, &mut measure::Measures),
+        F: Fn(&S, &mut measure::Measures) + Sync,
     {
-        startup::run_simulation(&self.name, self.measure_registry, f);
+        startup::run_with_config(
+            &self.name,
+            self.measure_registry,
+            self.termination,
+            Some(checkpoint::Codec::new()),
+            config,
+            exporter,
+            f,
+        )
     }
 }
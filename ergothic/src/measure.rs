@@ -1,5 +1,17 @@
 use ::accumulate::Acc;
+use ::accumulate::ComplexAcc;
+use ::accumulate::CounterAcc;
+use ::accumulate::CovAcc;
+use ::accumulate::Histogram;
+use ::accumulate::MomentAcc;
+use ::accumulate::RateAcc;
+use ::jackknife::Block;
+use ::quantile::QuantileSketch;
+use ::error::Error;
+use ::serde_json::Value;
+use ::std::collections::BTreeMap;
 use ::std::collections::HashMap;
+use ::std::sync::Arc;
 
 /// Represents a physical observable. Measuring expectation values of
 /// observables is the purpose of any *ergothic* simulation.
@@ -22,12 +34,272 @@ pub struct Measure {
 #[derive(Clone, Copy)]
 pub struct MeasureIdx(usize);
 
+/// A vector-valued physical observable, e.g. a correlator `G(k)` for all `k`.
+/// Its components are measured together on every configuration sample, and
+/// the full covariance between them is kept.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct VectorMeasure {
+  /// The human-readable name given to the observable.
+  pub name: String,
+
+  /// The corresponding accumulator of all components.
+  pub acc: CovAcc,
+
+  /// Components staged by the measurement function for the current sample.
+  #[serde(skip)]
+  staged: Vec<f64>,
+}
+
+impl VectorMeasure {
+  /// Constructs an empty vector measure with `len` components.
+  fn new(name: String, len: usize) -> VectorMeasure {
+    VectorMeasure {
+      name,
+      acc: CovAcc::new(len),
+      staged: Vec::new(),
+    }
+  }
+
+  /// Returns the components staged for the current sample. Components that
+  /// haven't been staged yet are NaN.
+  fn staged(&mut self) -> &mut [f64] {
+    if self.staged.is_empty() {
+      self.staged = vec![f64::NAN; self.acc.len()];
+    }
+    &mut self.staged
+  }
+}
+
+/// Positional index of a vector measure, see `MeasureIdx`.
+#[derive(Clone, Copy)]
+pub struct VectorMeasureIdx(usize);
+
+/// An input of a derived measure: the expectation value of a scalar measure,
+/// or of a component of a vector measure.
+#[derive(Clone, Copy)]
+pub enum DerivedInput {
+  Scalar(MeasureIdx),
+  Component(VectorMeasureIdx, usize),
+}
+
+impl From<MeasureIdx> for DerivedInput {
+  fn from(idx: MeasureIdx) -> DerivedInput {
+    DerivedInput::Scalar(idx)
+  }
+}
+
+impl From<(VectorMeasureIdx, usize)> for DerivedInput {
+  fn from((idx, i): (VectorMeasureIdx, usize)) -> DerivedInput {
+    DerivedInput::Component(idx, i)
+  }
+}
+
+/// A group of measures whose covariance matrix is accumulated, so that the
+/// quantities derived from several of them get correct error bars. The values
+/// of the members are consumed by the group on every sample on which all of
+/// them have been recorded with `Measures::accumulate`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CovarianceGroup {
+  /// The human-readable name given to the group.
+  pub name: String,
+
+  /// Names of the member measures, in the order of the components of `acc`.
+  pub measures: Vec<String>,
+
+  /// The accumulator of the values of the member measures.
+  pub acc: CovAcc,
+
+  /// Positional indices of the member measures.
+  #[serde(skip)]
+  indices: Vec<usize>,
+
+  /// Values of the members staged for the current sample.
+  #[serde(skip)]
+  staged: Vec<f64>,
+}
+
+impl CovarianceGroup {
+  /// Stages `value` of the measure with positional index `idx`, if it is a
+  /// member of the group.
+  fn stage(&mut self, idx: usize, value: f64) {
+    if let Some(pos) = self.indices.iter().position(|member| *member == idx) {
+      if self.staged.is_empty() {
+        self.staged = vec![f64::NAN; self.indices.len()];
+      }
+      self.staged[pos] = value;
+    }
+  }
+}
+
+/// Positional index of a covariance group, see `MeasureIdx`.
+#[derive(Clone, Copy)]
+pub struct CovarianceGroupIdx(usize);
+
+/// A physical observable whose full distribution is recorded in a histogram,
+/// rather than just its expectation value.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct HistogramMeasure {
+  /// The human-readable name given to the observable.
+  pub name: String,
+
+  /// The histogram of the values of the observable.
+  pub histogram: Histogram,
+}
+
+/// Positional index of a histogram measure, see `MeasureIdx`.
+#[derive(Clone, Copy)]
+pub struct HistogramMeasureIdx(usize);
+
+/// A physical observable whose quantiles are estimated with a mergeable
+/// sketch, e.g. the median and tail percentiles of heavy-tailed observables.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct QuantileMeasure {
+  /// The human-readable name given to the observable.
+  pub name: String,
+
+  /// The sketch of the distribution of the values of the observable.
+  pub sketch: QuantileSketch,
+}
+
+/// Positional index of a quantile measure, see `MeasureIdx`.
+#[derive(Clone, Copy)]
+pub struct QuantileMeasureIdx(usize);
+
+/// An integer-valued observable counted exactly, e.g. the number of cluster
+/// flips per update.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct CounterMeasure {
+  /// The human-readable name given to the observable.
+  pub name: String,
+
+  /// The accumulator of the counts.
+  pub acc: CounterAcc,
+}
+
+/// Positional index of a counter measure, see `MeasureIdx`.
+#[derive(Clone, Copy)]
+pub struct CounterMeasureIdx(usize);
+
+/// The rate of a boolean outcome, e.g. the acceptance rate of Metropolis
+/// updates. Its confidence intervals are Wilson score intervals.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct RateMeasure {
+  /// The human-readable name given to the observable.
+  pub name: String,
+
+  /// The accumulator of the outcomes.
+  pub acc: RateAcc,
+}
+
+/// Positional index of a rate measure, see `MeasureIdx`.
+#[derive(Clone, Copy)]
+pub struct RateMeasureIdx(usize);
+
+/// A complex-valued physical observable, e.g. a Polyakov loop. Its real and
+/// imaginary parts are referred to as `Re(name)` and `Im(name)`.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct ComplexMeasure {
+  /// The human-readable name given to the observable.
+  pub name: String,
+
+  /// The accumulator of the real and imaginary parts of the observable.
+  pub acc: ComplexAcc,
+}
+
+/// Positional index of a complex measure, see `MeasureIdx`.
+#[derive(Clone, Copy)]
+pub struct ComplexMeasureIdx(usize);
+
+/// A physical observable whose higher moments are accumulated, e.g. the
+/// magnetization in finite-size scaling studies needing its Binder cumulant.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct MomentMeasure {
+  /// The human-readable name given to the observable.
+  pub name: String,
+
+  /// The accumulator of the moments of the observable.
+  pub acc: MomentAcc,
+}
+
+/// Positional index of a moment measure, see `MeasureIdx`.
+#[derive(Clone, Copy)]
+pub struct MomentMeasureIdx(usize);
+
+/// The function computing a derived measure from the values of its inputs.
+pub type DerivedFunction = Arc<dyn Fn(&[f64]) -> f64 + Send + Sync>;
+
+/// The function giving the number of components of a vector measure from the
+/// physics parameters of the simulation.
+pub type VectorLen = Arc<dyn Fn(&BTreeMap<String, Value>) -> Result<usize, Error> + Send + Sync>;
+
+/// A function of the expectation values of other measures, e.g. an effective
+/// mass `log(G(t) / G(t + 1))`. Its value and uncertainty are estimated with
+/// the jackknife method over blocks of samples, which are the data points
+/// merged with `Measures::merge_block`. Derived measures are not exported,
+/// since they can be recomputed from the exported measures.
+#[derive(Clone)]
+pub struct DerivedMeasure {
+  /// The human-readable name given to the observable.
+  pub name: String,
+
+  /// Names of the input measures. Components of vector measures are referred
+  /// to as `name[i]`.
+  pub inputs: Vec<String>,
+
+  function: DerivedFunction,
+
+  /// Number of samples and means of the inputs in every block.
+  blocks: Vec<Block>,
+}
+
+impl DerivedMeasure {
+  /// Evaluates the function for given values of the inputs.
+  pub fn eval(&self, inputs: &[f64]) -> f64 {
+    (self.function)(inputs)
+  }
+
+  /// Gives the value of the function at the expectation values of the inputs.
+  pub fn value(&self) -> f64 {
+    ::jackknife::jackknife(&self.blocks, &*self.function).0
+  }
+
+  /// Gives the jackknife estimate of the statistical error. NaN if there are
+  /// less than two blocks.
+  pub fn uncertainty(&self) -> f64 {
+    ::jackknife::jackknife(&self.blocks, &*self.function).1
+  }
+
+  /// Gives the number of blocks the estimates are based on.
+  pub fn num_of_blocks(&self) -> usize {
+    self.blocks.len()
+  }
+}
+
 /// A collection of physical observables. Determining expectation values of each
 /// of the measures with reasonable accuracy is the sole purpose of the
 /// *ergothic* simulation.
 #[derive(Clone, Serialize, Deserialize)]
+#[serde(from = "MeasuresRepr")]
 pub struct Measures {
   measures: Vec<Measure>,
+  #[serde(default)]
+  vector_measures: Vec<VectorMeasure>,
+  #[serde(default)]
+  covariance_groups: Vec<CovarianceGroup>,
+  #[serde(default)]
+  histogram_measures: Vec<HistogramMeasure>,
+  #[serde(default)]
+  moment_measures: Vec<MomentMeasure>,
+  #[serde(default)]
+  complex_measures: Vec<ComplexMeasure>,
+  #[serde(default)]
+  quantile_measures: Vec<QuantileMeasure>,
+  #[serde(default)]
+  counter_measures: Vec<CounterMeasure>,
+  #[serde(default)]
+  rate_measures: Vec<RateMeasure>,
+  #[serde(skip)]
+  derived_measures: Vec<DerivedMeasure>,
 }
 
 impl Measures {
@@ -35,6 +307,15 @@ impl Measures {
   pub fn new_empty() -> Measures {
     Measures {
       measures: Vec::new(),
+      vector_measures: Vec::new(),
+      covariance_groups: Vec::new(),
+      histogram_measures: Vec::new(),
+      moment_measures: Vec::new(),
+      complex_measures: Vec::new(),
+      quantile_measures: Vec::new(),
+      counter_measures: Vec::new(),
+      rate_measures: Vec::new(),
+      derived_measures: Vec::new(),
     }
   }
 
@@ -48,12 +329,146 @@ impl Measures {
     &self.measures[idx.0]
   }
 
+  /// Returns an immutable slice of registered vector measures.
+  pub fn vector_slice(&self) -> &[VectorMeasure] {
+    &self.vector_measures
+  }
+
+  /// Returns an immutable reference to the vector measure pointed to by `idx`.
+  pub fn get_vector(&self, idx: VectorMeasureIdx) -> &VectorMeasure {
+    &self.vector_measures[idx.0]
+  }
+
+  /// Returns an immutable slice of registered covariance groups.
+  pub fn covariance_group_slice(&self) -> &[CovarianceGroup] {
+    &self.covariance_groups
+  }
+
+  /// Returns an immutable reference to the covariance group pointed to by
+  /// `idx`.
+  pub fn get_covariance_group(&self, idx: CovarianceGroupIdx) -> &CovarianceGroup {
+    &self.covariance_groups[idx.0]
+  }
+
+  /// Returns an immutable slice of registered histogram measures.
+  pub fn histogram_slice(&self) -> &[HistogramMeasure] {
+    &self.histogram_measures
+  }
+
+  /// Returns an immutable reference to the histogram measure pointed to by
+  /// `idx`.
+  pub fn get_histogram(&self, idx: HistogramMeasureIdx) -> &HistogramMeasure {
+    &self.histogram_measures[idx.0]
+  }
+
+  /// Returns an immutable slice of registered moment measures.
+  pub fn moment_slice(&self) -> &[MomentMeasure] {
+    &self.moment_measures
+  }
+
+  /// Returns an immutable reference to the moment measure pointed to by `idx`.
+  pub fn get_moments(&self, idx: MomentMeasureIdx) -> &MomentMeasure {
+    &self.moment_measures[idx.0]
+  }
+
+  /// Returns an immutable slice of registered complex measures.
+  pub fn complex_slice(&self) -> &[ComplexMeasure] {
+    &self.complex_measures
+  }
+
+  /// Returns an immutable reference to the complex measure pointed to by
+  /// `idx`.
+  pub fn get_complex(&self, idx: ComplexMeasureIdx) -> &ComplexMeasure {
+    &self.complex_measures[idx.0]
+  }
+
+  /// Returns an immutable slice of registered quantile measures.
+  pub fn quantile_slice(&self) -> &[QuantileMeasure] {
+    &self.quantile_measures
+  }
+
+  /// Returns an immutable reference to the quantile measure pointed to by
+  /// `idx`.
+  pub fn get_quantiles(&self, idx: QuantileMeasureIdx) -> &QuantileMeasure {
+    &self.quantile_measures[idx.0]
+  }
+
+  /// Returns an immutable slice of registered counter measures.
+  pub fn counter_slice(&self) -> &[CounterMeasure] {
+    &self.counter_measures
+  }
+
+  /// Returns an immutable reference to the counter measure pointed to by
+  /// `idx`.
+  pub fn get_counter(&self, idx: CounterMeasureIdx) -> &CounterMeasure {
+    &self.counter_measures[idx.0]
+  }
+
+  /// Returns an immutable slice of registered rate measures.
+  pub fn rate_slice(&self) -> &[RateMeasure] {
+    &self.rate_measures
+  }
+
+  /// Returns an immutable reference to the rate measure pointed to by `idx`.
+  pub fn get_rate(&self, idx: RateMeasureIdx) -> &RateMeasure {
+    &self.rate_measures[idx.0]
+  }
+
+  /// Returns an immutable slice of derived measures.
+  pub fn derived_slice(&self) -> &[DerivedMeasure] {
+    &self.derived_measures
+  }
+
+  /// Adds a derived measure computing `function` of the expectation values of
+  /// the measures named `inputs`. Components of vector measures are referred to
+  /// as `name[i]`. Fails if a derived measure with the same name exists.
+  pub fn add_derived_measure(&mut self, name: String, inputs: Vec<String>,
+                             function: DerivedFunction) -> Result<(), String> {
+    if self.derived_measures.iter().any(|derived| derived.name == name) {
+      return Err(format!("Derived measure '{}' is defined twice.", name));
+    }
+    self.derived_measures.push(DerivedMeasure {
+      name,
+      inputs,
+      function,
+      blocks: Vec::new(),
+    });
+    Ok(())
+  }
+
   /// Resets accumulators for all measures, effectively forgetting about all
   /// recorded samples.
   pub fn reset(&mut self) {
     for measure in self.measures.iter_mut() {
       measure.acc = Acc::new();
     }
+    for measure in self.vector_measures.iter_mut() {
+      measure.acc = CovAcc::new(measure.acc.len());
+    }
+    for group in self.covariance_groups.iter_mut() {
+      group.acc = CovAcc::new(group.acc.len());
+    }
+    for measure in self.histogram_measures.iter_mut() {
+      measure.histogram = measure.histogram.cleared();
+    }
+    for measure in self.moment_measures.iter_mut() {
+      measure.acc = MomentAcc::new();
+    }
+    for measure in self.complex_measures.iter_mut() {
+      measure.acc = ComplexAcc::new();
+    }
+    for measure in self.quantile_measures.iter_mut() {
+      measure.sketch = measure.sketch.cleared();
+    }
+    for measure in self.counter_measures.iter_mut() {
+      measure.acc = CounterAcc::new();
+    }
+    for measure in self.rate_measures.iter_mut() {
+      measure.acc = RateAcc::new();
+    }
+    for derived in self.derived_measures.iter_mut() {
+      derived.blocks.clear();
+    }
   }
 
   /// Returns a mutable reference to the accumulator corresponding to the
@@ -62,15 +477,403 @@ impl Measures {
     &mut self.measures[idx.0].acc
   }
 
-  /// Shorthand for `self.accumulator(idx).consume(value)`.
+  /// Shorthand for `self.accumulator(idx).consume(value)`. Also stages the
+  /// value for the covariance groups the measure is a member of. Values
+  /// consumed by the accumulator directly are not seen by the groups.
   pub fn accumulate(&mut self, idx: MeasureIdx, value: f64) {
     self.accumulator(idx).consume(value);
+    for group in self.covariance_groups.iter_mut() {
+      group.stage(idx.0, value);
+    }
+  }
+
+  /// Shorthand for `self.accumulator(idx).consume_weighted(value, weight)`,
+  /// for reweighted observables. Weighted values are not seen by the
+  /// covariance groups.
+  pub fn accumulate_weighted(&mut self, idx: MeasureIdx, value: f64, weight: f64) {
+    self.accumulator(idx).consume_weighted(value, weight);
+  }
+
+  /// Stages the value of the `i`-th component of the vector measure pointed to
+  /// by `idx` for the current sample. Once the measurement function returns,
+  /// the simulation engine commits the staged components. Samples with
+  /// components that haven't been staged are skipped.
+  pub fn accumulate_at(&mut self, idx: VectorMeasureIdx, i: usize, value: f64) {
+    self.vector_measures[idx.0].staged()[i] = value;
+  }
+
+  /// Stages the values of all components of the vector measure pointed to by
+  /// `idx` for the current sample. Panics if the number of components doesn't
+  /// match.
+  pub fn accumulate_slice(&mut self, idx: VectorMeasureIdx, values: &[f64]) {
+    self.vector_measures[idx.0].staged().copy_from_slice(values);
+  }
+
+  /// Records a value in the histogram measure pointed to by `idx`.
+  pub fn fill(&mut self, idx: HistogramMeasureIdx, value: f64) {
+    self.histogram_measures[idx.0].histogram.consume(value);
+  }
+
+  /// Records a value of the moment measure pointed to by `idx`.
+  pub fn accumulate_moments(&mut self, idx: MomentMeasureIdx, value: f64) {
+    self.moment_measures[idx.0].acc.consume(value);
+  }
+
+  /// Records a value `re + i im` of the complex measure pointed to by `idx`.
+  pub fn accumulate_complex(&mut self, idx: ComplexMeasureIdx, re: f64, im: f64) {
+    self.complex_measures[idx.0].acc.consume(re, im);
+  }
+
+  /// Records a value of the quantile measure pointed to by `idx`.
+  pub fn accumulate_quantiles(&mut self, idx: QuantileMeasureIdx, value: f64) {
+    self.quantile_measures[idx.0].sketch.consume(value);
+  }
+
+  /// Records a `count` of the counter measure pointed to by `idx`.
+  pub fn accumulate_count(&mut self, idx: CounterMeasureIdx, count: u64) {
+    self.counter_measures[idx.0].acc.consume(count);
+  }
+
+  /// Records the outcome of a trial of the rate measure pointed to by `idx`.
+  pub fn accumulate_outcome(&mut self, idx: RateMeasureIdx, success: bool) {
+    self.rate_measures[idx.0].acc.consume(success);
+  }
+
+  /// Consumes the staged values of all vector measures and covariance groups
+  /// and clears them. Called by the simulation engine after each call of the
+  /// measurement function.
+  pub fn commit_staged(&mut self) {
+    for measure in self.vector_measures.iter_mut() {
+      if !measure.staged.is_empty() {
+        measure.acc.consume(&measure.staged);
+        measure.staged.clear();
+      }
+    }
+    for group in self.covariance_groups.iter_mut() {
+      if !group.staged.is_empty() {
+        group.acc.consume(&group.staged);
+        group.staged.clear();
+      }
+    }
+  }
+
+  /// Gives the largest number of samples recorded by any of the measures.
+  pub fn num_of_samples(&self) -> f64 {
+    let scalar = self.measures.iter().map(|measure| measure.acc.num_of_samples());
+    let vector = self.vector_measures.iter()
+        .map(|measure| measure.acc.num_of_samples());
+    let histogram = self.histogram_measures.iter()
+        .map(|measure| measure.histogram.num_of_samples());
+    let moments = self.moment_measures.iter()
+        .map(|measure| measure.acc.num_of_samples());
+    let complex = self.complex_measures.iter()
+        .map(|measure| measure.acc.num_of_samples());
+    let quantiles = self.quantile_measures.iter()
+        .map(|measure| measure.sketch.num_of_samples());
+    let counters = self.counter_measures.iter()
+        .map(|measure| measure.acc.num_of_samples() as f64);
+    let rates = self.rate_measures.iter()
+        .map(|measure| measure.acc.num_of_trials() as f64);
+    scalar.chain(vector).chain(histogram).chain(moments).chain(complex).chain(quantiles)
+        .chain(counters).chain(rates).fold(0.0, f64::max)
+  }
+
+  /// Tells whether none of the measures has recorded any samples.
+  pub fn is_empty(&self) -> bool {
+    self.measures.iter().all(|measure| measure.acc.num_of_samples() == 0.0)
+        && self.vector_measures.iter()
+            .all(|measure| measure.acc.num_of_samples() == 0.0)
+        && self.histogram_measures.iter()
+            .all(|measure| measure.histogram.num_of_samples() == 0.0)
+        && self.moment_measures.iter()
+            .all(|measure| measure.acc.num_of_samples() == 0.0)
+        && self.complex_measures.iter()
+            .all(|measure| measure.acc.num_of_samples() == 0.0)
+        && self.quantile_measures.iter()
+            .all(|measure| measure.sketch.num_of_samples() == 0.0)
+        && self.counter_measures.iter().all(|measure| measure.acc.num_of_samples() == 0)
+        && self.rate_measures.iter().all(|measure| measure.acc.num_of_trials() == 0)
+  }
+
+  /// Removes the measures (of all kinds, including derived ones) and the
+  /// covariance groups whose names `f` returns false for. Covariance groups
+  /// with removed members are removed as well. Note that this invalidates
+  /// previously obtained measure indices.
+  pub fn retain<F: FnMut(&str) -> bool>(&mut self, mut f: F) {
+    self.measures.retain(|measure| f(&measure.name));
+    self.vector_measures.retain(|measure| f(&measure.name));
+    let measures = &self.measures;
+    self.covariance_groups.retain(|group| f(&group.name)
+        && group.measures.iter()
+            .all(|member| measures.iter().any(|measure| measure.name == *member)));
+    self.reindex_covariance_groups();
+    self.histogram_measures.retain(|measure| f(&measure.name));
+    self.moment_measures.retain(|measure| f(&measure.name));
+    self.complex_measures.retain(|measure| f(&measure.name));
+    self.quantile_measures.retain(|measure| f(&measure.name));
+    self.counter_measures.retain(|measure| f(&measure.name));
+    self.rate_measures.retain(|measure| f(&measure.name));
+    self.derived_measures.retain(|derived| f(&derived.name));
+  }
+
+  /// Merges the accumulators of `other` into the accumulators of the measures
+  /// with the same names. Measures missing from `self` are appended, so that
+  /// collections of measures coming from different data points can be merged
+  /// together. The jackknife blocks of derived measures are appended as well.
+  /// Histograms which can't be merged, since their binnings differ, as well as
+  /// vector measures whose numbers of components differ and covariance groups
+  /// whose members differ, are left as they are and reported in the error once
+  /// everything else is merged.
+  pub fn merge(&mut self, other: &Measures) -> Result<(), String> {
+    let merged = self.merge_accumulators(other);
+    for derived in other.derived_measures.iter() {
+      let target = self.derived_measures.iter_mut()
+          .find(|candidate| candidate.name == derived.name);
+      match target {
+        Some(target) => target.blocks.extend(derived.blocks.iter().cloned()),
+        None => self.derived_measures.push(derived.clone()),
+      }
+    }
+    merged
+  }
+
+  /// Same as `merge`, but `other` is also recorded as a single jackknife block
+  /// of the derived measures, e.g. when merging data points. Derived measures
+  /// missing from `self` are added.
+  pub fn merge_block(&mut self, other: &Measures) -> Result<(), String> {
+    let merged = self.merge_accumulators(other);
+    for derived in other.derived_measures.iter() {
+      if !self.derived_measures.iter().any(|candidate| candidate.name == derived.name) {
+        self.derived_measures.push(DerivedMeasure {
+          blocks: Vec::new(),
+          ..derived.clone()
+        });
+      }
+    }
+    for derived in self.derived_measures.iter_mut() {
+      let block: Option<Block> = derived.inputs.iter()
+          .map(|input| other.stats(input))
+          .collect();
+      // Skip the blocks where some of the inputs haven't been measured.
+      if let Some(block) = block {
+        derived.blocks.push(block);
+      }
+    }
+    merged
+  }
+
+  /// Gives the weight and the mean of a scalar, moment or counter measure, the
+  /// rate of a rate measure, a component of a vector measure referred to as
+  /// `name[i]`, or a part of a complex measure referred to as `Re(name)` or
+  /// `Im(name)`. The weight is the number of samples, or the sum of their
+  /// weights for weighted samples of scalar measures. Returns `None` if there
+  /// is no such measure, or it has no samples.
+  pub fn stats(&self, name: &str) -> Option<(f64, f64)> {
+    let scalar = self.measures.iter().find(|measure| measure.name == name)
+        .map(|measure| (measure.acc.total_weight(), measure.acc.value()));
+    let moments = || self.moment_measures.iter().find(|measure| measure.name == name)
+        .map(|measure| (measure.acc.num_of_samples(), measure.acc.value()));
+    let counter = || self.counter_measures.iter().find(|measure| measure.name == name)
+        .map(|measure| (measure.acc.num_of_samples() as f64, measure.acc.mean()));
+    let rate = || self.rate_measures.iter().find(|measure| measure.name == name)
+        .map(|measure| (measure.acc.num_of_trials() as f64, measure.acc.rate()));
+    let complex = || {
+      let (part, inner) = name.strip_suffix(')')?.split_once('(')?;
+      let i = match part {
+        "Re" => 0,
+        "Im" => 1,
+        _ => return None,
+      };
+      let measure = self.complex_measures.iter().find(|measure| measure.name == inner)?;
+      Some((measure.acc.num_of_samples(), measure.acc.parts().value(i)))
+    };
+    let stats = match scalar.or_else(moments).or_else(counter).or_else(rate)
+        .or_else(complex) {
+      Some(stats) => stats,
+      None => {
+        let open = name.rfind('[')?;
+        let i: usize = name[open + 1..].strip_suffix(']')?.parse().ok()?;
+        let measure = self.vector_measures.iter()
+            .find(|measure| measure.name == name[..open])?;
+        if i >= measure.acc.len() {
+          return None;
+        }
+        (measure.acc.num_of_samples(), measure.acc.value(i))
+      }
+    };
+    if stats.0 == 0.0 {
+      return None;
+    }
+    Some(stats)
+  }
+
+  fn merge_accumulators(&mut self, other: &Measures) -> Result<(), String> {
+    let mut conflicts = Vec::new();
+    for (pos, measure) in other.measures.iter().enumerate() {
+      // Collections coming from the same simulation share the layout.
+      let same_pos = self.measures.get(pos)
+          .is_some_and(|candidate| candidate.name == measure.name);
+      let target = if same_pos {
+        Some(pos)
+      } else {
+        self.measures.iter().position(|candidate| candidate.name == measure.name)
+      };
+      match target {
+        Some(target) => self.measures[target].acc.merge(measure.acc.clone()),
+        None => self.measures.push(measure.clone()),
+      }
+    }
+    for measure in other.vector_measures.iter() {
+      let target = self.vector_measures.iter_mut()
+          .find(|candidate| candidate.name == measure.name);
+      match target {
+        Some(target) => if let Err(err) = target.acc.merge(&measure.acc) {
+          conflicts.push(format!("Failed to merge vector measure '{}': {}", measure.name, err));
+        },
+        None => self.vector_measures.push(measure.clone()),
+      }
+    }
+    for group in other.covariance_groups.iter() {
+      let target = self.covariance_groups.iter_mut()
+          .find(|candidate| candidate.name == group.name);
+      match target {
+        Some(ref target) if target.measures != group.measures => {
+          conflicts.push(format!(
+              "Failed to merge covariance group '{}': groups have different members, {:?} and {:?}.",
+              group.name, target.measures, group.measures));
+        },
+        Some(target) => if let Err(err) = target.acc.merge(&group.acc) {
+          conflicts.push(format!("Failed to merge covariance group '{}': {}", group.name, err));
+        },
+        None => self.covariance_groups.push(group.clone()),
+      }
+    }
+    for measure in other.histogram_measures.iter() {
+      let target = self.histogram_measures.iter_mut()
+          .find(|candidate| candidate.name == measure.name);
+      match target {
+        Some(target) => if let Err(err) = target.histogram.merge(&measure.histogram) {
+          conflicts.push(format!("Failed to merge histogram '{}': {}", measure.name, err));
+        },
+        None => self.histogram_measures.push(measure.clone()),
+      }
+    }
+    for measure in other.moment_measures.iter() {
+      let target = self.moment_measures.iter_mut()
+          .find(|candidate| candidate.name == measure.name);
+      match target {
+        Some(target) => target.acc.merge(&measure.acc),
+        None => self.moment_measures.push(measure.clone()),
+      }
+    }
+    for measure in other.complex_measures.iter() {
+      let target = self.complex_measures.iter_mut()
+          .find(|candidate| candidate.name == measure.name);
+      match target {
+        Some(target) => target.acc.merge(&measure.acc),
+        None => self.complex_measures.push(measure.clone()),
+      }
+    }
+    for measure in other.quantile_measures.iter() {
+      let target = self.quantile_measures.iter_mut()
+          .find(|candidate| candidate.name == measure.name);
+      match target {
+        Some(target) => target.sketch.merge(&measure.sketch),
+        None => self.quantile_measures.push(measure.clone()),
+      }
+    }
+    for measure in other.counter_measures.iter() {
+      let target = self.counter_measures.iter_mut()
+          .find(|candidate| candidate.name == measure.name);
+      match target {
+        Some(target) => target.acc.merge(&measure.acc),
+        None => self.counter_measures.push(measure.clone()),
+      }
+    }
+    for measure in other.rate_measures.iter() {
+      let target = self.rate_measures.iter_mut()
+          .find(|candidate| candidate.name == measure.name);
+      match target {
+        Some(target) => target.acc.merge(&measure.acc),
+        None => self.rate_measures.push(measure.clone()),
+      }
+    }
+    self.reindex_covariance_groups();
+    if !conflicts.is_empty() {
+      return Err(conflicts.join(" "));
+    }
+    Ok(())
+  }
+
+  /// Points the covariance groups to the current positions of their members,
+  /// which aren't serialized and change as measures are added or removed.
+  /// Groups with missing members stop consuming values.
+  fn reindex_covariance_groups(&mut self) {
+    let measures = &self.measures;
+    for group in self.covariance_groups.iter_mut() {
+      let indices: Option<Vec<usize>> = group.measures.iter()
+          .map(|member| measures.iter()
+              .position(|measure| measure.name == *member))
+          .collect();
+      group.indices = indices.unwrap_or_default();
+      group.staged.clear();
+    }
+  }
+}
+
+/// Serialized form of `Measures`. The covariance groups are reindexed once
+/// the measures are restored.
+#[derive(Deserialize)]
+struct MeasuresRepr {
+  measures: Vec<Measure>,
+  #[serde(default)]
+  vector_measures: Vec<VectorMeasure>,
+  #[serde(default)]
+  covariance_groups: Vec<CovarianceGroup>,
+  #[serde(default)]
+  histogram_measures: Vec<HistogramMeasure>,
+  #[serde(default)]
+  moment_measures: Vec<MomentMeasure>,
+  #[serde(default)]
+  complex_measures: Vec<ComplexMeasure>,
+  #[serde(default)]
+  quantile_measures: Vec<QuantileMeasure>,
+  #[serde(default)]
+  counter_measures: Vec<CounterMeasure>,
+  #[serde(default)]
+  rate_measures: Vec<RateMeasure>,
+}
+
+impl From<MeasuresRepr> for Measures {
+  fn from(repr: MeasuresRepr) -> Measures {
+    let mut measures = Measures {
+      measures: repr.measures,
+      vector_measures: repr.vector_measures,
+      covariance_groups: repr.covariance_groups,
+      histogram_measures: repr.histogram_measures,
+      moment_measures: repr.moment_measures,
+      complex_measures: repr.complex_measures,
+      quantile_measures: repr.quantile_measures,
+      counter_measures: repr.counter_measures,
+      rate_measures: repr.rate_measures,
+      derived_measures: Vec::new(),
+    };
+    measures.reindex_covariance_groups();
+    measures
   }
 }
 
 pub struct MeasureRegistry {
   measures: Measures,
   name_index: HashMap<String, MeasureIdx>,
+  vector_name_index: HashMap<String, VectorMeasureIdx>,
+
+  /// Vector measures whose numbers of components depend on the parameters.
+  vector_lens: Vec<(VectorMeasureIdx, VectorLen)>,
+
+  /// Components of such vector measures referred to by derived measures, as
+  /// the name of the derived measure, the vector measure and the component.
+  derived_components: Vec<(String, VectorMeasureIdx, usize)>,
 }
 
 /// Contains a list of measures and a map from measure names to measure indexes.
@@ -80,6 +883,9 @@ impl MeasureRegistry {
     MeasureRegistry {
       measures: Measures::new_empty(),
       name_index: HashMap::new(),
+      vector_name_index: HashMap::new(),
+      vector_lens: Vec::new(),
+      derived_components: Vec::new(),
     }
   }
   
@@ -88,16 +894,33 @@ impl MeasureRegistry {
     &self.measures
   }
 
-  /// Lookup of the measure by its name. Returns a measure index or `None` if a
-  /// measure with a given name doesn't exist.
-  pub fn find(&mut self, name: &str) -> Option<MeasureIdx> {
-    self.name_index.get(name).cloned()
-  }
-
   /// Returns an interior-immutable list of measures suitable for using in the
-  /// *ergodic* simulation engine. Destructs `self`.
+  /// *ergodic* simulation engine at the physics `params`. Vector measures
+  /// registered with `register_vector_with` get their numbers of components at
+  /// `params`. Fails if a number of components can't be determined, or a
+  /// derived measure refers to a component out of range.
   /// Previously returned by `self.register(..)` measure indices can be used to
   /// introspect the resulting list of measures.
+  pub fn freeze_at(&self, params: &BTreeMap<String, Value>) -> Result<Measures, Error> {
+    let mut measures = self.measures.clone();
+    for &(idx, ref len) in self.vector_lens.iter() {
+      let measure = &mut measures.vector_measures[idx.0];
+      *measure = VectorMeasure::new(measure.name.clone(), len(params)?);
+    }
+    for &(ref name, idx, i) in self.derived_components.iter() {
+      let measure = &measures.vector_measures[idx.0];
+      if i >= measure.acc.len() {
+        return Err(Error::Config(format!(
+            "Derived measure '{}' refers to component {} of '{}', which has {}.",
+            name, i, &measure.name, measure.acc.len())));
+      }
+    }
+    Ok(measures)
+  }
+
+  /// Same as `freeze_at`, for measures which don't depend on the parameters.
+  /// Destructs `self`.
+  #[cfg(test)]
   pub fn freeze(self) -> Measures {
     self.measures
   }
@@ -106,7 +929,7 @@ impl MeasureRegistry {
   /// index of the measure in the collection of measures. If a measure with the
   /// same name has been registered before, panics.
   pub fn register(&mut self, name: String) -> MeasureIdx {
-    if self.name_index.contains_key(&name) {
+    if self.is_registered(&name) {
       panic!("Ambiguous measure definition: '{}' was registered twice.", &name);
     }
     self.measures.measures.push(Measure {
@@ -118,9 +941,368 @@ impl MeasureRegistry {
     res_idx
   }
 
-  /// Returns a mutable reference to the accumulator corresponding to a measure
-  /// pointed to by `idx`.
-  pub fn accumulator(&mut self, idx: MeasureIdx) -> &mut Acc {
-    self.measures.accumulator(idx)
+  /// Registers a new vector measure with a given `name` and `len` components.
+  /// Returns a safely wrapped index of the vector measure. If a measure with
+  /// the same name has been registered before, panics.
+  pub fn register_vector(&mut self, name: String, len: usize) -> VectorMeasureIdx {
+    if self.is_registered(&name) {
+      panic!("Ambiguous measure definition: '{}' was registered twice.", &name);
+    }
+    self.measures.vector_measures.push(VectorMeasure::new(name.clone(), len));
+    let res_idx = VectorMeasureIdx(self.measures.vector_measures.len() - 1);
+    self.vector_name_index.insert(name, res_idx);
+    res_idx
+  }
+
+  /// Registers a new vector measure with a given `name`, whose number of
+  /// components is given by `len` from the physics parameters when the
+  /// measures are frozen with `freeze_at`. Until then, the measure has no
+  /// components. Returns a safely wrapped index of the vector measure. If a
+  /// measure with the same name has been registered before, panics.
+  pub fn register_vector_with(&mut self, name: String, len: VectorLen) -> VectorMeasureIdx {
+    let res_idx = self.register_vector(name, 0);
+    self.vector_lens.push((res_idx, len));
+    res_idx
+  }
+
+  /// Registers a new histogram measure with a given `name`, recording values in
+  /// `histogram`, e.g. `Histogram::fixed(..)` or `Histogram::adaptive(..)`.
+  /// Returns a safely wrapped index of the histogram measure. If a measure with
+  /// the same name has been registered before, panics.
+  pub fn register_histogram(&mut self, name: String, histogram: Histogram)
+      -> HistogramMeasureIdx {
+    if self.is_registered(&name) {
+      panic!("Ambiguous measure definition: '{}' was registered twice.", &name);
+    }
+    self.measures.histogram_measures.push(HistogramMeasure { name, histogram });
+    HistogramMeasureIdx(self.measures.histogram_measures.len() - 1)
+  }
+
+  /// Registers a new moment measure with a given `name`. Returns a safely
+  /// wrapped index of the moment measure. If a measure with the same name has
+  /// been registered before, panics.
+  pub fn register_moments(&mut self, name: String) -> MomentMeasureIdx {
+    if self.is_registered(&name) {
+      panic!("Ambiguous measure definition: '{}' was registered twice.", &name);
+    }
+    self.measures.moment_measures.push(MomentMeasure { name, acc: MomentAcc::new() });
+    MomentMeasureIdx(self.measures.moment_measures.len() - 1)
+  }
+
+  /// Registers a new complex measure with a given `name`. Returns a safely
+  /// wrapped index of the complex measure. If a measure with the same name has
+  /// been registered before, panics.
+  pub fn register_complex(&mut self, name: String) -> ComplexMeasureIdx {
+    if self.is_registered(&name) {
+      panic!("Ambiguous measure definition: '{}' was registered twice.", &name);
+    }
+    self.measures.complex_measures.push(ComplexMeasure { name, acc: ComplexAcc::new() });
+    ComplexMeasureIdx(self.measures.complex_measures.len() - 1)
+  }
+
+  /// Registers a new quantile measure with a given `name`, estimating the
+  /// quantiles with `sketch`, e.g. `QuantileSketch::default()`. Returns a
+  /// safely wrapped index of the quantile measure. If a measure with the same
+  /// name has been registered before, panics.
+  pub fn register_quantiles(&mut self, name: String, sketch: QuantileSketch)
+      -> QuantileMeasureIdx {
+    if self.is_registered(&name) {
+      panic!("Ambiguous measure definition: '{}' was registered twice.", &name);
+    }
+    self.measures.quantile_measures.push(QuantileMeasure { name, sketch });
+    QuantileMeasureIdx(self.measures.quantile_measures.len() - 1)
+  }
+
+  /// Registers a new counter measure with a given `name`. Returns a safely
+  /// wrapped index of the counter measure. If a measure with the same name has
+  /// been registered before, panics.
+  pub fn register_counter(&mut self, name: String) -> CounterMeasureIdx {
+    if self.is_registered(&name) {
+      panic!("Ambiguous measure definition: '{}' was registered twice.", &name);
+    }
+    self.measures.counter_measures.push(CounterMeasure { name, acc: CounterAcc::new() });
+    CounterMeasureIdx(self.measures.counter_measures.len() - 1)
+  }
+
+  /// Registers a new rate measure with a given `name`. Returns a safely wrapped
+  /// index of the rate measure. If a measure with the same name has been
+  /// registered before, panics.
+  pub fn register_rate(&mut self, name: String) -> RateMeasureIdx {
+    if self.is_registered(&name) {
+      panic!("Ambiguous measure definition: '{}' was registered twice.", &name);
+    }
+    self.measures.rate_measures.push(RateMeasure { name, acc: RateAcc::new() });
+    RateMeasureIdx(self.measures.rate_measures.len() - 1)
+  }
+
+  /// Registers a new covariance group with a given `name` over the measures
+  /// pointed to by `members`. Returns a safely wrapped index of the group. If a
+  /// group with the same name has been registered before, or a measure is
+  /// listed twice, panics.
+  pub fn register_covariance_group(&mut self, name: String, members: &[MeasureIdx])
+      -> CovarianceGroupIdx {
+    if self.measures.covariance_groups.iter().any(|group| group.name == name) {
+      panic!("Ambiguous covariance group definition: '{}' was registered twice.",
+             &name);
+    }
+    let indices: Vec<usize> = members.iter().map(|idx| idx.0).collect();
+    for (pos, idx) in indices.iter().enumerate() {
+      if indices[..pos].contains(idx) {
+        panic!("Measure '{}' is listed twice in covariance group '{}'.",
+               &self.measures.measures[*idx].name, &name);
+      }
+    }
+    self.measures.covariance_groups.push(CovarianceGroup {
+      name,
+      measures: indices.iter()
+          .map(|idx| self.measures.measures[*idx].name.clone()).collect(),
+      acc: CovAcc::new(indices.len()),
+      indices,
+      staged: Vec::new(),
+    });
+    CovarianceGroupIdx(self.measures.covariance_groups.len() - 1)
+  }
+
+  /// Registers a new derived measure with a given `name`, computing `function`
+  /// of the expectation values of `inputs`. If a measure with the same name
+  /// has been registered before, or an input is a component out of range,
+  /// panics.
+  pub fn register_derived(&mut self, name: String, inputs: &[DerivedInput],
+                          function: DerivedFunction) {
+    if self.is_registered(&name) {
+      panic!("Ambiguous measure definition: '{}' was registered twice.", &name);
+    }
+    let inputs = inputs.iter().map(|input| match *input {
+      DerivedInput::Scalar(idx) => self.measures.measures[idx.0].name.clone(),
+      DerivedInput::Component(idx, i) => {
+        let measure = &self.measures.vector_measures[idx.0];
+        if self.vector_lens.iter().any(|&(len_idx, _)| len_idx.0 == idx.0) {
+          // The component is checked once the number of components is known.
+          self.derived_components.push((name.clone(), idx, i));
+        } else if i >= measure.acc.len() {
+          panic!("Derived measure '{}' refers to component {} of '{}', which has {}.",
+                 &name, i, &measure.name, measure.acc.len());
+        }
+        format!("{}[{}]", measure.name, i)
+      }
+    }).collect();
+    if let Err(err) = self.measures.add_derived_measure(name, inputs, function) {
+      panic!("{}", err);
+    }
+  }
+
+  /// Tells whether a measure of any kind with a given `name` has been
+  /// registered.
+  fn is_registered(&self, name: &str) -> bool {
+    self.name_index.contains_key(name)
+        || self.vector_name_index.contains_key(name)
+        || self.measures.histogram_measures.iter().any(|measure| measure.name == name)
+        || self.measures.moment_measures.iter().any(|measure| measure.name == name)
+        || self.measures.complex_measures.iter().any(|measure| measure.name == name)
+        || self.measures.quantile_measures.iter().any(|measure| measure.name == name)
+        || self.measures.counter_measures.iter().any(|measure| measure.name == name)
+        || self.measures.rate_measures.iter().any(|measure| measure.name == name)
+        || self.measures.derived_measures.iter().any(|derived| derived.name == name)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Registers the scalar measures `A` and `B`, and the covariance group `AB`
+  /// over `B` and `A`.
+  fn grouped() -> (Measures, MeasureIdx, MeasureIdx) {
+    let mut reg = MeasureRegistry::new();
+    let a = reg.register("A".to_string());
+    let b = reg.register("B".to_string());
+    reg.register_covariance_group("AB".to_string(), &[b, a]);
+    (reg.freeze(), a, b)
+  }
+
+  /// Records the values of `A` and `B` of a sample.
+  fn record(measures: &mut Measures, a: MeasureIdx, b: MeasureIdx, values: (f64, f64)) {
+    measures.accumulate(a, values.0);
+    measures.accumulate(b, values.1);
+    measures.commit_staged();
+  }
+
+  #[test]
+  fn reindexes_covariance_groups_after_deserializing() {
+    let (mut measures, a, b) = grouped();
+    record(&mut measures, a, b, (1.0, 2.0));
+    let json = ::serde_json::to_string(&measures).unwrap();
+    let mut restored: Measures = ::serde_json::from_str(&json).unwrap();
+    record(&mut restored, a, b, (3.0, 6.0));
+    let group = &restored.covariance_group_slice()[0].acc;
+    assert_eq!(group.num_of_samples(), 2.0);
+    assert_eq!(group.values(), &[4.0, 2.0]);
+  }
+
+  #[test]
+  fn reindexes_covariance_groups_after_merging() {
+    let (mut measures, a, b) = grouped();
+    record(&mut measures, a, b, (1.0, 2.0));
+    // The members are at other positions in the merged collection.
+    let mut reg = MeasureRegistry::new();
+    let c = reg.register("C".to_string());
+    let mut merged = reg.freeze();
+    merged.merge(&measures).unwrap();
+    let (a, b) = (MeasureIdx(1), MeasureIdx(2));
+    assert_eq!(merged.get(a).name, "A");
+    record(&mut merged, a, b, (3.0, 6.0));
+    merged.accumulate(c, 5.0);
+    merged.commit_staged();
+    let group = &merged.covariance_group_slice()[0].acc;
+    assert_eq!(group.num_of_samples(), 2.0);
+    assert_eq!(group.values(), &[4.0, 2.0]);
+  }
+
+  #[test]
+  fn retain_removes_covariance_groups_with_removed_members() {
+    let mut reg = MeasureRegistry::new();
+    let x = reg.register("X".to_string());
+    let a = reg.register("A".to_string());
+    let b = reg.register("B".to_string());
+    reg.register_covariance_group("AB".to_string(), &[a, b]);
+    reg.register_covariance_group("XA".to_string(), &[x, a]);
+    let mut measures = reg.freeze();
+    measures.retain(|name| name != "X");
+    let names: Vec<&str> = measures.covariance_group_slice().iter()
+        .map(|group| group.name.as_str()).collect();
+    assert_eq!(names, vec!["AB"]);
+    // The remaining group follows its members to their new positions.
+    record(&mut measures, MeasureIdx(0), MeasureIdx(1), (1.0, 2.0));
+    assert_eq!(measures.covariance_group_slice()[0].acc.values(), &[1.0, 2.0]);
+  }
+
+  #[test]
+  fn merge_reports_conflicting_vector_measures_and_groups() {
+    let measures = |len: usize, members: &[&str]| {
+      let mut reg = MeasureRegistry::new();
+      let idx: Vec<MeasureIdx> = members.iter()
+          .map(|name| reg.register(name.to_string())).collect();
+      reg.register_covariance_group("G".to_string(), &idx);
+      let v = reg.register_vector("V".to_string(), len);
+      let mut measures = reg.freeze();
+      measures.accumulate_slice(v, &vec![1.0; len]);
+      measures.commit_staged();
+      measures
+    };
+    let mut merged = measures(2, &["A", "B"]);
+    let err = merged.merge(&measures(3, &["B", "A"])).unwrap_err();
+    assert!(err.contains("vector measure 'V'"), "{}", err);
+    assert!(err.contains("covariance group 'G'"), "{}", err);
+    assert_eq!(merged.vector_slice()[0].acc.num_of_samples(), 1.0);
+    merged.merge(&measures(2, &["A", "B"])).unwrap();
+    assert_eq!(merged.vector_slice()[0].acc.num_of_samples(), 2.0);
+  }
+
+  #[test]
+  fn merge_block_records_a_block_per_data_point() {
+    let mut reg = MeasureRegistry::new();
+    let x = reg.register("X".to_string());
+    let v = reg.register_vector("V".to_string(), 2);
+    reg.register_derived("X/V[1]".to_string(),
+                         &[DerivedInput::Scalar(x), DerivedInput::Component(v, 1)],
+                         Arc::new(|inputs: &[f64]| inputs[0] / inputs[1]));
+    let template = reg.freeze();
+    let data_point = |x_value: f64, v_value: Option<f64>| {
+      let mut measures = template.clone();
+      measures.accumulate(x, x_value);
+      if let Some(v_value) = v_value {
+        measures.accumulate_slice(v, &[0.0, v_value]);
+      }
+      measures.commit_staged();
+      measures
+    };
+
+    // Derived measures missing from the merged collection are added.
+    let mut merged = Measures::new_empty();
+    merged.merge_block(&data_point(2.0, Some(1.0))).unwrap();
+    merged.merge_block(&data_point(6.0, Some(2.0))).unwrap();
+    // A block without some of the inputs is skipped.
+    merged.merge_block(&data_point(1.0, None)).unwrap();
+    let derived = &merged.derived_slice()[0];
+    assert_eq!(derived.num_of_blocks(), 2);
+    assert_eq!(merged.get(x).acc.num_of_samples(), 3.0);
+    // The ratio of the means over the blocks, rather than the mean of ratios.
+    assert_eq!(derived.value(), 4.0 / 1.5);
+  }
+
+  #[test]
+  fn merge_block_weighs_inputs_by_their_weights() {
+    let mut reg = MeasureRegistry::new();
+    let x = reg.register("X".to_string());
+    reg.register_derived("Mean X".to_string(), &[DerivedInput::Scalar(x)],
+                         Arc::new(|inputs: &[f64]| inputs[0]));
+    let template = reg.freeze();
+    let mut merged = Measures::new_empty();
+    for &(value, weight) in &[(1.0, 3.0), (3.0, 1.0)] {
+      let mut measures = template.clone();
+      measures.accumulate_weighted(x, value, weight);
+      merged.merge_block(&measures).unwrap();
+    }
+    assert_eq!(merged.stats("X"), Some((4.0, 1.5)));
+    assert_eq!(merged.derived_slice()[0].value(), 1.5);
+  }
+
+  #[test]
+  fn stats_parses_names_of_parts_and_components() {
+    let mut reg = MeasureRegistry::new();
+    let x = reg.register("X".to_string());
+    let v = reg.register_vector("V".to_string(), 2);
+    let z = reg.register_complex("Z".to_string());
+    let c = reg.register_counter("C".to_string());
+    let r = reg.register_rate("R".to_string());
+    reg.register("Empty".to_string());
+    let mut measures = reg.freeze();
+    measures.accumulate(x, 1.5);
+    measures.accumulate_slice(v, &[2.0, 3.0]);
+    measures.accumulate_complex(z, 4.0, -5.0);
+    measures.accumulate_count(c, 6);
+    measures.accumulate_outcome(r, true);
+    measures.accumulate_outcome(r, false);
+    measures.commit_staged();
+
+    assert_eq!(measures.stats("X"), Some((1.0, 1.5)));
+    assert_eq!(measures.stats("V[0]"), Some((1.0, 2.0)));
+    assert_eq!(measures.stats("V[1]"), Some((1.0, 3.0)));
+    assert_eq!(measures.stats("Re(Z)"), Some((1.0, 4.0)));
+    assert_eq!(measures.stats("Im(Z)"), Some((1.0, -5.0)));
+    assert_eq!(measures.stats("C"), Some((1.0, 6.0)));
+    assert_eq!(measures.stats("R"), Some((2.0, 0.5)));
+    for name in &["V[2]", "V[-1]", "V[]", "V", "Z", "Ab(Z)", "Re(Z", "Re(X)", "X[0]",
+                  "Empty", "Missing"] {
+      assert_eq!(measures.stats(name), None, "{}", name);
+    }
+  }
+
+  #[test]
+  fn freeze_at_sizes_vector_measures_by_params() {
+    let mut reg = MeasureRegistry::new();
+    let fixed = reg.register_vector("Fixed".to_string(), 2);
+    let len: VectorLen = Arc::new(|params: &BTreeMap<String, Value>| {
+      params.get("n").and_then(Value::as_u64).map(|n| n as usize)
+          .ok_or_else(|| Error::Config("n is missing.".to_string()))
+    });
+    let sized = reg.register_vector_with("Sized".to_string(), len);
+    reg.register_derived("Last".to_string(), &[DerivedInput::Component(sized, 2)],
+                         Arc::new(|inputs| inputs[0]));
+    assert_eq!(reg.measures().get_vector(sized).acc.len(), 0);
+
+    let params = |n: u64| -> BTreeMap<String, Value> {
+      vec![("n".to_string(), Value::from(n))].into_iter().collect()
+    };
+    for n in 3..5 {
+      let measures = reg.freeze_at(&params(n)).unwrap();
+      assert_eq!(measures.get_vector(fixed).acc.len(), 2);
+      assert_eq!(measures.get_vector(sized).acc.len(), n as usize);
+    }
+    match reg.freeze_at(&params(2)) {
+      Err(Error::Config(err)) => assert!(err.contains("component 2 of 'Sized'"), "{}", err),
+      _ => panic!("Accepted a derived measure of a missing component."),
+    }
+    assert!(reg.freeze_at(&BTreeMap::new()).is_err());
   }
 }
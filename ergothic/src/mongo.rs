@@ -0,0 +1,461 @@
+use bson::Bson;
+use bson::Document;
+use std::io::Read;
+use std::io::Write;
+use std::net::TcpStream;
+use std::net::ToSocketAddrs;
+use std::time::Duration;
+
+/// Op code of the `OP_MSG` message, the only wire protocol message used by
+/// modern MongoDB servers (3.6+).
+const OP_MSG: i32 = 2013;
+
+/// Port assumed for hosts listed in the connection string without one.
+const DEFAULT_PORT: u16 = 27017;
+
+/// Timeout for connecting to a host and for each read or write on the socket.
+/// A stuck data sink should surface as an export error instead of stalling
+/// the simulation forever.
+const IO_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Upper bound on the size of a server reply. MongoDB limits messages to 48MB.
+const MAX_MESSAGE_SIZE: usize = 48 * 1024 * 1024;
+
+/// A minimal MongoDB client speaking the `OP_MSG` wire protocol over plain TCP.
+/// It supports exactly what *ergothic* needs: running commands against a
+/// database. Authentication and TLS are not supported.
+/// The connection is established lazily and dropped on any I/O error, so that
+/// the next command reconnects, trying the hosts from the connection string in
+/// order.
+pub struct Client {
+    hosts: Vec<(String, u16)>,
+    stream: Option<TcpStream>,
+    next_request_id: i32,
+}
+
+impl Client {
+    /// Constructs a client from a connection string of the form
+    /// `mongodb://host1[:port1][,host2[:port2],...][/[database][?options]]`,
+    /// with IPv6 hosts in brackets, e.g. `[::1]:27017`. Does not connect to the
+    /// server.
+    pub fn new(uri: &str) -> Result<Client, String> {
+        let rest = match uri.trim().strip_prefix("mongodb://") {
+            Some(rest) => rest,
+            None => return Err(format!("Not a mongodb:// connection string: '{}'.", uri)),
+        };
+        let host_list = rest.split(['/', '?']).next().unwrap_or("");
+        if host_list.contains('@') {
+            return Err(
+                "Authentication in MongoDB connection strings is not supported.".to_string(),
+            );
+        }
+        let mut hosts = Vec::new();
+        for host in host_list.split(',').filter(|h| !h.is_empty()) {
+            hosts.push(parse_host(host)?);
+        }
+        if hosts.is_empty() {
+            return Err(format!("No hosts in MongoDB connection string '{}'.", uri));
+        }
+        Ok(Client {
+            hosts,
+            stream: None,
+            next_request_id: 1,
+        })
+    }
+
+    /// Runs a command against the database `db` and returns the server reply.
+    /// Replies with `ok` different from 1 are converted to errors.
+    pub fn command(&mut self, db: &str, mut command: Document) -> Result<Document, String> {
+        command.insert("$db", db);
+        let result = self.round_trip(&command);
+        if result.is_err() {
+            // The connection may be in an undefined state. Reconnect next time.
+            self.stream = None;
+        }
+        let reply = result?;
+        let ok = match reply.get("ok") {
+            Some(&Bson::FloatingPoint(ok)) => ok == 1.0,
+            Some(&Bson::I32(ok)) => ok == 1,
+            Some(&Bson::I64(ok)) => ok == 1,
+            _ => false,
+        };
+        if !ok {
+            return Err(format!(
+                "MongoDB command failed: {}",
+                reply.get_str("errmsg").unwrap_or("unknown error")
+            ));
+        }
+        Ok(reply)
+    }
+
+    /// Inserts a single document into the collection `coll` of the database
+    /// `db`. Write errors reported by the server are converted to errors.
+    pub fn insert(&mut self, db: &str, coll: &str, document: Document) -> Result<(), String> {
+        let reply = self.command(
+            db,
+            doc! {
+                "insert": coll,
+                "documents": [document],
+            },
+        )?;
+        if let Ok(write_errors) = reply.get_array("writeErrors") {
+            if !write_errors.is_empty() {
+                return Err(format!(
+                    "MongoDB write errors: {}",
+                    Bson::Array(write_errors.clone())
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns all documents of the collection `coll` of the database `db`
+    /// matching `filter`, exhausting the server-side cursor.
+    pub fn find(
+        &mut self,
+        db: &str,
+        coll: &str,
+        filter: Document,
+    ) -> Result<Vec<Document>, String> {
+        let mut reply = self.command(
+            db,
+            doc! {
+                "find": coll,
+                "filter": filter,
+            },
+        )?;
+        let mut documents = Vec::new();
+        let mut batch_name = "firstBatch";
+        loop {
+            let cursor = reply
+                .get_document("cursor")
+                .map_err(|err| format!("Malformed MongoDB cursor: {}", err))?;
+            let batch = cursor
+                .get_array(batch_name)
+                .map_err(|err| format!("Malformed MongoDB cursor: {}", err))?;
+            for item in batch.iter() {
+                match *item {
+                    Bson::Document(ref document) => documents.push(document.clone()),
+                    _ => return Err("Malformed MongoDB cursor: non-document item.".to_string()),
+                }
+            }
+            let cursor_id = cursor.get_i64("id").unwrap_or(0);
+            if cursor_id == 0 {
+                return Ok(documents);
+            }
+            reply = self.command(
+                db,
+                doc! {
+                    "getMore": cursor_id,
+                    "collection": coll,
+                },
+            )?;
+            batch_name = "nextBatch";
+        }
+    }
+
+    /// Returns the connected stream, connecting to the first reachable host if
+    /// there is no connection yet.
+    fn stream(&mut self) -> Result<&mut TcpStream, String> {
+        if self.stream.is_none() {
+            let mut errors = Vec::new();
+            for &(ref name, port) in self.hosts.iter() {
+                match connect(name, port) {
+                    Ok(stream) => {
+                        self.stream = Some(stream);
+                        break;
+                    }
+                    Err(err) => errors.push(format!("{}:{}: {}", name, port, err)),
+                }
+            }
+            if self.stream.is_none() {
+                return Err(format!(
+                    "Failed to connect to MongoDB: {}",
+                    errors.join("; ")
+                ));
+            }
+        }
+        Ok(self.stream.as_mut().unwrap())
+    }
+
+    /// Sends a single `OP_MSG` carrying `command` and reads the reply.
+    fn round_trip(&mut self, command: &Document) -> Result<Document, String> {
+        let mut body = Vec::new();
+        bson::encode_document(&mut body, command).map_err(|err| format!("{}", err))?;
+        let request_id = self.next_request_id;
+        self.next_request_id = self.next_request_id.wrapping_add(1);
+
+        let mut message = Vec::with_capacity(21 + body.len());
+        write_i32(&mut message, (21 + body.len()) as i32);
+        write_i32(&mut message, request_id);
+        write_i32(&mut message, 0); // responseTo.
+        write_i32(&mut message, OP_MSG);
+        write_i32(&mut message, 0); // flagBits.
+        message.push(0); // Section kind 0: a single command document.
+        message.extend_from_slice(&body);
+
+        let stream = self.stream()?;
+        stream
+            .write_all(&message)
+            .map_err(|err| format!("{}", err))?;
+
+        let mut header = [0u8; 16];
+        stream
+            .read_exact(&mut header)
+            .map_err(|err| format!("{}", err))?;
+        let length = read_i32(&header[0..4]) as usize;
+        if !(21..=MAX_MESSAGE_SIZE).contains(&length) {
+            return Err(format!("Malformed MongoDB reply of length {}.", length));
+        }
+        if read_i32(&header[8..12]) != request_id || read_i32(&header[12..16]) != OP_MSG {
+            return Err("Unexpected MongoDB reply.".to_string());
+        }
+        let mut reply = vec![0u8; length - 16];
+        stream
+            .read_exact(&mut reply)
+            .map_err(|err| format!("{}", err))?;
+        // Skip flagBits. The reply consists of a single kind 0 section.
+        if reply[4] != 0 {
+            return Err(format!(
+                "Unsupported MongoDB reply section kind {}.",
+                reply[4]
+            ));
+        }
+        bson::decode_document(&mut &reply[5..]).map_err(|err| format!("{}", err))
+    }
+}
+
+/// Splits a host of a connection string into the name and the port. IPv6
+/// addresses are given in brackets, e.g. `[::1]:27017`.
+fn parse_host(host: &str) -> Result<(String, u16), String> {
+    let (name, port) = if let Some(rest) = host.strip_prefix('[') {
+        match rest.find(']') {
+            Some(end) => match &rest[end + 1..] {
+                "" => (&rest[..end], None),
+                port => match port.strip_prefix(':') {
+                    Some(port) => (&rest[..end], Some(port)),
+                    None => return Err(format!("Invalid MongoDB host '{}'.", host)),
+                },
+            },
+            None => return Err(format!("Unclosed bracket in MongoDB host '{}'.", host)),
+        }
+    } else {
+        let mut parts = host.rsplitn(2, ':');
+        let last = parts.next().unwrap();
+        match parts.next() {
+            Some(name) => (name, Some(last)),
+            None => (last, None),
+        }
+    };
+    if name.is_empty() {
+        return Err(format!("Invalid MongoDB host '{}'.", host));
+    }
+    let port = match port {
+        Some(port) => port
+            .parse::<u16>()
+            .map_err(|_| format!("Invalid port in MongoDB host '{}'.", host))?,
+        None => DEFAULT_PORT,
+    };
+    Ok((name.to_string(), port))
+}
+
+/// Connects to the first resolved address of `name:port`.
+fn connect(name: &str, port: u16) -> ::std::io::Result<TcpStream> {
+    let mut last_err = None;
+    for addr in (name, port).to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, IO_TIMEOUT) {
+            Ok(stream) => {
+                stream.set_read_timeout(Some(IO_TIMEOUT))?;
+                stream.set_write_timeout(Some(IO_TIMEOUT))?;
+                stream.set_nodelay(true)?;
+                return Ok(stream);
+            }
+            Err(err) => last_err = Some(err),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| {
+        ::std::io::Error::new(::std::io::ErrorKind::NotFound, "host did not resolve")
+    }))
+}
+
+fn write_i32(buf: &mut Vec<u8>, value: i32) {
+    buf.extend_from_slice(&value.to_le_bytes());
+}
+
+fn read_i32(bytes: &[u8]) -> i32 {
+    i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use export::DataPoint;
+    use export::Exporter;
+    use export::MongoExporter;
+    use measure::MeasureRegistry;
+    use measure::Measures;
+    use std::collections::BTreeMap;
+    use std::net::TcpListener;
+    use std::thread;
+    use std::thread::JoinHandle;
+
+    /// An in-process stand-in for mongod. Accepts a single connection, answers
+    /// `num_of_commands` OP_MSG commands with the replies produced by `respond`
+    /// and returns the commands it has received.
+    fn stand_in<F>(num_of_commands: usize, respond: F) -> (String, JoinHandle<Vec<Document>>)
+    where
+        F: Fn(&Document) -> Document + Send + 'static,
+    {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let uri = format!("mongodb://{}/", listener.local_addr().unwrap());
+        let handle = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut commands = Vec::new();
+            for _ in 0..num_of_commands {
+                let mut header = [0u8; 16];
+                stream.read_exact(&mut header).unwrap();
+                assert_eq!(read_i32(&header[12..16]), OP_MSG);
+                let mut body = vec![0u8; read_i32(&header[0..4]) as usize - 16];
+                stream.read_exact(&mut body).unwrap();
+                assert_eq!(read_i32(&body[0..4]), 0, "flagBits");
+                assert_eq!(body[4], 0, "section kind");
+                let command = bson::decode_document(&mut &body[5..]).unwrap();
+
+                let mut reply = Vec::new();
+                bson::encode_document(&mut reply, &respond(&command)).unwrap();
+                let mut message = Vec::new();
+                write_i32(&mut message, (21 + reply.len()) as i32);
+                write_i32(&mut message, 0);
+                write_i32(&mut message, read_i32(&header[4..8])); // responseTo.
+                write_i32(&mut message, OP_MSG);
+                write_i32(&mut message, 0);
+                message.push(0);
+                message.extend_from_slice(&reply);
+                stream.write_all(&message).unwrap();
+                commands.push(command);
+            }
+            commands
+        });
+        (uri, handle)
+    }
+
+    #[test]
+    fn export_inserts_data_point() {
+        let (uri, stand_in) = stand_in(1, |_| doc! { "ok": 1.0, "n": 1 });
+        let mut reg = MeasureRegistry::new();
+        let x = reg.register("X".to_string());
+        let mut measures = reg.freeze();
+        measures.accumulate(x, 1.0);
+        measures.accumulate(x, 3.0);
+        let mut params = BTreeMap::new();
+        params.insert("beta".to_string(), ::serde_json::json!(5.7));
+        let data_point = DataPoint::new("Sim", "node1", 42, &params, &measures);
+
+        let mut exporter = MongoExporter::new(&uri, "ergothic_data", "sim").unwrap();
+        exporter.export(&data_point).unwrap();
+
+        let commands = stand_in.join().unwrap();
+        assert_eq!(commands[0].get_str("insert").unwrap(), "sim");
+        assert_eq!(commands[0].get_str("$db").unwrap(), "ergothic_data");
+        let documents = commands[0].get_array("documents").unwrap();
+        assert_eq!(documents.len(), 1);
+        let document = match documents[0] {
+            Bson::Document(ref document) => document.clone(),
+            _ => panic!("Not a document: {}", documents[0]),
+        };
+        assert_eq!(document.get_str("simulation").unwrap(), "Sim");
+        assert_eq!(document.get_str("host").unwrap(), "node1");
+        assert_eq!(document.get_i64("seed").unwrap(), 42);
+        let stored: DataPoint = ::bson::from_bson(Bson::Document(document)).unwrap();
+        assert_eq!(stored.measures.get(x).acc.value(), 2.0);
+        assert_eq!(stored.params, params);
+    }
+
+    #[test]
+    fn export_reports_write_errors() {
+        let (uri, stand_in) = stand_in(1, |_| {
+            doc! {
+                "ok": 1.0,
+                "n": 0,
+                "writeErrors": [{ "index": 0, "code": 11000, "errmsg": "duplicate key" }],
+            }
+        });
+        let mut exporter = MongoExporter::new(&uri, "db", "coll").unwrap();
+        let data_point =
+            DataPoint::new("Sim", "node1", 0, &BTreeMap::new(), &Measures::new_empty());
+        let err = exporter.export(&data_point).unwrap_err();
+        assert!(err.0.contains("duplicate key"), "{}", err.0);
+        stand_in.join().unwrap();
+    }
+
+    #[test]
+    fn export_reports_failed_commands() {
+        let (uri, stand_in) = stand_in(1, |_| doc! { "ok": 0.0, "errmsg": "not primary" });
+        let mut exporter = MongoExporter::new(&uri, "db", "coll").unwrap();
+        let data_point =
+            DataPoint::new("Sim", "node1", 0, &BTreeMap::new(), &Measures::new_empty());
+        let err = exporter.export(&data_point).unwrap_err();
+        assert!(err.0.contains("not primary"), "{}", err.0);
+        stand_in.join().unwrap();
+    }
+
+    #[test]
+    fn export_reports_connection_errors() {
+        // Nothing listens on the port once the listener is dropped.
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let mut exporter =
+            MongoExporter::new(&format!("mongodb://{}", addr), "db", "coll").unwrap();
+        let data_point =
+            DataPoint::new("Sim", "node1", 0, &BTreeMap::new(), &Measures::new_empty());
+        let err = exporter.export(&data_point).unwrap_err();
+        assert!(
+            err.0.starts_with("Failed to connect to MongoDB"),
+            "{}",
+            err.0
+        );
+    }
+
+    #[test]
+    fn find_exhausts_cursor() {
+        let (uri, stand_in) = stand_in(2, |command| {
+            if command.contains_key("find") {
+                doc! { "ok": 1.0, "cursor": { "id": 7i64, "firstBatch": [{ "x": 1 }] } }
+            } else {
+                doc! { "ok": 1.0, "cursor": { "id": 0i64, "nextBatch": [{ "x": 2 }] } }
+            }
+        });
+        let mut client = Client::new(&uri).unwrap();
+        let documents = client.find("db", "coll", doc! {}).unwrap();
+        let xs: Vec<i32> = documents.iter().map(|d| d.get_i32("x").unwrap()).collect();
+        assert_eq!(xs, vec![1, 2]);
+        let commands = stand_in.join().unwrap();
+        assert_eq!(commands[1].get_i64("getMore").unwrap(), 7);
+        assert_eq!(commands[1].get_str("collection").unwrap(), "coll");
+    }
+
+    #[test]
+    fn parses_connection_strings() {
+        let client = Client::new("mongodb://a,b:27018/db?w=1").unwrap();
+        assert_eq!(
+            client.hosts,
+            vec![("a".to_string(), 27017), ("b".to_string(), 27018)]
+        );
+        let client = Client::new("mongodb://[::1]:27018,[fe80::1],c").unwrap();
+        assert_eq!(
+            client.hosts,
+            vec![
+                ("::1".to_string(), 27018),
+                ("fe80::1".to_string(), 27017),
+                ("c".to_string(), 27017)
+            ]
+        );
+        assert!(Client::new("mongodb://[::1/").is_err());
+        assert!(Client::new("mongodb://[::1]x/").is_err());
+        assert!(Client::new("mongodb://a:port/").is_err());
+        assert!(Client::new("mongodb://user:pass@a/").is_err());
+        assert!(Client::new("http://a/").is_err());
+    }
+}
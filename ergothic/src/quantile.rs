@@ -0,0 +1,340 @@
+/// A `QuantileSketch` estimates quantiles of the distribution of an
+/// observable, e.g. the median and the tail percentiles of heavy-tailed
+/// observables, for which the mean is misleading. It is a t-digest (Dunning &
+/// Ertl): the samples are clustered into centroids, which are small in the
+/// tails and large around the median, so that the relative accuracy of the
+/// tail quantiles stays high while the memory footprint is bounded by the
+/// `compression`. Sketches are mergeable across flushes and nodes.
+#[derive(Clone, Deserialize, Serialize)]
+#[serde(into = "QuantileSketchRepr", from = "QuantileSketchRepr")]
+pub struct QuantileSketch {
+    compression: f64,
+    min: f64,
+    max: f64,
+
+    /// Centroids as pairs of (mean, weight), sorted by mean.
+    centroids: Vec<(f64, f64)>,
+
+    /// Samples not yet merged into the centroids.
+    buffer: Vec<(f64, f64)>,
+}
+
+/// Serialized form of `QuantileSketch`, with all samples merged into the
+/// centroids. The bounds of an empty sketch are infinite, which JSON can't
+/// represent, so they are omitted.
+#[derive(Deserialize, Serialize)]
+struct QuantileSketchRepr {
+    compression: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    min: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    max: Option<f64>,
+    centroids: Vec<(f64, f64)>,
+}
+
+impl From<QuantileSketch> for QuantileSketchRepr {
+    fn from(mut sketch: QuantileSketch) -> QuantileSketchRepr {
+        sketch.compress();
+        let empty = sketch.centroids.is_empty();
+        QuantileSketchRepr {
+            compression: sketch.compression,
+            min: if empty { None } else { Some(sketch.min) },
+            max: if empty { None } else { Some(sketch.max) },
+            centroids: sketch.centroids,
+        }
+    }
+}
+
+impl From<QuantileSketchRepr> for QuantileSketch {
+    fn from(repr: QuantileSketchRepr) -> QuantileSketch {
+        QuantileSketch {
+            compression: repr.compression,
+            min: repr.min.unwrap_or(f64::INFINITY),
+            max: repr.max.unwrap_or(f64::NEG_INFINITY),
+            centroids: repr.centroids,
+            buffer: Vec::new(),
+        }
+    }
+}
+
+/// Compression of sketches constructed with `QuantileSketch::default()`. Keeps
+/// about a hundred centroids, and estimates the quantiles to a fraction of a
+/// percent in rank, better in the tails.
+const DEFAULT_COMPRESSION: f64 = 200.0;
+
+impl Default for QuantileSketch {
+    fn default() -> QuantileSketch {
+        QuantileSketch::new(DEFAULT_COMPRESSION)
+    }
+}
+
+impl QuantileSketch {
+    /// Constructs an empty sketch. The number of centroids grows with the
+    /// `compression`; higher values are more accurate, but take more memory.
+    pub fn new(compression: f64) -> QuantileSketch {
+        assert!(
+            compression >= 10.0,
+            "QuantileSketch::new(..): compression should be at least 10."
+        );
+        QuantileSketch {
+            compression,
+            min: f64::INFINITY,
+            max: f64::NEG_INFINITY,
+            centroids: Vec::new(),
+            buffer: Vec::new(),
+        }
+    }
+
+    /// Constructs an empty sketch with the same compression as `self`.
+    pub fn cleared(&self) -> QuantileSketch {
+        QuantileSketch::new(self.compression)
+    }
+
+    /// Gives the number of recorded samples.
+    pub fn num_of_samples(&self) -> f64 {
+        let weight = |centroids: &[(f64, f64)]| centroids.iter().map(|c| c.1).sum::<f64>();
+        weight(&self.centroids) + weight(&self.buffer)
+    }
+
+    /// Gives the smallest recorded sample.
+    pub fn min(&self) -> f64 {
+        self.min
+    }
+
+    /// Gives the largest recorded sample.
+    pub fn max(&self) -> f64 {
+        self.max
+    }
+
+    /// Consumes a sample value. Non-finite values are skipped: infinite
+    /// centroids can't be merged, and JSON can't represent them.
+    pub fn consume(&mut self, value: f64) {
+        if !value.is_finite() {
+            return;
+        }
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+        self.buffer.push((value, 1.0));
+        if self.buffer.len() as f64 > 5.0 * self.compression {
+            self.compress();
+        }
+    }
+
+    /// Merges another sketch into this one. Up to the accuracy of the sketches,
+    /// equivalent to calling `self.consume(..)` for each of the samples
+    /// consumed previously by `other`.
+    pub fn merge(&mut self, other: &QuantileSketch) {
+        self.min = self.min.min(other.min);
+        self.max = self.max.max(other.max);
+        self.buffer.extend_from_slice(&other.centroids);
+        self.buffer.extend_from_slice(&other.buffer);
+        self.compress();
+    }
+
+    /// Gives the estimate of the `q`-th quantile, e.g. the median for `q` = 0.5.
+    /// NaN if no samples have been recorded.
+    pub fn quantile(&self, q: f64) -> f64 {
+        let mut sketch = self.clone();
+        sketch.compress();
+        sketch.compressed_quantile(q.clamp(0.0, 1.0))
+    }
+
+    /// Gives the estimates of several quantiles at once, see `quantile(..)`.
+    pub fn quantiles(&self, qs: &[f64]) -> Vec<f64> {
+        let mut sketch = self.clone();
+        sketch.compress();
+        qs.iter()
+            .map(|q| sketch.compressed_quantile(q.clamp(0.0, 1.0)))
+            .collect()
+    }
+
+    fn compressed_quantile(&self, q: f64) -> f64 {
+        let centroids = &self.centroids;
+        let (first, last) = match (centroids.first(), centroids.last()) {
+            (Some(first), Some(last)) => (*first, *last),
+            _ => return f64::NAN,
+        };
+        if centroids.len() == 1 {
+            return first.0;
+        }
+        let total: f64 = centroids.iter().map(|c| c.1).sum();
+        let target = q * total;
+        // Between the smallest sample and the center of the first centroid.
+        if target < first.1 / 2.0 {
+            return self.min + (first.0 - self.min) * target / (first.1 / 2.0);
+        }
+        // Between the center of the last centroid and the largest sample.
+        if target > total - last.1 / 2.0 {
+            let rest = total - target;
+            return self.max - (self.max - last.0) * rest / (last.1 / 2.0);
+        }
+        // Interpolate linearly between the centers of adjacent centroids.
+        let mut center = first.1 / 2.0;
+        for pair in centroids.windows(2) {
+            let next_center = center + (pair[0].1 + pair[1].1) / 2.0;
+            if target <= next_center {
+                let t = (target - center) / (next_center - center);
+                return pair[0].0 + (pair[1].0 - pair[0].0) * t;
+            }
+            center = next_center;
+        }
+        last.0
+    }
+
+    /// Merges the buffered samples into the centroids. A centroid may grow as
+    /// long as it spans at most one unit of the scale function
+    /// `k(q) = compression / z * ln(q / (1 - q))`, with the normalization
+    /// `z = 4 ln(n / compression) + 24` for `n` samples. The centroids grow
+    /// geometrically from single samples at the extremes towards the median.
+    fn compress(&mut self) {
+        if self.buffer.is_empty() {
+            return;
+        }
+        let mut points = ::std::mem::take(&mut self.centroids);
+        points.append(&mut self.buffer);
+        points.sort_by(|a, b| a.0.total_cmp(&b.0));
+        let total: f64 = points.iter().map(|c| c.1).sum();
+        let normalization = 4.0 * (total / self.compression).max(1.0).ln() + 24.0;
+        let scale = self.compression / normalization;
+        let k = |q: f64| scale * (q / (1.0 - q)).ln();
+        let k_inv = |k: f64| 1.0 / (1.0 + (-k / scale).exp());
+
+        let mut points = points.into_iter();
+        let mut current = points.next().unwrap();
+        let mut weight_before = 0.0;
+        let mut weight_limit = total * k_inv(k(0.0) + 1.0);
+        for point in points {
+            if weight_before + current.1 + point.1 <= weight_limit {
+                let weight = current.1 + point.1;
+                current.0 += (point.0 - current.0) * point.1 / weight;
+                current.1 = weight;
+            } else {
+                weight_before += current.1;
+                self.centroids.push(current);
+                weight_limit = total * k_inv(k(weight_before / total) + 1.0);
+                current = point;
+            }
+        }
+        self.centroids.push(current);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::rngs::StdRng;
+    use rand::Rng;
+    use rand::SeedableRng;
+
+    /// Draws `n` samples from the exponential distribution with unit rate,
+    /// whose `q`-th quantile is `-ln(1 - q)`.
+    fn exponential_values(rng: &mut StdRng, n: usize) -> Vec<f64> {
+        (0..n).map(|_| -(1.0 - rng.gen::<f64>()).ln()).collect()
+    }
+
+    /// Checks the estimates of `sketch` against the quantiles of the
+    /// exponential distribution. The ranks of the estimates may be off by
+    /// four standard deviations of the ranks of the sample quantiles.
+    fn assert_exponential_quantiles(sketch: &QuantileSketch) {
+        let n = sketch.num_of_samples();
+        let qs = [0.001, 0.01, 0.1, 0.25, 0.5, 0.75, 0.9, 0.99, 0.999];
+        for (&q, estimate) in qs.iter().zip(sketch.quantiles(&qs)) {
+            let rank = 1.0 - (-estimate).exp();
+            assert!(
+                (rank - q).abs() <= 4.0 * (q * (1.0 - q) / n).sqrt(),
+                "quantile {}: estimate {} has rank {}",
+                q,
+                estimate,
+                rank
+            );
+        }
+    }
+
+    /// Writes `sketch` to JSON and reads it back.
+    fn round_trip(sketch: &QuantileSketch) -> QuantileSketch {
+        ::serde_json::from_str(&::serde_json::to_string(sketch).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn empty_sketch_round_trips() {
+        let sketch = QuantileSketch::new(50.0);
+        assert_eq!(
+            ::serde_json::to_string(&sketch).unwrap(),
+            r#"{"compression":50.0,"centroids":[]}"#
+        );
+        let mut restored = round_trip(&sketch);
+        assert_eq!(restored.num_of_samples(), 0.0);
+
+        // The restored sketch still takes the bounds of new samples.
+        restored.consume(2.0);
+        restored.consume(-3.0);
+        assert_eq!((restored.min(), restored.max()), (-3.0, 2.0));
+
+        let mut merged = QuantileSketch::new(50.0);
+        merged.merge(&round_trip(&sketch));
+        merged.merge(&restored);
+        assert_eq!((merged.min(), merged.max()), (-3.0, 2.0));
+    }
+
+    #[test]
+    fn estimates_quantiles_of_known_distribution() {
+        let mut rng = StdRng::seed_from_u64(19);
+        let mut sketch = QuantileSketch::default();
+        for value in exponential_values(&mut rng, 100_000) {
+            sketch.consume(value);
+        }
+        assert_eq!(sketch.num_of_samples(), 100_000.0);
+        assert!(sketch.centroids.len() < 200);
+        assert_exponential_quantiles(&sketch);
+        assert_eq!(sketch.quantile(0.0), sketch.min());
+        assert_eq!(sketch.quantile(1.0), sketch.max());
+    }
+
+    #[test]
+    fn merged_sketches_estimate_quantiles_of_all_samples() {
+        let mut rng = StdRng::seed_from_u64(19);
+        let values = exponential_values(&mut rng, 100_000);
+        let mut merged = QuantileSketch::default();
+        for chunk in values.chunks(7_000) {
+            let mut sketch = QuantileSketch::default();
+            for &value in chunk {
+                sketch.consume(value);
+            }
+            merged.merge(&round_trip(&sketch));
+        }
+        let min = values.iter().cloned().fold(f64::INFINITY, f64::min);
+        let max = values.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+        assert_eq!(merged.num_of_samples(), 100_000.0);
+        assert_eq!((merged.min(), merged.max()), (min, max));
+        assert!(merged.centroids.len() < 200);
+        assert_exponential_quantiles(&merged);
+    }
+
+    #[test]
+    fn skips_non_finite_values() {
+        let mut sketch = QuantileSketch::new(50.0);
+        for &value in &[f64::INFINITY, f64::INFINITY, f64::NEG_INFINITY, f64::NAN] {
+            sketch.consume(value);
+        }
+        assert_eq!(sketch.num_of_samples(), 0.0);
+        sketch.consume(1.0);
+        sketch.consume(f64::INFINITY);
+        let mut merged = round_trip(&sketch);
+        merged.merge(&sketch);
+        assert_eq!(merged.num_of_samples(), 2.0);
+        assert_eq!((merged.min(), merged.max()), (1.0, 1.0));
+        assert_eq!(merged.quantile(0.5), 1.0);
+    }
+
+    #[test]
+    fn sketch_round_trips() {
+        let mut sketch = QuantileSketch::default();
+        for i in 0..1000 {
+            sketch.consume(f64::from(i));
+        }
+        let restored = round_trip(&sketch);
+        assert_eq!(restored.num_of_samples(), 1000.0);
+        assert_eq!((restored.min(), restored.max()), (0.0, 999.0));
+        assert_eq!(restored.quantile(0.5), sketch.quantile(0.5));
+    }
+}
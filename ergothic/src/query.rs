@@ -0,0 +1,245 @@
+use export::DataPoint;
+use std::fs::File;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::path::Path;
+
+/// Errors returned when reading data points. Contain a string describing the
+/// cause of the error.
+#[derive(Debug)]
+pub struct QueryError(pub String);
+
+/// Selects data points by the simulation name and the time of the flush.
+#[derive(Clone, Default)]
+pub struct Filter {
+    /// Only select data points produced by the simulation with this name.
+    pub simulation: Option<String>,
+
+    /// Only select data points flushed at or after this time, in seconds since
+    /// the UNIX epoch.
+    pub since: Option<i64>,
+
+    /// Only select data points flushed at or before this time, in seconds since
+    /// the UNIX epoch.
+    pub until: Option<i64>,
+}
+
+impl Filter {
+    /// Tells whether the data point satisfies all conditions of the filter.
+    pub fn matches(&self, data_point: &DataPoint) -> bool {
+        if let Some(ref simulation) = self.simulation {
+            if data_point.simulation != *simulation {
+                return false;
+            }
+        }
+        if let Some(since) = self.since {
+            if data_point.timestamp < since {
+                return false;
+            }
+        }
+        if let Some(until) = self.until {
+            if data_point.timestamp > until {
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Expresses the filter as a MongoDB query document.
+    fn to_bson(&self) -> ::bson::Document {
+        let mut query = ::bson::Document::new();
+        if let Some(ref simulation) = self.simulation {
+            query.insert("simulation", simulation.clone());
+        }
+        let mut timestamp = ::bson::Document::new();
+        if let Some(since) = self.since {
+            timestamp.insert("$gte", since);
+        }
+        if let Some(until) = self.until {
+            timestamp.insert("$lte", until);
+        }
+        if !timestamp.is_empty() {
+            query.insert("timestamp", timestamp);
+        }
+        query
+    }
+}
+
+/// Reads the data points matching `filter` from a file written by
+/// `FileExporter`. Empty lines are skipped. A malformed last line is skipped
+/// with a warning, since it may be torn by a node killed mid-write or still
+/// being written, while malformed lines followed by other data points are
+/// errors.
+pub fn read_file<P: AsRef<Path>>(path: P, filter: &Filter) -> Result<Vec<DataPoint>, QueryError> {
+    let path = path.as_ref();
+    let file = File::open(path)
+        .map_err(|err| QueryError(format!("Failed to open {}: {}", path.display(), err)))?;
+    let mut data_points = Vec::new();
+    // The last malformed line, which is only an error if more lines follow.
+    let mut malformed: Option<QueryError> = None;
+    for (line_no, line) in BufReader::new(file).lines().enumerate() {
+        let line =
+            line.map_err(|err| QueryError(format!("Failed to read {}: {}", path.display(), err)))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        if let Some(err) = malformed.take() {
+            return Err(err);
+        }
+        match ::serde_json::from_str::<DataPoint>(&line) {
+            Ok(data_point) => {
+                if filter.matches(&data_point) {
+                    data_points.push(data_point);
+                }
+            }
+            Err(err) => {
+                malformed = Some(QueryError(format!(
+                    "{}:{}: {}",
+                    path.display(),
+                    line_no + 1,
+                    err
+                )))
+            }
+        }
+    }
+    if let Some(QueryError(err)) = malformed {
+        warn!("Skipping an incomplete last data point: {}", err);
+    }
+    Ok(data_points)
+}
+
+/// Reads the data points matching `filter` from the collection `coll` of the
+/// database `db` written by `MongoExporter`.
+pub fn read_mongo(
+    uri: &str,
+    db: &str,
+    coll: &str,
+    filter: &Filter,
+) -> Result<Vec<DataPoint>, QueryError> {
+    let mut client = ::mongo::Client::new(uri).map_err(QueryError)?;
+    let documents = client
+        .find(db, coll, filter.to_bson())
+        .map_err(QueryError)?;
+    let mut data_points = Vec::with_capacity(documents.len());
+    for document in documents {
+        let data_point = ::bson::from_bson(::bson::Bson::Document(document))
+            .map_err(|err| QueryError(format!("Malformed data point: {}", err)))?;
+        data_points.push(data_point);
+    }
+    Ok(data_points)
+}
+
+/// Matches `name` against a shell-style glob `pattern`, where `*` matches any
+/// sequence of characters and `?` matches any single character.
+pub fn glob_matches(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    // Position in the pattern right after the last `*`, and the position in the
+    // name it has been matched against. Used for backtracking.
+    let mut backtrack: Option<(usize, usize)> = None;
+    let (mut p, mut n) = (0, 0);
+    while n < name.len() {
+        if p < pattern.len() && (pattern[p] == '?' || pattern[p] == name[n]) {
+            p += 1;
+            n += 1;
+        } else if p < pattern.len() && pattern[p] == '*' {
+            p += 1;
+            backtrack = Some((p, n));
+        } else if let Some((star_p, star_n)) = backtrack {
+            // Let the last `*` absorb one more character.
+            p = star_p;
+            n = star_n + 1;
+            backtrack = Some((star_p, star_n + 1));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use measure::Measures;
+    use std::collections::BTreeMap;
+    use std::path::PathBuf;
+
+    /// Writes `contents` to a file of a test and returns its path.
+    fn temp_file(name: &str, contents: &str) -> PathBuf {
+        let path = ::std::env::temp_dir().join(format!(
+            "ergothic-query-{}-{}.jsonl",
+            name,
+            ::std::process::id()
+        ));
+        ::std::fs::write(&path, contents).unwrap();
+        path
+    }
+
+    /// A line of the file exporter holding a data point of the simulation
+    /// `simulation`.
+    fn line(simulation: &str) -> String {
+        let data_point = DataPoint::new(
+            simulation,
+            "node1",
+            0,
+            &BTreeMap::new(),
+            &Measures::new_empty(),
+        );
+        ::serde_json::to_string(&data_point).unwrap() + "\n"
+    }
+
+    #[test]
+    fn read_file_skips_torn_last_line() {
+        let torn = &line("B")[..20];
+        let path = temp_file("torn", &(line("A") + &line("B") + torn));
+        let data_points = read_file(&path, &Filter::default()).unwrap();
+        let simulations: Vec<&str> = data_points.iter().map(|d| &d.simulation[..]).collect();
+        assert_eq!(simulations, vec!["A", "B"]);
+    }
+
+    #[test]
+    fn read_file_rejects_malformed_lines_in_the_middle() {
+        let path = temp_file("malformed", &(line("A") + "{\"simulation\n\n" + &line("B")));
+        let err = match read_file(&path, &Filter::default()) {
+            Ok(_) => panic!("A malformed line has been skipped."),
+            Err(err) => err,
+        };
+        assert!(err.0.contains(":2:"), "{}", err.0);
+    }
+
+    #[test]
+    fn read_file_filters_data_points() {
+        let path = temp_file("filter", &(line("A") + &line("B") + &line("A")));
+        let filter = Filter {
+            simulation: Some("A".to_string()),
+            ..Filter::default()
+        };
+        assert_eq!(read_file(&path, &filter).unwrap().len(), 2);
+    }
+
+    #[test]
+    fn glob_matches_literal_names() {
+        assert!(glob_matches("Ising", "Ising"));
+        assert!(!glob_matches("Ising", "Ising2"));
+        assert!(!glob_matches("Ising", "Isin"));
+        assert!(glob_matches("", ""));
+        assert!(!glob_matches("", "Ising"));
+    }
+
+    #[test]
+    fn glob_matches_wildcards() {
+        assert!(glob_matches("*", ""));
+        assert!(glob_matches("*", "Ising"));
+        assert!(glob_matches("Ising*", "Ising 2D"));
+        assert!(glob_matches("*2D", "Ising 2D"));
+        assert!(glob_matches("Is*g*D", "Ising 2D"));
+        assert!(glob_matches("I?ing", "Ising"));
+        assert!(!glob_matches("I?ing", "Iing"));
+        assert!(!glob_matches("*3D", "Ising 2D"));
+        // The last `*` has to backtrack past a partial match.
+        assert!(glob_matches("*ab", "aab"));
+        assert!(glob_matches("a*b*c", "abbbc"));
+        assert!(!glob_matches("a*b*c", "abbbcd"));
+        assert!(glob_matches("Wilson ??", "Wilson 4x"));
+    }
+}
@@ -1,12 +1,42 @@
+use checkpoint::ChainRng;
+use checkpoint::Checkpoint;
+use checkpoint::Codec;
+use error::Error;
+use export::ExportError;
 use measure::Measures;
+use rand::Rng;
+use rand::SeedableRng;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
 use std::time::Duration;
+use std::time::Instant;
 use std::time::SystemTime;
 
 /// A configuration sample from the ergodic distribution must implement this
 /// trait in order to be used in the *ergothic* simulation.
+/// All randomness must come from the `rng` supplied by the simulation engine.
+/// The engine seeds it from the `--seed` command line argument, so that any run
+/// can be reproduced exactly.
 pub trait Sample {
-    /// Creates a new configuration sample with randomized degrees of freedom.
-    fn prepare() -> Self;
+    /// Physics parameters of the simulation, e.g. the lattice size and the
+    /// coupling, usually a struct deriving `Deserialize` and `Serialize`. The
+    /// engine fills them from the `params` table of the configuration file, the
+    /// `ERGOTHIC_PARAM_<name>` environment variables and the `--param` command
+    /// line arguments, rejecting the parameters it doesn't use, and records them
+    /// in every exported data point. Use `NoParams` if there are none.
+    type Params: Serialize + DeserializeOwned + Sync;
+
+    /// Creates a new configuration sample with randomized degrees of freedom
+    /// for given `params`.
+    fn prepare<R: Rng>(params: &Self::Params, rng: &mut R) -> Self;
 
     /// Generally, randomized samples are highly atypical. In order to improve the
     /// quality of simulation results, a configuration sample has to be
@@ -16,9 +46,9 @@ pub trait Sample {
     /// Simulation engines allowed free to call this function from time to time to
     /// get rid of possible biases and improve ergodicity, as long as it is not on
     /// the critical path.
-    fn thermalize(&mut self) {
+    fn thermalize<R: Rng>(&mut self, rng: &mut R) {
         for _ in 0..20 {
-            self.mutate();
+            self.mutate(rng);
         }
     }
 
@@ -27,14 +57,195 @@ pub trait Sample {
     /// that your implementation is not biased.
     /// The most common implementation of `mutate` uses the Metropolis algorithm.
     /// You may want to check out the `metropolis` module for useful helpers.
-    fn mutate(&mut self);
+    fn mutate<R: Rng>(&mut self, rng: &mut R);
+}
+
+/// Parameters of samples which have none, see `Sample::Params`. Rejects any
+/// parameters given in the configuration.
+#[derive(Clone, Copy, Debug, Default, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct NoParams {}
+
+/// Deserializes the physics parameters of the simulation into `P`. Also returns
+/// them serialized back, with the defaults filled in, for recording them in the
+/// data points.
+pub fn resolve_params<P: Serialize + DeserializeOwned>(
+    params: BTreeMap<String, Value>,
+) -> Result<(P, BTreeMap<String, Value>), Error> {
+    let invalid = |err: ::serde_json::Error| {
+        Error::Config(format!("Invalid parameters of the simulation: {}", err))
+    };
+    let typed: P = ::config::deserialize_params(params)
+        .map_err(|err| Error::Config(format!("Invalid parameters of the simulation: {}", err)))?;
+    let recorded = match ::serde_json::to_value(&typed).map_err(invalid)? {
+        Value::Object(map) => map.into_iter().collect(),
+        _ => {
+            return Err(Error::Config(
+                "The parameters of the simulation should be a struct.".to_string(),
+            ))
+        }
+    };
+    Ok((typed, recorded))
+}
+
+/// Measures aggregated over a run of the simulation at a point of the parameter
+/// space, e.g. at a grid point of a sweep.
+#[derive(Clone)]
+pub struct RunResult {
+    /// The physics parameters of the point with the defaults filled in, as
+    /// recorded in the data points.
+    pub params: BTreeMap<String, Value>,
+
+    /// The measures aggregated over the whole run.
+    pub measures: Measures,
+}
+
+/// Conditions for stopping the simulation. The simulation stops as soon as any
+/// of the conditions is met. Without conditions, it runs forever.
+#[derive(Clone, Debug, Default)]
+pub struct Termination {
+    /// Stop after drawing this many samples in total across all chains.
+    pub max_samples: Option<u64>,
+
+    /// Stop after running for this long.
+    pub max_time: Option<Duration>,
+
+    /// Stop when the relative binned uncertainty of the target measures drops
+    /// below this value. Checked on every flush.
+    pub target_relative_uncertainty: Option<f64>,
+
+    /// Names of the measures checked against `target_relative_uncertainty`. If
+    /// empty, all measures are checked.
+    pub target_measures: Vec<String>,
+}
+
+impl Termination {
+    /// Checks that all target measures are scalar measures of `measures`.
+    fn validate(&self, measures: &Measures) -> Result<(), Error> {
+        for target_measure in self.target_measures.iter() {
+            if !measures.slice().iter().any(|m| m.name == *target_measure) {
+                return Err(Error::Config(format!(
+                    "Unknown target measure '{}' (--target_measure), expected the name of a \
+                     scalar measure.",
+                    target_measure
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// Tells whether the target uncertainty has been reached for all target
+    /// measures. Measures with no samples or a zero expectation value never
+    /// reach the target, and neither does a simulation without target measures.
+    fn target_reached(&self, measures: &Measures) -> bool {
+        let target = match self.target_relative_uncertainty {
+            Some(target) => target,
+            None => return false,
+        };
+        let mut checked = false;
+        let targets = measures.slice().iter().filter(|measure| {
+            self.target_measures.is_empty() || self.target_measures.contains(&measure.name)
+        });
+        for measure in targets {
+            let value = measure.acc.value().abs();
+            // Also rejects NaN values.
+            if !(value > 0.0 && measure.acc.binned_uncertainty() / value < target) {
+                return false;
+            }
+            checked = true;
+        }
+        checked
+    }
 }
 
+/// State shared by all chains, which is used for controlling them from the
+/// flushing thread.
+struct ChainControl {
+    /// Raised from outside of the engine, see `Parameters::stop`. The engine
+    /// never raises it itself, so that the flag can be shared by several runs.
+    interrupt: Arc<AtomicBool>,
+
+    /// Raised by the engine when a termination condition is met.
+    stop: AtomicBool,
+    samples: AtomicU64,
+    max_samples: Option<u64>,
+
+    /// Incremented to request snapshots of the samples for a new checkpoint.
+    checkpoint_epoch: AtomicU64,
+
+    /// The first error the simulation couldn't recover from.
+    failure: Mutex<Option<Error>>,
+}
+
+impl ChainControl {
+    /// Reserves the next sample for the calling chain. Returns false if the
+    /// chain should stop instead. Samples are only counted if the number of
+    /// samples is limited.
+    fn next_sample(&self) -> bool {
+        if self.stopped() {
+            return false;
+        }
+        if let Some(max_samples) = self.max_samples {
+            if self.samples.fetch_add(1, Ordering::Relaxed) >= max_samples {
+                self.stop();
+                return false;
+            }
+        }
+        true
+    }
+
+    /// Tells whether the chains should stop.
+    fn stopped(&self) -> bool {
+        self.stop.load(Ordering::Relaxed) || self.interrupt.load(Ordering::Relaxed)
+    }
+
+    /// Stops all chains.
+    fn stop(&self) {
+        self.stop.store(true, Ordering::Relaxed);
+    }
+
+    /// Records an error the simulation can't recover from, and stops all
+    /// chains. Only the first error is returned from the run.
+    fn fail(&self, err: Error) {
+        error!("{}", err);
+        let mut failure = self.failure.lock().unwrap();
+        if failure.is_none() {
+            *failure = Some(err);
+        }
+        self.stop();
+    }
+}
+
+/// State of a background chain shared with the flushing thread.
+struct ChainState {
+    /// Values measured by the chain since the last flush.
+    measures: Measures,
+
+    /// Whether the chain has finished preparing its sample.
+    running: bool,
+
+    /// The latest snapshot of the sample and of the random number generator,
+    /// and the checkpoint epoch it was taken for.
+    snapshot: Option<(u64, Snapshot)>,
+}
+
+/// A saved sample, and the random number generator of its chain.
+type Snapshot = (Value, ChainRng);
+
 /// Simulation parameters.
 pub struct Parameters {
     /// The name of the simulation.
     pub name: String,
 
+    /// Identifies the node running the simulation in the exported data points.
+    pub host: String,
+
+    /// Seed of the random number generators of the chains. Recorded in the
+    /// exported data points. Running with the same seed and the same number of
+    /// threads reproduces the chains exactly. Chains resumed from a checkpoint
+    /// continue with the generators saved in it instead.
+    pub seed: u64,
+
     /// List of measures relevant to the simulation. Each `flush_interval`, the
     /// measures from that list will be exported to the data sink.
     pub measures: ::measure::Measures,
@@ -46,59 +257,985 @@ pub struct Parameters {
     /// Interval between subsequent flushes of the accumulated values.
     pub flush_interval: Duration,
 
-    /// Panic after this many export errors in a row.
+    /// Stop the simulation with an error after this many export errors in a
+    /// row.
     pub max_export_errors_in_row: Option<usize>,
+
+    /// Number of independent Markov chains, each running in its own thread.
+    /// Chains never communicate with each other. Their accumulated values are
+    /// merged together when flushing.
+    pub threads: usize,
+
+    /// Conditions for stopping the simulation.
+    pub termination: Termination,
+
+    /// Setting this flag from any thread (or a signal handler) stops the
+    /// simulation gracefully, as if a termination condition was met. Each chain
+    /// finishes its current step, and the accumulated values are flushed.
+    pub stop: Arc<AtomicBool>,
+
+    /// On termination, the final export is retried until this deadline
+    /// expires.
+    pub final_export_deadline: Duration,
+
+    /// If set, the samples of all chains and the measured values which haven't
+    /// been exported yet are periodically saved to this file. Requires a
+    /// `Checkpointable` sample.
+    pub checkpoint_path: Option<PathBuf>,
+
+    /// Minimum interval between subsequent checkpoints. Checkpoints are only
+    /// written after flushing.
+    pub checkpoint_interval: Duration,
+
+    /// If set, the simulation continues from this checkpoint instead of
+    /// preparing and thermalizing new samples. Requires a `Checkpointable`
+    /// sample.
+    pub resume: Option<Checkpoint>,
+
+    /// Physics parameters of the simulation, deserialized into `Sample::Params`
+    /// and recorded in every exported data point.
+    pub params: BTreeMap<String, Value>,
 }
 
-/// Runs the simulation in the infinite loop. Consumes `self`.
+/// Runs the simulation until one of the termination conditions is met, or in
+/// the infinite loop if there are none. Consumes `self`.
 /// The function `measure_fn` is called for each configuration sample. It is
 /// supplied with the (immutable) sample, and a mutable reference to the
 /// collection of measures. The function should calculate the values of the
 /// physical quantities for the configuration sample and supply those to the
 /// accumulators contained in the collection of measures.
-pub fn run<S: Sample, F>(mut parameters: Parameters, measure_fn: F)
+/// The first chain runs in the calling thread, which is also responsible for
+/// flushing. Each of the remaining `parameters.threads - 1` chains runs in a
+/// thread of its own, with its own sample and its own copy of the measures.
+/// Checkpointing requires a `codec` for the samples.
+/// On termination, performs a final flush and returns the measures aggregated
+/// over the whole run. Returns an error if the final flush has failed within
+/// `parameters.final_export_deadline`, in which case some of the measured
+/// values have been lost, if `parameters.max_export_errors_in_row` has been
+/// reached, or if a sample couldn't be saved or restored.
+pub fn run<S: Sample, F>(
+    mut parameters: Parameters,
+    codec: Option<Codec<S>>,
+    measure_fn: F,
+) -> Result<RunResult, Error>
 where
-    F: Fn(&S, &mut Measures),
+    F: Fn(&S, &mut Measures) + Sync,
 {
     info!("Running ergothic simulation \"{}\".", &parameters.name);
-    // Prepare and thermalize a sample.
-    let mut sample = S::prepare();
-    sample.thermalize();
-    let mut last_export_timestamp = SystemTime::now();
+    if codec.is_none() && (parameters.checkpoint_path.is_some() || parameters.resume.is_some()) {
+        return Err(Error::Config(
+            "Checkpoints require a Checkpointable sample, run the simulation with \
+             run_checkpointable."
+                .to_string(),
+        ));
+    }
+    parameters.termination.validate(&parameters.measures)?;
+    let (params, recorded_params) = resolve_params::<S::Params>(parameters.params.clone())?;
+    parameters.params = recorded_params;
+    let start_timestamp = Instant::now();
+    let control = ChainControl {
+        interrupt: parameters.stop.clone(),
+        stop: AtomicBool::new(false),
+        samples: AtomicU64::new(0),
+        max_samples: parameters.termination.max_samples,
+        checkpoint_epoch: AtomicU64::new(0),
+        failure: Mutex::new(None),
+    };
+
+    // Each chain draws its random numbers from a generator of its own, seeded
+    // from the node seed. Chains resumed from a checkpoint continue with their
+    // saved generators, and the other chains are seeded where the previous run
+    // has left off.
+    let mut seeder = ChainRng::seed_from_u64(parameters.seed);
+    // Samples to resume the chains from, if any.
+    let mut resumed_samples = Vec::new();
+    let mut resumed_rngs = Vec::new();
+    if let Some(checkpoint) = parameters.resume.take() {
+        if let Some(ref params) = checkpoint.params {
+            if *params != parameters.params {
+                return Err(Error::Config(format!(
+                    "The checkpoint was written with different parameters ({}) than the \
+                     simulation runs with ({}).",
+                    ::export::DebugExporter::format_params(params),
+                    ::export::DebugExporter::format_params(&parameters.params),
+                )));
+            }
+        }
+        info!(
+            "Resuming {} chains from a checkpoint.",
+            checkpoint.samples.len()
+        );
+        if checkpoint.samples.len() > parameters.threads {
+            warn!(
+                "Dropping {} chains from the checkpoint.",
+                checkpoint.samples.len() - parameters.threads
+            );
+        }
+        parameters
+            .measures
+            .merge(&checkpoint.measures)
+            .map_err(|err| {
+                Error::Config(format!("Failed to resume from the checkpoint. {}", err))
+            })?;
+        resumed_samples = checkpoint.samples;
+        resumed_rngs = checkpoint.rngs;
+        if let Some(saved_seeder) = checkpoint.seeder {
+            seeder = saved_seeder;
+        }
+    }
+    resumed_samples.resize(parameters.threads, Value::Null);
+    resumed_rngs.resize(parameters.threads, None);
+    let mut rngs: Vec<ChainRng> = resumed_samples
+        .iter()
+        .zip(resumed_rngs)
+        .map(|(sample, rng)| match rng {
+            Some(rng) if !sample.is_null() => rng,
+            _ => ChainRng::from_rng(&mut seeder).expect("Failed to seed a chain"),
+        })
+        .collect();
+
+    // State of the background chains. Each mutex is only ever contended while
+    // flushing or checkpointing.
+    let mut empty_measures = parameters.measures.clone();
+    empty_measures.reset();
+    let chain_states: Vec<Mutex<ChainState>> = (1..parameters.threads)
+        .map(|_| {
+            Mutex::new(ChainState {
+                measures: empty_measures.clone(),
+                running: false,
+                snapshot: None,
+            })
+        })
+        .collect();
+    // Values from all successfully exported data points.
+    let mut aggregated = empty_measures;
     let mut export_errors_in_row: usize = 0;
+    let mut checkpoints = Checkpoints {
+        last_timestamp: Instant::now(),
+        pending: None,
+        written: None,
+        seeder,
+    };
+    let codec = codec.as_ref();
+    let main_snapshot = ::std::thread::scope(|scope| {
+        let background = chain_states
+            .iter()
+            .zip(resumed_samples.drain(1..))
+            .zip(rngs.drain(1..));
+        for ((state, resumed), rng) in background {
+            let params = &params;
+            let measure_fn = &measure_fn;
+            let control = &control;
+            scope.spawn(move || run_chain(state, resumed, params, rng, codec, measure_fn, control));
+        }
+
+        // Prepare and thermalize a sample, or restore it from the checkpoint.
+        let mut rng = rngs.pop().unwrap();
+        let resumed = resumed_samples.pop().unwrap();
+        let mut sample = match initial_sample(resumed, &params, &mut rng, codec) {
+            Ok(sample) => sample,
+            Err(err) => {
+                control.fail(err);
+                return None;
+            }
+        };
+        let mut last_export_timestamp = SystemTime::now();
+        while control.next_sample() {
+            // Mutate the sample. This draws a new configuration from the ergodic
+            // distribution.
+            sample.mutate(&mut rng);
+
+            // Measure and record the values of observables.
+            measure_fn(&sample, &mut parameters.measures);
+            parameters.measures.commit_staged();
+
+            if let Some(max_time) = parameters.termination.max_time {
+                if start_timestamp.elapsed() >= max_time {
+                    control.stop();
+                }
+            }
+
+            if last_export_timestamp.elapsed().unwrap() >= parameters.flush_interval {
+                last_export_timestamp = SystemTime::now();
+                match flush(&mut parameters, &chain_states, &mut aggregated) {
+                    Ok(()) => {
+                        export_errors_in_row = 0;
+                        checkpoints.forget_exported(&parameters);
+                    }
+                    Err(ExportError(ref err)) => {
+                        export_errors_in_row += 1;
+                        error!("Failed to export measured values: {:?}", err);
+                        let max_export_errors_in_row = parameters.max_export_errors_in_row;
+                        if max_export_errors_in_row.is_some_and(|max| export_errors_in_row >= max) {
+                            control.fail(Error::Export(ExportError(format!(
+                                "{} errors in a row, the last one being: {}",
+                                export_errors_in_row, err
+                            ))));
+                        }
+                    }
+                }
+                if parameters.termination.target_reached(&aggregated) {
+                    info!("Reached the target uncertainty.");
+                    control.stop();
+                }
+                if let Some(codec) = codec {
+                    checkpoints.start(&parameters, &sample, &rng, codec, &control);
+                }
+            }
+            checkpoints.try_complete(&parameters, &chain_states, &control);
+        }
+        codec.and_then(|codec| match (codec.encode)(&sample) {
+            Ok(snapshot) => Some((snapshot, rng)),
+            Err(err) => {
+                control.fail(Error::Sample(err));
+                None
+            }
+        })
+    });
+
+    // All chains have stopped by now. Export the remaining values, retrying
+    // with exponential backoff until the deadline, unless exporting has already
+    // failed too many times.
+    info!("Terminating ergothic simulation \"{}\".", &parameters.name);
+    let failure = control.failure.into_inner().unwrap();
+    let result = match failure {
+        Some(Error::Export(_)) => Ok(()),
+        _ => final_flush(&mut parameters, &chain_states, &mut aggregated),
+    };
+    // Save the final samples, together with the values that couldn't be
+    // exported. If a sample couldn't be saved or restored, the previous
+    // checkpoint is kept instead, since the sample would be lost otherwise.
+    let main_snapshot = match failure {
+        Some(Error::Sample(_)) => None,
+        _ => main_snapshot,
+    };
+    if let Some(main_snapshot) = main_snapshot {
+        let mut snapshots = vec![Some(main_snapshot)];
+        snapshots.extend(chain_states.iter().map(|state| {
+            state
+                .lock()
+                .unwrap()
+                .snapshot
+                .take()
+                .map(|(_, snapshot)| snapshot)
+        }));
+        checkpoints.write(&parameters, snapshots);
+    }
+    match failure {
+        Some(err) => {
+            if let Err(ExportError(err)) = result {
+                error!("Failed to export the remaining measured values: {}", err);
+            }
+            Err(err)
+        }
+        None => result
+            .map(|()| RunResult {
+                params: parameters.params,
+                measures: aggregated,
+            })
+            .map_err(Error::Export),
+    }
+}
+
+/// Exports the remaining values on termination, retrying with exponential
+/// backoff until `parameters.final_export_deadline`.
+fn final_flush(
+    parameters: &mut Parameters,
+    chain_states: &[Mutex<ChainState>],
+    aggregated: &mut Measures,
+) -> Result<(), ExportError> {
+    let deadline = Instant::now() + parameters.final_export_deadline;
+    let mut backoff = Duration::from_millis(100);
     loop {
-        // Mutate the sample. This draws a new configuration from the ergodic
-        // distribution.
-        sample.mutate();
-
-        // Measure and record the values of observables.
-        measure_fn(&sample, &mut parameters.measures);
-
-        if last_export_timestamp.elapsed().unwrap() >= parameters.flush_interval {
-            last_export_timestamp = SystemTime::now();
-            // Export a new data point containing the accumulated expectations.
-            match parameters.exporter.export(&parameters.measures) {
-                Ok(()) => {
-                    export_errors_in_row = 0;
-                    // Exported a data point. Reset the accumulated expectations and
-                    // continue the simulation.
-                    parameters.measures.reset();
+        match flush(parameters, chain_states, aggregated) {
+            Ok(()) => return Ok(()),
+            Err(err) => {
+                let now = Instant::now();
+                if now + backoff > deadline {
+                    return Err(err);
                 }
-                Err(::export::ExportError(ref err)) => {
-                    export_errors_in_row += 1;
-                    // Export failed. Reporting an error and keeping the accumulated
-                    // expectations in hope of exporting them the next time.
-                    error!("Failed to export measured values: {:?}", err);
+                error!("Failed to export measured values, retrying: {:?}", err.0);
+                ::std::thread::sleep(backoff);
+                backoff *= 2;
+            }
+        }
+    }
+}
+
+/// Collects the values accumulated by all chains and exports them as a new
+/// data point, unless there are none. On success, the exported values are
+/// moved to `aggregated`.
+/// Otherwise, they are kept in `parameters.measures` in hope of exporting them
+/// the next time.
+fn flush(
+    parameters: &mut Parameters,
+    chain_states: &[Mutex<ChainState>],
+    aggregated: &mut Measures,
+) -> Result<(), ExportError> {
+    for state in chain_states.iter() {
+        let measures = &mut state.lock().unwrap().measures;
+        if let Err(err) = parameters.measures.merge(measures) {
+            warn!("{}", err);
+        }
+        measures.reset();
+    }
+    if parameters.measures.is_empty() {
+        // Nothing to export.
+        return Ok(());
+    }
+    // Export a new data point containing the accumulated expectations.
+    let data_point = ::export::DataPoint::new(
+        &parameters.name,
+        &parameters.host,
+        parameters.seed,
+        &parameters.params,
+        &parameters.measures,
+    );
+    parameters.exporter.export(&data_point)?;
+    // Exported a data point. Reset the accumulated expectations and continue
+    // the simulation.
+    if let Err(err) = aggregated.merge_block(&parameters.measures) {
+        warn!("{}", err);
+    }
+    parameters.measures.reset();
+    Ok(())
+}
+
+/// Restores a sample from its snapshot, or prepares and thermalizes a new one
+/// for given `params` if there is no snapshot.
+fn initial_sample<S: Sample>(
+    snapshot: Value,
+    params: &S::Params,
+    rng: &mut ChainRng,
+    codec: Option<&Codec<S>>,
+) -> Result<S, Error> {
+    match codec {
+        Some(codec) if !snapshot.is_null() => (codec.decode)(snapshot).map_err(Error::Sample),
+        _ => {
+            let mut sample = S::prepare(params, rng);
+            sample.thermalize(rng);
+            Ok(sample)
+        }
+    }
+}
+
+/// Runs a background chain until stopped, recording the values of observables
+/// in its `state`. Takes a snapshot of the sample whenever a new checkpoint is
+/// requested, and on exit.
+fn run_chain<S: Sample, F>(
+    state: &Mutex<ChainState>,
+    resumed: Value,
+    params: &S::Params,
+    mut rng: ChainRng,
+    codec: Option<&Codec<S>>,
+    measure_fn: &F,
+    control: &ChainControl,
+) where
+    F: Fn(&S, &mut Measures),
+{
+    let mut sample = match initial_sample(resumed, params, &mut rng, codec) {
+        Ok(sample) => sample,
+        Err(err) => return control.fail(err),
+    };
+    state.lock().unwrap().running = true;
+    while control.next_sample() {
+        sample.mutate(&mut rng);
+        let mut state = state.lock().unwrap();
+        measure_fn(&sample, &mut state.measures);
+        state.measures.commit_staged();
+        if let Some(codec) = codec {
+            let epoch = control.checkpoint_epoch.load(Ordering::Relaxed);
+            if state.snapshot.as_ref().map_or(0, |snapshot| snapshot.0) != epoch {
+                match (codec.encode)(&sample) {
+                    Ok(snapshot) => state.snapshot = Some((epoch, (snapshot, rng.clone()))),
+                    Err(err) => return control.fail(Error::Sample(err)),
                 }
             }
-            if let Some(ref max_export_errors_in_row) = parameters.max_export_errors_in_row {
-                if export_errors_in_row >= *max_export_errors_in_row {
-                    panic!(
-                        "Reached a maximum of {} export errors in row.",
-                        *max_export_errors_in_row
-                    );
+        }
+    }
+    if let Some(codec) = codec {
+        match (codec.encode)(&sample) {
+            Ok(snapshot) => state.lock().unwrap().snapshot = Some((u64::MAX, (snapshot, rng))),
+            Err(err) => control.fail(Error::Sample(err)),
+        }
+    }
+}
+
+/// Keeps track of the checkpoints written by the flushing thread.
+struct Checkpoints {
+    last_timestamp: Instant,
+
+    /// A checkpoint waiting for the snapshots of the background chains, and
+    /// its epoch.
+    pending: Option<(u64, Checkpoint)>,
+
+    /// The last written checkpoint.
+    written: Option<Checkpoint>,
+
+    /// The generator seeding new chains after resuming.
+    seeder: ChainRng,
+}
+
+impl Checkpoints {
+    /// Starts a new checkpoint if it's due. Snapshots the sample of the calling
+    /// chain and the values that haven't been exported, and requests the
+    /// snapshots of the background chains.
+    fn start<S: Sample>(
+        &mut self,
+        parameters: &Parameters,
+        sample: &S,
+        rng: &ChainRng,
+        codec: &Codec<S>,
+        control: &ChainControl,
+    ) {
+        if parameters.checkpoint_path.is_none()
+            || self.pending.is_some()
+            || self.last_timestamp.elapsed() < parameters.checkpoint_interval
+        {
+            return;
+        }
+        self.last_timestamp = Instant::now();
+        let snapshot = match (codec.encode)(sample) {
+            Ok(snapshot) => snapshot,
+            Err(err) => return control.fail(Error::Sample(err)),
+        };
+        let epoch = control.checkpoint_epoch.fetch_add(1, Ordering::Relaxed) + 1;
+        let checkpoint = self.checkpoint(parameters, vec![Some((snapshot, rng.clone()))]);
+        self.pending = Some((epoch, checkpoint));
+    }
+
+    /// Writes the pending checkpoint once all running background chains have
+    /// taken their snapshots. Chains that haven't finished preparing their
+    /// samples are saved as `null`.
+    fn try_complete(
+        &mut self,
+        parameters: &Parameters,
+        chain_states: &[Mutex<ChainState>],
+        control: &ChainControl,
+    ) {
+        let epoch = match self.pending {
+            Some((epoch, _)) => epoch,
+            None => return,
+        };
+        let mut snapshots = Vec::with_capacity(chain_states.len());
+        for state in chain_states.iter() {
+            let state = state.lock().unwrap();
+            match state.snapshot {
+                Some((snapshot_epoch, ref snapshot)) if snapshot_epoch >= epoch => {
+                    snapshots.push(Some(snapshot.clone()))
+                }
+                _ if !state.running => snapshots.push(None),
+                _ if control.stopped() => {
+                    // The final checkpoint will be written instead.
+                    self.pending = None;
+                    return;
                 }
+                _ => return,
+            }
+        }
+        let (_, mut checkpoint) = self.pending.take().unwrap();
+        for snapshot in snapshots {
+            let (sample, rng) = match snapshot {
+                Some((sample, rng)) => (sample, Some(rng)),
+                None => (Value::Null, None),
+            };
+            checkpoint.samples.push(sample);
+            checkpoint.rngs.push(rng);
+        }
+        self.written = Some(checkpoint);
+        self.save(parameters);
+    }
+
+    /// Clears the unexported values from the last written checkpoint, once they
+    /// have been exported. Otherwise, resuming from it would export them again.
+    fn forget_exported(&mut self, parameters: &Parameters) {
+        if let Some((_, ref mut checkpoint)) = self.pending {
+            checkpoint.measures.reset();
+        }
+        let stale = match self.written {
+            Some(ref checkpoint) => !checkpoint.measures.is_empty(),
+            None => false,
+        };
+        if stale {
+            self.written.as_mut().unwrap().measures.reset();
+            self.save(parameters);
+        }
+    }
+
+    /// Writes a checkpoint with given snapshots right away.
+    fn write(&mut self, parameters: &Parameters, snapshots: Vec<Option<Snapshot>>) {
+        if parameters.checkpoint_path.is_none() {
+            return;
+        }
+        self.written = Some(self.checkpoint(parameters, snapshots));
+        self.save(parameters);
+    }
+
+    /// Constructs a checkpoint of given snapshots. Chains without a snapshot
+    /// are saved as `null`.
+    fn checkpoint(&self, parameters: &Parameters, snapshots: Vec<Option<Snapshot>>) -> Checkpoint {
+        let (samples, rngs) = snapshots
+            .into_iter()
+            .map(|snapshot| match snapshot {
+                Some((sample, rng)) => (sample, Some(rng)),
+                None => (Value::Null, None),
+            })
+            .unzip();
+        Checkpoint {
+            simulation: parameters.name.clone(),
+            params: Some(parameters.params.clone()),
+            timestamp: ::export::unix_timestamp(),
+            samples,
+            rngs,
+            seeder: Some(self.seeder.clone()),
+            measures: parameters.measures.clone(),
+        }
+    }
+
+    fn save(&self, parameters: &Parameters) {
+        if let (Some(path), Some(checkpoint)) = (&parameters.checkpoint_path, &self.written) {
+            match checkpoint.save(path) {
+                Ok(()) => info!("Saved a checkpoint to {}.", path.display()),
+                Err(err) => error!("Failed to save a checkpoint: {}", err),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use export::DataPoint;
+    use export::Exporter;
+    use measure::MeasureIdx;
+    use measure::MeasureRegistry;
+    use std::path::Path;
+
+    /// Counts its steps, and draws a random value on each of them.
+    struct Walk {
+        steps: u64,
+        value: f64,
+    }
+
+    impl Sample for Walk {
+        type Params = NoParams;
+
+        fn prepare<R: Rng>(_params: &NoParams, _rng: &mut R) -> Walk {
+            Walk {
+                steps: 0,
+                value: 0.0,
+            }
+        }
+
+        fn mutate<R: Rng>(&mut self, rng: &mut R) {
+            self.steps += 1;
+            self.value = rng.gen();
+        }
+    }
+
+    /// Keeps the exported data points.
+    #[derive(Clone, Default)]
+    struct Collector(Arc<Mutex<Vec<DataPoint>>>);
+
+    impl Exporter for Collector {
+        fn export(&mut self, data_point: &DataPoint) -> Result<(), ExportError> {
+            self.0.lock().unwrap().push(data_point.clone());
+            Ok(())
+        }
+    }
+
+    /// Parameters of a test run flushing on every sample.
+    fn parameters(
+        measures: Measures,
+        threads: usize,
+        termination: Termination,
+        exporter: &Collector,
+    ) -> Parameters {
+        Parameters {
+            name: "Walk".to_string(),
+            host: "node1".to_string(),
+            seed: 1,
+            measures,
+            exporter: Box::new(exporter.clone()),
+            flush_interval: Duration::from_secs(0),
+            max_export_errors_in_row: None,
+            threads,
+            termination,
+            stop: Arc::new(AtomicBool::new(false)),
+            final_export_deadline: Duration::from_secs(1),
+            checkpoint_path: None,
+            checkpoint_interval: Duration::from_secs(600),
+            resume: None,
+            params: BTreeMap::new(),
+        }
+    }
+
+    /// Registers the measure `One`, which is always 1, and `Value`.
+    fn measures() -> (Measures, MeasureIdx, MeasureIdx) {
+        let mut reg = MeasureRegistry::new();
+        let one = reg.register("One".to_string());
+        let value = reg.register("Value".to_string());
+        (reg.freeze(), one, value)
+    }
+
+    #[test]
+    fn merged_chains_match_one_chain() {
+        let mut reg = MeasureRegistry::new();
+        let x = reg.register("X".to_string());
+        let mut one_chain = reg.freeze();
+        let mut chains = vec![one_chain.clone(); 4];
+        for i in 0..1000 {
+            let value = (i as f64 * 0.37).sin();
+            one_chain.accumulate(x, value);
+            // The last chain stays empty.
+            chains[i % 3].accumulate(x, value);
+        }
+
+        // Flushing merges the chains into the measures of the first one.
+        let (first, rest) = chains.split_at_mut(1);
+        for measures in rest.iter_mut() {
+            first[0].merge(measures).unwrap();
+            measures.reset();
+        }
+        let (merged, total) = (&first[0].get(x).acc, &one_chain.get(x).acc);
+        assert_eq!(merged.num_of_samples(), 1000.0);
+        assert!((merged.value() - total.value()).abs() < 1e-12);
+        assert!((merged.uncertainty() - total.uncertainty()).abs() < 1e-12);
+        assert_eq!(rest[0].get(x).acc.num_of_samples(), 0.0);
+    }
+
+    #[test]
+    fn merges_chains_into_the_totals_of_one_chain() {
+        for &threads in &[1, 4] {
+            let (measures, one, value) = measures();
+            let exporter = Collector::default();
+            let termination = Termination {
+                max_samples: Some(1000),
+                ..Termination::default()
+            };
+            let parameters = parameters(measures, threads, termination, &exporter);
+            let result = run::<Walk, _>(parameters, None, |s, ms| {
+                ms.accumulate(one, 1.0);
+                ms.accumulate(value, s.value);
+            })
+            .unwrap();
+
+            // Every sample is counted exactly once, whichever chain drew it.
+            let totals = &result.measures;
+            assert_eq!(totals.get(one).acc.num_of_samples(), 1000.0);
+            assert_eq!(totals.get(one).acc.value(), 1.0);
+            assert_eq!(totals.get(value).acc.num_of_samples(), 1000.0);
+
+            // The totals are the merge of the exported data points.
+            let mut merged = totals.clone();
+            merged.reset();
+            for data_point in exporter.0.lock().unwrap().iter() {
+                merged.merge(&data_point.measures).unwrap();
+            }
+            assert_eq!(merged.get(one).acc.num_of_samples(), 1000.0);
+            let (merged, total) = (&merged.get(value).acc, &totals.get(value).acc);
+            assert!((merged.value() - total.value()).abs() < 1e-12);
+            assert!((merged.uncertainty() - total.uncertainty()).abs() < 1e-12);
+        }
+    }
+
+    /// Runs the walk measuring `Value` and `Zero` until `termination`. Returns
+    /// the number of samples and the measures.
+    fn run_until(termination: Termination) -> Result<(f64, Measures), Error> {
+        let mut reg = MeasureRegistry::new();
+        let value = reg.register("Value".to_string());
+        let zero = reg.register("Zero".to_string());
+        let parameters = parameters(reg.freeze(), 2, termination, &Collector::default());
+        let result = run::<Walk, _>(parameters, None, |s, ms| {
+            ms.accumulate(value, s.value);
+            ms.accumulate(zero, 0.0);
+        })?;
+        let samples = result.measures.get(value).acc.num_of_samples();
+        Ok((samples, result.measures))
+    }
+
+    #[test]
+    fn stops_after_max_samples() {
+        let (samples, _) = run_until(Termination {
+            max_samples: Some(500),
+            ..Termination::default()
+        })
+        .unwrap();
+        assert_eq!(samples, 500.0);
+    }
+
+    #[test]
+    fn stops_after_max_time() {
+        let start = Instant::now();
+        let (samples, _) = run_until(Termination {
+            max_time: Some(Duration::from_millis(50)),
+            ..Termination::default()
+        })
+        .unwrap();
+        assert!(samples > 0.0);
+        let elapsed = start.elapsed();
+        assert!(elapsed >= Duration::from_millis(50), "{:?}", elapsed);
+        assert!(elapsed < Duration::from_secs(10), "{:?}", elapsed);
+    }
+
+    #[test]
+    fn stops_at_target_uncertainty() {
+        let (samples, measures) = run_until(Termination {
+            max_samples: Some(1_000_000),
+            target_relative_uncertainty: Some(0.05),
+            target_measures: vec!["Value".to_string()],
+            ..Termination::default()
+        })
+        .unwrap();
+        assert!(samples < 1_000_000.0, "{}", samples);
+        let acc = &measures.slice()[0].acc;
+        assert!(acc.binned_uncertainty() / acc.value() < 0.05);
+    }
+
+    #[test]
+    fn zero_expectation_values_never_reach_target() {
+        for target_measures in [vec![], vec!["Zero".to_string()]] {
+            let (samples, _) = run_until(Termination {
+                max_samples: Some(500),
+                target_relative_uncertainty: Some(0.5),
+                target_measures,
+                ..Termination::default()
+            })
+            .unwrap();
+            assert_eq!(samples, 500.0);
+        }
+    }
+
+    #[test]
+    fn target_not_reached_without_scalar_measures() {
+        let mut reg = MeasureRegistry::new();
+        let steps = reg.register_counter("Steps".to_string());
+        let termination = Termination {
+            max_samples: Some(500),
+            target_relative_uncertainty: Some(0.5),
+            ..Termination::default()
+        };
+        let parameters = parameters(reg.freeze(), 1, termination, &Collector::default());
+        let result = run::<Walk, _>(parameters, None, |_, ms| {
+            ms.accumulate_count(steps, 1);
+        })
+        .unwrap();
+        assert_eq!(result.measures.get_counter(steps).acc.total(), 500);
+    }
+
+    #[test]
+    fn rejects_unknown_target_measures() {
+        match run_until(Termination {
+            target_relative_uncertainty: Some(0.5),
+            target_measures: vec!["Vlaue".to_string()],
+            ..Termination::default()
+        }) {
+            Err(Error::Config(err)) => assert!(err.contains("'Vlaue'"), "{}", err),
+            Err(err) => panic!("Unexpected error: {}", err),
+            Ok(_) => panic!("Ran with an unknown target measure."),
+        }
+    }
+
+    /// Fails the first `failures` exports, and keeps the data points of the
+    /// successful ones.
+    struct Flaky {
+        failures: usize,
+        collector: Collector,
+    }
+
+    impl Exporter for Flaky {
+        fn export(&mut self, data_point: &DataPoint) -> Result<(), ExportError> {
+            if self.failures > 0 {
+                self.failures -= 1;
+                return Err(ExportError("Unavailable".to_string()));
             }
+            self.collector.export(data_point)
+        }
+    }
+
+    /// Runs the walk on 2 threads without periodic flushes until the stop flag
+    /// is raised, exporting to `exporter`.
+    fn run_until_stopped(exporter: Flaky) -> Result<RunResult, Error> {
+        let (measures, one, _) = measures();
+        let mut parameters = parameters(measures, 2, Termination::default(), &exporter.collector);
+        parameters.exporter = Box::new(exporter);
+        parameters.flush_interval = Duration::from_secs(3600);
+        let stop = parameters.stop.clone();
+        ::std::thread::spawn(move || {
+            ::std::thread::sleep(Duration::from_millis(20));
+            stop.store(true, Ordering::Relaxed);
+        });
+        run::<Walk, _>(parameters, None, |_, ms| ms.accumulate(one, 1.0))
+    }
+
+    #[test]
+    fn exports_final_data_point_when_stopped() {
+        let collector = Collector::default();
+        let result = run_until_stopped(Flaky {
+            failures: 2,
+            collector: collector.clone(),
+        })
+        .unwrap();
+        // The final export is retried until it succeeds.
+        let data_points = collector.0.lock().unwrap();
+        assert_eq!(data_points.len(), 1);
+        let samples = result.measures.slice()[0].acc.num_of_samples();
+        assert!(samples > 0.0);
+        assert_eq!(
+            data_points[0].measures.slice()[0].acc.num_of_samples(),
+            samples
+        );
+    }
+
+    #[test]
+    fn fails_when_final_export_misses_deadline() {
+        match run_until_stopped(Flaky {
+            failures: usize::MAX,
+            collector: Collector::default(),
+        }) {
+            Err(Error::Export(ExportError(err))) => assert_eq!(err, "Unavailable"),
+            Err(err) => panic!("Unexpected error: {}", err),
+            Ok(_) => panic!("The final export has succeeded."),
         }
     }
+
+    /// A random walk, counting its steps.
+    #[derive(Serialize, Deserialize)]
+    struct Walker {
+        x: i64,
+        steps: u64,
+
+        /// Whether the walker has been prepared in this run, rather than
+        /// restored from a checkpoint.
+        #[serde(skip)]
+        prepared: bool,
+    }
+
+    impl Sample for Walker {
+        type Params = NoParams;
+
+        fn prepare<R: Rng>(_params: &NoParams, _rng: &mut R) -> Walker {
+            Walker {
+                x: 0,
+                steps: 0,
+                prepared: true,
+            }
+        }
+
+        fn mutate<R: Rng>(&mut self, rng: &mut R) {
+            self.x += if rng.gen::<bool>() { 1 } else { -1 };
+            self.steps += 1;
+        }
+    }
+
+    impl ::checkpoint::Checkpointable for Walker {}
+
+    /// Path of a checkpoint file of a test.
+    fn checkpoint_path(name: &str) -> PathBuf {
+        let path = ::std::env::temp_dir().join(format!(
+            "ergothic-checkpoint-{}-{}.json",
+            name,
+            ::std::process::id()
+        ));
+        let _ = ::std::fs::remove_file(&path);
+        path
+    }
+
+    /// Runs the walk for `max_samples` samples on `threads` chains, saving
+    /// checkpoints to `path` and resuming from `resume`. Returns the number of
+    /// samples drawn by walkers prepared in this run, the number of samples
+    /// drawn by restored walkers, and the smallest number of steps of a
+    /// restored walker.
+    fn walk(
+        max_samples: u64,
+        threads: usize,
+        path: &Path,
+        resume: Option<Checkpoint>,
+    ) -> (f64, f64, f64) {
+        let mut reg = MeasureRegistry::new();
+        let prepared = reg.register("Prepared".to_string());
+        let restored = reg.register("Restored".to_string());
+        let termination = Termination {
+            max_samples: Some(max_samples),
+            ..Termination::default()
+        };
+        let mut parameters = parameters(reg.freeze(), threads, termination, &Collector::default());
+        parameters.checkpoint_path = Some(path.to_path_buf());
+        parameters.resume = resume;
+        let min_steps = Mutex::new(f64::INFINITY);
+        let result = run::<Walker, _>(parameters, Some(Codec::new()), |s, ms| {
+            if s.prepared {
+                ms.accumulate(prepared, 1.0);
+            } else {
+                ms.accumulate(restored, 1.0);
+                let mut min_steps = min_steps.lock().unwrap();
+                *min_steps = min_steps.min(s.steps as f64);
+            }
+        })
+        .unwrap();
+        (
+            result.measures.get(prepared).acc.num_of_samples(),
+            result.measures.get(restored).acc.num_of_samples(),
+            min_steps.into_inner().unwrap(),
+        )
+    }
+
+    #[test]
+    fn resumes_from_checkpoint() {
+        let path = checkpoint_path("resume");
+        let (prepared, restored, _) = walk(1000, 2, &path, None);
+        assert_eq!((prepared, restored), (1000.0, 0.0));
+
+        let checkpoint = Checkpoint::load(&path).unwrap();
+        assert_eq!(checkpoint.simulation, "Walk");
+        assert_eq!(checkpoint.samples.len(), 2);
+        let saved_steps: u64 = checkpoint
+            .samples
+            .iter()
+            .map(|s| s["steps"].as_u64().unwrap())
+            .sum();
+        // Each walker has also been thermalized.
+        assert!(saved_steps >= 1000, "{}", saved_steps);
+
+        let (prepared, restored, min_steps) = walk(1000, 2, &path, Some(checkpoint));
+        assert_eq!((prepared, restored), (0.0, 1000.0));
+        assert!(min_steps > 1.0, "{}", min_steps);
+    }
+
+    #[test]
+    fn prepares_chains_missing_from_checkpoint() {
+        let path = checkpoint_path("null");
+        walk(100, 1, &path, None);
+
+        // A chain that hadn't finished thermalizing is saved as null.
+        let mut checkpoint = Checkpoint::load(&path).unwrap();
+        checkpoint.samples = vec![Value::Null];
+        let (prepared, restored, _) = walk(100, 1, &path, Some(checkpoint));
+        assert_eq!((prepared, restored), (100.0, 0.0));
+    }
+
+    #[test]
+    fn continues_random_numbers_of_chains() {
+        // Reads the walker saved in a checkpoint.
+        let saved_walker = |path: &Path| {
+            let checkpoint = Checkpoint::load(path).unwrap();
+            assert!(checkpoint.rngs[0].is_some());
+            assert!(checkpoint.seeder.is_some());
+            checkpoint.samples[0].clone()
+        };
+
+        let path = checkpoint_path("straight");
+        walk(2000, 1, &path, None);
+        let straight = saved_walker(&path);
+
+        let path = checkpoint_path("continued");
+        walk(1000, 1, &path, None);
+        saved_walker(&path);
+        walk(1000, 1, &path, Some(Checkpoint::load(&path).unwrap()));
+        let continued = saved_walker(&path);
+
+        // Resumed with the same seed, the walk doesn't replay the first half,
+        // but ends where the uninterrupted walk does.
+        assert_eq!(continued, straight);
+    }
 }
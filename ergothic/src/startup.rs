@@ -1,18 +1,60 @@
+use checkpoint::Checkpoint;
+use checkpoint::Codec;
+use config::ConfigFile;
+use error::Error;
 use export::Exporter;
+use export::ForwardingExporter;
 use measure::MeasureRegistry;
 use measure::Measures;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::Value;
 use simulation::Parameters;
+use simulation::RunResult;
+use simulation::Termination;
+use std::any::Any;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::atomic::AtomicBool;
+use std::sync::Arc;
+use std::time::Duration;
 use structopt::StructOpt;
 
+/// Exit code of a simulation which couldn't start because of an invalid
+/// configuration.
+pub const EXIT_CODE_CONFIG_ERROR: i32 = 2;
+
+/// Exit code of a simulation which has terminated, but failed to export the
+/// remaining measured values.
+pub const EXIT_CODE_FINAL_EXPORT_FAILED: i32 = 3;
+
+/// Exit code of a simulation which failed to save or restore a sample.
+pub const EXIT_CODE_SAMPLE_ERROR: i32 = 4;
+
+/// Exit code of a simulation a grid point of which has panicked, the same as of
+/// an uncaught panic.
+pub const EXIT_CODE_PANIC: i32 = 101;
+
 #[derive(Debug, StructOpt)]
 #[structopt(
     name = "ergothic simulation",
     about = "A distributed statistical simulation using ergothic library."
 )]
 pub struct CmdArgs {
+    /// Configuration file in the TOML or YAML format. Its keys are named after
+    /// the arguments, e.g. `threads = 64`, and the physics parameters go into
+    /// the `params` table. Arguments given on the command line override the
+    /// values from the file.
+    /// Example: --config /shared/ergothic/my_simulation.toml
+    #[structopt(long = "config")]
+    pub config: Option<String>,
+
     /// Run in production mode. Exactly one exporter spec should be provided.
+    /// `--production false` turns off the production mode set in the
+    /// configuration file.
+    /// Example: --production
     #[structopt(long = "production")]
-    pub production_mode: bool,
+    pub production: Option<Option<bool>>,
 
     /// MongoDB connection string to export measurements to. Child arguments:
     /// [--mongo_db, --mongo_coll].
@@ -30,6 +72,18 @@ pub struct CmdArgs {
     #[structopt(long = "mongo_coll")]
     pub mongo_coll: Option<String>,
 
+    /// File to append measurements to, one JSON object per line. Child
+    /// arguments: [--output_file_max_mb].
+    /// Example: --output_file /shared/ergothic/my_simulation.jsonl
+    #[structopt(long = "output_file")]
+    pub output_file: Option<String>,
+
+    /// Rotate the output file when it grows beyond this many megabytes. Parent
+    /// argument: --output_file.
+    /// Example: --output_file_max_mb 100
+    #[structopt(long = "output_file_max_mb")]
+    pub output_file_max_mb: Option<u64>,
+
     /// Flush interval for measurements in seconds.
     /// Example: --flush_interval_secs 600 (flush every 10 minutes).
     #[structopt(long = "flush_interval_secs")]
@@ -39,46 +93,453 @@ pub struct CmdArgs {
     /// [0, 1). The real interval will be chosen at random within this fraction of
     /// the --flush_interval_secs. The purpose is to avoid contention in the
     /// storage service coming from all nodes reporting data points at the same
-    /// time. Has effect only in production mode.
+    /// time. Has effect only in production mode. Defaults to 0.5.
     /// Example: --flush_interval_randomization 0.2 (randomize within 20%).
-    #[structopt(long = "flush_interval_randomization", default_value = "0.5")]
-    pub flush_interval_randomization: f64,
+    #[structopt(long = "flush_interval_randomization")]
+    pub flush_interval_randomization: Option<f64>,
+
+    /// Number of independent Markov chains to run, each in its own thread.
+    /// Chains never communicate with each other, their accumulated values are
+    /// merged before exporting. Defaults to 1.
+    /// Example: --threads 64
+    #[structopt(long = "threads")]
+    pub threads: Option<usize>,
+
+    /// Seed of the random number generators, below 2^63. Each thread derives the
+    /// seed of its chain from it. By default, a seed is derived from the host
+    /// name and the current time. Nodes with a --node_index other than 0 mix
+    /// their index into the seed, so that the nodes of a sweep given the same
+    /// seed run different chains. The seed of the node is recorded in every
+    /// exported data point, so that any run can be reproduced. Chains resumed
+    /// from a checkpoint continue the random number streams saved in it.
+    /// Example: --seed 42
+    #[structopt(long = "seed")]
+    pub seed: Option<u64>,
+
+    /// Stop the simulation after drawing this many samples in total across all
+    /// threads. Overrides the value set in code.
+    /// Example: --max_samples 1000000000
+    #[structopt(long = "max_samples")]
+    pub max_samples: Option<u64>,
 
-    /// Simulation will panic after receiving this many export errors in a row.
-    /// Default value is infinity.
+    /// Stop the simulation after running for this many seconds. Overrides the
+    /// value set in code.
+    /// Example: --max_time_secs 86400 (run for a day).
+    #[structopt(long = "max_time_secs")]
+    pub max_time_secs: Option<u64>,
+
+    /// Stop the simulation when the relative uncertainty of all target
+    /// measures drops below this value. Overrides the value set in code.
+    /// Child arguments: [--target_measure].
+    /// Example: --target_relative_uncertainty 0.001
+    #[structopt(long = "target_relative_uncertainty")]
+    pub target_relative_uncertainty: Option<f64>,
+
+    /// Name of a measure checked against --target_relative_uncertainty. Can be
+    /// given multiple times. By default, all measures are checked. Overrides
+    /// the measures set in code. Parent argument: --target_relative_uncertainty.
+    /// Example: --target_measure "Mean X"
+    #[structopt(long = "target_measure")]
+    pub target_measures: Vec<String>,
+
+    /// Periodically save the samples of all chains to this file, so that the
+    /// simulation can be resumed later with --resume. Child arguments:
+    /// [--checkpoint_interval_secs]. Requires a checkpointable sample.
+    /// Example: --checkpoint /shared/ergothic/node1.checkpoint.json
+    #[structopt(long = "checkpoint")]
+    pub checkpoint: Option<String>,
+
+    /// Minimum interval between checkpoints in seconds. Defaults to 600.
+    /// Parent argument: --checkpoint.
+    /// Example: --checkpoint_interval_secs 3600 (save every hour).
+    #[structopt(long = "checkpoint_interval_secs")]
+    pub checkpoint_interval_secs: Option<u64>,
+
+    /// Continue the simulation from a checkpoint written with --checkpoint,
+    /// skipping the preparation and thermalization of samples. Requires a
+    /// checkpointable sample.
+    /// Example: --resume /shared/ergothic/node1.checkpoint.json
+    #[structopt(long = "resume")]
+    pub resume: Option<String>,
+
+    /// On termination (including SIGTERM, SIGINT and SIGQUIT), keep retrying the final
+    /// export for this many seconds. Defaults to 20.
+    /// Example: --final_export_deadline_secs 20
+    #[structopt(long = "final_export_deadline_secs")]
+    pub final_export_deadline_secs: Option<u64>,
+
+    /// Simulation will stop with an error after receiving this many export
+    /// errors in a row. Default value is infinity.
     #[structopt(long = "max_errors_in_row")]
     pub max_export_errors_in_row: Option<usize>,
+
+    /// Physics parameter of the simulation, given as `name=value`. The value
+    /// is parsed as JSON if possible, e.g. a number or an array, and taken as a
+    /// string otherwise. Can be given multiple times. Overrides the parameter
+    /// from the environment variable `ERGOTHIC_PARAM_<name>`, which in turn
+    /// overrides the `params` table of the configuration file.
+    /// Example: --param coupling=0.25
+    #[structopt(long = "param")]
+    pub params: Vec<String>,
+
+    /// Physics parameter swept over a grid of values, given as
+    /// `name=[value, ...]`. Can be given multiple times, the grid being the
+    /// cartesian product of the values. The chains of all nodes take the grid
+    /// points round-robin, continuing from the chains of the nodes with lower
+    /// --node_index, and every data point records the parameters of its grid
+    /// point. Overrides the `sweep` table of the configuration file, and the
+    /// parameter given with --param.
+    /// Example: --sweep 'beta=[5.6, 5.7, 5.8]'
+    #[structopt(long = "sweep")]
+    pub sweep: Vec<String>,
+
+    /// Index of the node among the nodes running a sweep, starting from 0.
+    /// With as many nodes as grid points and a single thread each, every node
+    /// runs a grid point of its own. Defaults to the JOB_COMPLETION_INDEX
+    /// environment variable set by Kubernetes indexed jobs, or to 0.
+    /// Example: --node_index 7
+    #[structopt(long = "node_index")]
+    pub node_index: Option<usize>,
 }
 
-/// Parses the command line arguments and produces simulation parameters.
-pub fn construct_parameters(name: String, measures: Measures, mut args: CmdArgs) -> Parameters {
-    let mut rng = ::rand::thread_rng();
-    use rand::distributions::Distribution;
-    let exporter: Box<dyn Exporter>;
-    if args.production_mode {
-        if cfg!(debug_assertions) {
-            panic!("Please build an optimized binary.");
+/// Prefix of the names of environment variables setting the physics parameters
+/// of the simulation, e.g. `ERGOTHIC_PARAM_coupling=0.25`.
+const PARAM_ENV_PREFIX: &str = "ERGOTHIC_PARAM_";
+
+/// Environment variable holding the index of the node, set by Kubernetes
+/// indexed jobs.
+const NODE_INDEX_ENV: &str = "JOB_COMPLETION_INDEX";
+
+impl CmdArgs {
+    /// Fills in the arguments missing from the command line with the values
+    /// from the configuration file given with `--config`, if any. Returns the
+    /// physics parameters and the swept parameters from the file.
+    pub fn apply_config_file(&mut self) -> Result<(BTreeMap<String, Value>, Sweep), Error> {
+        let file = match self.config.take() {
+            Some(path) => ConfigFile::load(path)?,
+            None => return Ok((BTreeMap::new(), BTreeMap::new())),
+        };
+        if self.production.is_none() {
+            self.production = file.production.map(Some);
         }
-        if let Some(_mongo) = args.mongo {
-            // let mongo_db = args
-            //     .mongo_db
-            //     .expect("Child argument --mongo_db is required.");
-            // let mongo_coll = args
-            //     .mongo_coll
-            //     .expect("Child argument --mongo_coll is required.");
-            exporter = Box::new(::export::DebugExporter::new());
-        } else {
-            panic!("Argument --mongo is required in production mode.");
+        // An exporter given on the command line replaces the exporter of the
+        // file as a whole, rather than being mixed with it.
+        let cmd_exporter = self.mongo.is_some()
+            || self.mongo_db.is_some()
+            || self.mongo_coll.is_some()
+            || self.output_file.is_some()
+            || self.output_file_max_mb.is_some();
+        if !cmd_exporter {
+            self.mongo = file.mongo;
+            self.mongo_db = file.mongo_db;
+            self.mongo_coll = file.mongo_coll;
+            self.output_file = file.output_file;
+            self.output_file_max_mb = file.output_file_max_mb;
+        }
+        self.flush_interval_secs = self.flush_interval_secs.or(file.flush_interval_secs);
+        self.flush_interval_randomization = self
+            .flush_interval_randomization
+            .or(file.flush_interval_randomization);
+        self.threads = self.threads.or(file.threads);
+        self.seed = self.seed.or(file.seed);
+        self.max_samples = self.max_samples.or(file.max_samples);
+        self.max_time_secs = self.max_time_secs.or(file.max_time_secs);
+        self.target_relative_uncertainty = self
+            .target_relative_uncertainty
+            .or(file.target_relative_uncertainty);
+        if self.target_measures.is_empty() {
+            self.target_measures = file.target_measure;
+        }
+        self.checkpoint = self.checkpoint.take().or(file.checkpoint);
+        self.checkpoint_interval_secs = self
+            .checkpoint_interval_secs
+            .or(file.checkpoint_interval_secs);
+        self.resume = self.resume.take().or(file.resume);
+        self.final_export_deadline_secs = self
+            .final_export_deadline_secs
+            .or(file.final_export_deadline_secs);
+        self.max_export_errors_in_row = self.max_export_errors_in_row.or(file.max_errors_in_row);
+        self.node_index = self.node_index.or(file.node_index);
+        Ok((file.params, file.sweep))
+    }
+
+    /// Tells whether to run in production mode. A bare `--production` turns it
+    /// on.
+    pub fn production_mode(&self) -> bool {
+        self.production
+            .is_some_and(|production| production.unwrap_or(true))
+    }
+
+    /// Collects the physics parameters from the configuration file, the
+    /// environment and the command line, in the order of increasing priority.
+    fn collect_params(
+        &self,
+        mut params: BTreeMap<String, Value>,
+    ) -> Result<BTreeMap<String, Value>, Error> {
+        for (key, value) in ::std::env::vars() {
+            if let Some(name) = key.strip_prefix(PARAM_ENV_PREFIX) {
+                params.insert(name.to_string(), parse_param_value(&value));
+            }
         }
+        for param in self.params.iter() {
+            match param.split_once('=') {
+                Some((name, value)) if !name.trim().is_empty() => {
+                    params.insert(name.trim().to_string(), parse_param_value(value.trim()));
+                }
+                _ => {
+                    return Err(Error::Config(format!(
+                        "Invalid argument --param {}, expected 'name=value'.",
+                        param
+                    )))
+                }
+            }
+        }
+        Ok(params)
+    }
+
+    /// Collects the swept parameters from the configuration file and the
+    /// command line, the latter taking priority.
+    fn collect_sweep(&self, mut sweep: Sweep) -> Result<Sweep, Error> {
+        for axis in self.sweep.iter() {
+            let invalid = || {
+                Error::Config(format!(
+                    "Invalid argument --sweep {}, expected 'name=[value, ...]'.",
+                    axis
+                ))
+            };
+            let (name, values) = axis.split_once('=').ok_or_else(invalid)?;
+            if name.trim().is_empty() {
+                return Err(invalid());
+            }
+            let values = ::serde_json::from_str(values.trim()).map_err(|_| invalid())?;
+            sweep.insert(name.trim().to_string(), values);
+        }
+        Ok(sweep)
+    }
+
+    /// Gives the index of the node given with `--node_index`, or set in the
+    /// environment by Kubernetes indexed jobs.
+    fn node_index(&self) -> Result<usize, Error> {
+        if let Some(node_index) = self.node_index {
+            return Ok(node_index);
+        }
+        match ::std::env::var(NODE_INDEX_ENV) {
+            Ok(value) => value.trim().parse().map_err(|_| {
+                Error::Config(format!(
+                    "Invalid node index {}={}, expected a non-negative integer.",
+                    NODE_INDEX_ENV, value
+                ))
+            }),
+            Err(_) => Ok(0),
+        }
+    }
+}
+
+/// Values of the swept parameters, by the name of the parameter.
+type Sweep = BTreeMap<String, Vec<Value>>;
+
+/// Values of the physics parameters, by the name of the parameter.
+type Params = BTreeMap<String, Value>;
+
+/// Parses the value of a parameter given as a string: as JSON if possible, or
+/// as a plain string otherwise.
+fn parse_param_value(value: &str) -> Value {
+    ::serde_json::from_str(value).unwrap_or_else(|_| Value::String(value.to_string()))
+}
+
+/// Parses the command line arguments and the configuration file given with
+/// `--config`. Returns the arguments, the physics parameters and the swept
+/// parameters.
+fn parse_args() -> Result<(CmdArgs, BTreeMap<String, Value>, Sweep), Error> {
+    let mut args = CmdArgs::from_args();
+    let (file_params, file_sweep) = args.apply_config_file()?;
+    let params = args.collect_params(file_params)?;
+    let sweep = args.collect_sweep(file_sweep)?;
+    Ok((args, params, sweep))
+}
+
+/// Resolves the physics parameters given on the command line, in the
+/// configuration file and in the environment into `P`, through the same
+/// configuration as `run_simulation`, see `config_params`. If the parameters
+/// are invalid, prints the error and exits the process.
+pub fn cmd_params<P: Serialize + DeserializeOwned>() -> Vec<P> {
+    let resolved = parse_args().and_then(|(args, params, sweep)| {
+        let config = construct_config(&mut Termination::default(), args, params, sweep)?;
+        config_params(&config)
+    });
+    match resolved {
+        Ok(params) => params,
+        Err(err) => exit_with_error(err),
+    }
+}
+
+/// Resolves the physics parameters of every grid point of the sweep run by the
+/// node configured by `config` into `P`, in the order of their first chains.
+pub fn config_params<P: Serialize + DeserializeOwned>(config: &RunConfig) -> Result<Vec<P>, Error> {
+    grid_assignment(config)?
+        .into_iter()
+        .map(|(_, params)| ::simulation::resolve_params(params).map(|(params, _)| params))
+        .collect()
+}
+
+/// Gives the grid points of the sweep run by the node configured by `config`,
+/// in the order of their first chains, as the number of chains running each of
+/// them and the physics parameters at the point. Without a sweep, gives the
+/// single point of the parameter space.
+fn grid_assignment(config: &RunConfig) -> Result<Vec<(usize, Params)>, Error> {
+    let points = ::sweep::grid_points(&config.sweep)?;
+    let groups = ::sweep::assign(points.len(), config.node_index, config.threads.max(1));
+    Ok(groups
+        .into_iter()
+        .map(|(point, threads)| {
+            let mut params = config.params.clone();
+            params.extend(points[point].clone());
+            (threads, params)
+        })
+        .collect())
+}
+
+/// Programmatic configuration of a simulation run, see `Simulation::run_with`.
+/// The defaults match running the simulation binary without arguments, except
+/// that nothing is printed and no signal handlers are installed.
+#[derive(Clone, Debug)]
+pub struct RunConfig {
+    /// Interval between subsequent flushes of the accumulated values to the
+    /// exporter. Defaults to 2 seconds.
+    pub flush_interval: Duration,
+
+    /// Number of independent Markov chains, each running in its own thread.
+    /// Defaults to 1.
+    pub threads: usize,
+
+    /// Seed of the random number generators, below 2^63. By default, a seed is
+    /// derived from the host name and the current time. A `node_index` other
+    /// than 0 is mixed into the seed.
+    pub seed: Option<u64>,
+
+    /// Identifies the node in the exported data points. Defaults to the host
+    /// name.
+    pub host: Option<String>,
+
+    /// Stop the simulation with `Error::Export` after this many export errors
+    /// in a row. By default, export errors are only logged.
+    pub max_export_errors_in_row: Option<usize>,
+
+    /// On termination, the final export is retried until this deadline
+    /// expires. Defaults to 20 seconds.
+    pub final_export_deadline: Duration,
+
+    /// If set, the samples of all chains are periodically saved to this file.
+    /// Requires `Simulation::run_checkpointable_with`.
+    pub checkpoint_path: Option<PathBuf>,
+
+    /// Minimum interval between subsequent checkpoints. Defaults to 10
+    /// minutes.
+    pub checkpoint_interval: Duration,
+
+    /// If set, the simulation continues from the checkpoint saved to this
+    /// file. Requires `Simulation::run_checkpointable_with`.
+    pub resume: Option<PathBuf>,
+
+    /// Setting this flag from any thread stops the simulation gracefully, as if
+    /// a termination condition was met.
+    pub stop: Arc<AtomicBool>,
+
+    /// Physics parameters of the simulation, e.g. the coupling, deserialized
+    /// into `Sample::Params`.
+    pub params: BTreeMap<String, Value>,
+
+    /// Physics parameters swept over grids of values, overriding `params`.
+    /// Chain `c` of the node runs the grid point
+    /// `(node_index * threads + c) % num_of_points`, where the grid points are
+    /// the cartesian product of the values, the parameters taken in the
+    /// alphabetical order. The chains running the same grid point are run as a
+    /// simulation of their own, with the seed increased by the position of the
+    /// grid point on the node, and with the termination conditions applying to
+    /// each grid point separately. Checkpoints are only supported if all chains
+    /// of the node run the same grid point. Empty by default.
+    pub sweep: BTreeMap<String, Vec<Value>>,
+
+    /// Index of the node among the nodes running a sweep. Defaults to 0.
+    pub node_index: usize,
+}
+
+impl Default for RunConfig {
+    fn default() -> RunConfig {
+        RunConfig {
+            flush_interval: Duration::from_secs(2),
+            threads: 1,
+            seed: None,
+            host: None,
+            max_export_errors_in_row: None,
+            final_export_deadline: Duration::from_secs(20),
+            checkpoint_path: None,
+            checkpoint_interval: Duration::from_secs(600),
+            resume: None,
+            stop: Arc::new(AtomicBool::new(false)),
+            params: BTreeMap::new(),
+            sweep: BTreeMap::new(),
+            node_index: 0,
+        }
+    }
+}
+
+/// Validates the exporter arguments and constructs the exporter of the run.
+pub fn construct_exporter(args: &CmdArgs) -> Result<Box<dyn Exporter>, Error> {
+    let config_error = |err: &str| Err(Error::Config(err.to_string()));
+    if !args.production_mode() {
+        return Ok(Box::new(::export::DebugExporter::new()));
+    }
+    if cfg!(debug_assertions) {
+        return config_error(
+            "Production mode requires an optimized binary, build it with --release.",
+        );
+    }
+    if args.mongo.is_some() && args.output_file.is_some() {
+        return config_error("Arguments --mongo and --output_file are mutually exclusive.");
+    }
+    if let Some(ref mongo) = args.mongo {
+        let mongo_db = match args.mongo_db {
+            Some(ref mongo_db) => mongo_db,
+            None => return config_error("Child argument --mongo_db is required."),
+        };
+        let mongo_coll = match args.mongo_coll {
+            Some(ref mongo_coll) => mongo_coll,
+            None => return config_error("Child argument --mongo_coll is required."),
+        };
+        let exporter = ::export::MongoExporter::new(mongo, mongo_db, mongo_coll)
+            .map_err(|err| Error::Config(format!("Invalid argument --mongo: {}", err.0)))?;
+        Ok(Box::new(exporter))
+    } else if let Some(ref output_file) = args.output_file {
+        let max_len = args.output_file_max_mb.map(|mb| mb * 1024 * 1024);
+        let exporter = ::export::FileExporter::new(output_file, max_len)
+            .map_err(|err| Error::Config(format!("Invalid argument --output_file: {}", err.0)))?;
+        Ok(Box::new(exporter))
     } else {
-        exporter = Box::new(::export::DebugExporter::new());
+        config_error("Argument --mongo or --output_file is required in production mode.")
     }
+}
+
+/// Validates the command line arguments and produces the configuration of the
+/// run with the physics `params` and the swept parameters. Arguments overriding
+/// the termination conditions are applied to `termination`.
+pub fn construct_config(
+    termination: &mut Termination,
+    args: CmdArgs,
+    params: BTreeMap<String, Value>,
+    sweep: Sweep,
+) -> Result<RunConfig, Error> {
+    let mut rng = ::rand::thread_rng();
+    use rand::distributions::Distribution;
+    let node_index = args.node_index()?;
+    let production_mode = args.production_mode();
+    let config_error = |err: &str| Err(Error::Config(err.to_string()));
 
     let flush_interval_secs;
     if let Some(flush_interval_secs_some) = args.flush_interval_secs {
         flush_interval_secs = flush_interval_secs_some;
     } else {
-        if args.production_mode {
+        if production_mode {
             // Default value for production is to flush every 5 minutes.
             flush_interval_secs = 300;
         } else {
@@ -87,48 +548,472 @@ pub fn construct_parameters(name: String, measures: Measures, mut args: CmdArgs)
         }
     }
 
-    if args.flush_interval_randomization < 0.0 || args.flush_interval_randomization >= 1.0 {
-        panic!("Argument --path_integral_randomization should lie within [0, 1).");
+    let mut flush_interval_randomization = args.flush_interval_randomization.unwrap_or(0.5);
+    if !(0.0..1.0).contains(&flush_interval_randomization) {
+        return config_error("Argument --flush_interval_randomization should lie within [0, 1).");
     }
 
-    if !args.production_mode {
-        args.flush_interval_randomization = 0.0;
+    if !production_mode {
+        flush_interval_randomization = 0.0;
     }
 
     let flush_interval_min = ::std::cmp::max(
         1,
-        (flush_interval_secs as f64 * (1.0 - args.flush_interval_randomization)).round() as u64,
+        (flush_interval_secs as f64 * (1.0 - flush_interval_randomization)).round() as u64,
     );
     let flush_interval_max =
-        (flush_interval_secs as f64 * (1.0 + args.flush_interval_randomization)).round() as u64;
+        (flush_interval_secs as f64 * (1.0 + flush_interval_randomization)).round() as u64;
     let flush_interval_dist = ::rand::distributions::Uniform::<u64>::new_inclusive(
         flush_interval_min,
         flush_interval_max,
     );
-    let flush_interval = ::std::time::Duration::from_secs(flush_interval_dist.sample(&mut rng));
+    let flush_interval = Duration::from_secs(flush_interval_dist.sample(&mut rng));
 
-    let max_export_errors_in_row = args.max_export_errors_in_row;
+    if args.max_samples.is_some() {
+        termination.max_samples = args.max_samples;
+    }
+    if let Some(max_time_secs) = args.max_time_secs {
+        termination.max_time = Some(Duration::from_secs(max_time_secs));
+    }
+    if args.target_relative_uncertainty.is_some() {
+        termination.target_relative_uncertainty = args.target_relative_uncertainty;
+    }
+    if !args.target_measures.is_empty() {
+        termination.target_measures = args.target_measures;
+    }
+
+    let defaults = RunConfig::default();
+    let config = RunConfig {
+        flush_interval,
+        threads: args.threads.unwrap_or(defaults.threads),
+        seed: args.seed,
+        max_export_errors_in_row: args.max_export_errors_in_row,
+        final_export_deadline: args
+            .final_export_deadline_secs
+            .map_or(defaults.final_export_deadline, Duration::from_secs),
+        checkpoint_path: args.checkpoint.map(PathBuf::from),
+        checkpoint_interval: args
+            .checkpoint_interval_secs
+            .map_or(defaults.checkpoint_interval, Duration::from_secs),
+        resume: args.resume.map(PathBuf::from),
+        params,
+        sweep,
+        node_index,
+        ..defaults
+    };
+    Ok(config)
+}
 
-    Parameters {
+/// Validates the configuration of the run and produces simulation parameters.
+pub fn construct_parameters(
+    name: String,
+    measures: Measures,
+    termination: Termination,
+    config: RunConfig,
+    exporter: Box<dyn Exporter>,
+) -> Result<Parameters, Error> {
+    if config.threads == 0 {
+        return Err(Error::Config(
+            "The number of threads (--threads) should be positive.".to_string(),
+        ));
+    }
+
+    let host = config.host.unwrap_or_else(host_id);
+    let seed = resolve_seed(config.seed, config.node_index, &host)?;
+
+    let resume = match config.resume {
+        Some(path) => {
+            let checkpoint = Checkpoint::load(&path).map_err(|err| {
+                Error::Config(format!(
+                    "Can't resume from the checkpoint (--resume): {}",
+                    err
+                ))
+            })?;
+            if checkpoint.simulation != name {
+                return Err(Error::Config(format!(
+                    "Checkpoint {} (--resume) belongs to a different simulation \"{}\".",
+                    path.display(),
+                    checkpoint.simulation
+                )));
+            }
+            Some(checkpoint)
+        }
+        None => None,
+    };
+
+    Ok(Parameters {
         name,
+        host,
+        seed,
         measures,
         exporter,
-        flush_interval,
-        max_export_errors_in_row,
+        flush_interval: config.flush_interval,
+        max_export_errors_in_row: config.max_export_errors_in_row,
+        threads: config.threads,
+        termination,
+        stop: config.stop,
+        final_export_deadline: config.final_export_deadline,
+        checkpoint_path: config.checkpoint_path,
+        checkpoint_interval: config.checkpoint_interval,
+        resume,
+        params: config.params,
+    })
+}
+
+/// Identifies the node in the exported data points. Uses the host name, which
+/// is unique for every pod in Kubernetes and every node in batch clusters.
+fn host_id() -> String {
+    if let Ok(hostname) = ::std::env::var("HOSTNAME") {
+        if !hostname.is_empty() {
+            return hostname;
+        }
+    }
+    match ::std::fs::read_to_string("/etc/hostname") {
+        Ok(ref hostname) if !hostname.trim().is_empty() => hostname.trim().to_string(),
+        _ => "unknown".to_string(),
+    }
+}
+
+/// Validates the seed given in the configuration, or derives one for `host`,
+/// and mixes `node_index` into it.
+fn resolve_seed(seed: Option<u64>, node_index: usize, host: &str) -> Result<u64, Error> {
+    let seed = match seed {
+        Some(seed) if seed > i64::MAX as u64 => {
+            return Err(Error::Config(
+                "The seed (--seed) should be below 2^63.".to_string(),
+            ))
+        }
+        Some(seed) => seed,
+        None => derive_seed(host),
+    };
+    Ok(mix_node_index(seed, node_index))
+}
+
+/// Derives the seed of the node with index `node_index` from the seed of the
+/// run with the SplitMix64 finalizer, so that nodes given the same seed run
+/// unrelated chains. The node 0 keeps the seed unchanged, so that rerunning a
+/// node with the seed recorded in its data points and its grid point given as
+/// plain parameters reproduces it. The result
+/// is truncated to 63 bits like the seed.
+fn mix_node_index(seed: u64, node_index: usize) -> u64 {
+    if node_index == 0 {
+        return seed;
     }
+    let mut z = seed ^ (node_index as u64).wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    (z ^ (z >> 31)) >> 1
 }
 
-pub fn run_simulation<S, F>(name: &str, reg: MeasureRegistry, measure_fn: F)
+/// Derives a seed from the host name and the current time, so that different
+/// nodes, as well as restarts of the same node, run different chains. The seed
+/// is truncated to 63 bits to fit into a signed integer in the data sinks.
+fn derive_seed(host: &str) -> u64 {
+    use std::hash::Hash;
+    use std::hash::Hasher;
+    let mut hasher = ::std::collections::hash_map::DefaultHasher::new();
+    host.hash(&mut hasher);
+    ::std::process::id().hash(&mut hasher);
+    if let Ok(since_epoch) = ::std::time::SystemTime::now().duration_since(::std::time::UNIX_EPOCH)
+    {
+        since_epoch.as_nanos().hash(&mut hasher);
+    }
+    hasher.finish() >> 1
+}
+
+/// Runs the simulation configured by the command line arguments. If the
+/// simulation fails, prints the error and exits the process with a non-zero
+/// code.
+pub fn run_simulation<S, F>(
+    name: &str,
+    reg: MeasureRegistry,
+    mut termination: Termination,
+    codec: Option<Codec<S>>,
+    measure_fn: F,
+) -> Vec<RunResult>
 where
     S: ::simulation::Sample,
-    F: Fn(&S, &mut Measures),
+    F: Fn(&S, &mut Measures) + Sync,
 {
-    let cmd_args = CmdArgs::from_args();
-    if cmd_args.production_mode {
-        ::simple_logger::init().expect("Failed to initialize logger");
+    let result = parse_args().and_then(|(cmd_args, params, sweep)| {
+        init_logger(cmd_args.production_mode(), name)?;
+        let exporter = construct_exporter(&cmd_args)?;
+        let config = construct_config(&mut termination, cmd_args, params, sweep)?;
+        install_signal_handlers(&config.stop)?;
+        run_with_config(name, reg, termination, codec, config, exporter, measure_fn)
+    });
+    match result {
+        Ok(results) => results,
+        Err(err) => exit_with_error(err),
+    }
+}
+
+/// Initializes the logger in production mode, or greets the user otherwise.
+fn init_logger(production_mode: bool, name: &str) -> Result<(), Error> {
+    if production_mode {
+        ::simple_logger::init()
+            .map_err(|err| Error::Config(format!("Failed to initialize the logger: {}", err)))
     } else {
         println!("Running ergothic simulation \"{}\".", name);
+        Ok(())
+    }
+}
+
+/// Prints the error and exits the process with the corresponding code.
+fn exit_with_error(err: Error) -> ! {
+    error!("{}", err);
+    eprintln!("{}", err);
+    ::std::process::exit(match err {
+        Error::Config(_) => EXIT_CODE_CONFIG_ERROR,
+        Error::Export(_) => EXIT_CODE_FINAL_EXPORT_FAILED,
+        Error::Sample(_) => EXIT_CODE_SAMPLE_ERROR,
+        Error::Panic(_) => EXIT_CODE_PANIC,
+    });
+}
+
+/// Runs the simulation with a programmatic configuration. Neither parses the
+/// command line arguments, nor initializes the logger, nor installs signal
+/// handlers. Returns the measures of every grid point of the sweep run by the
+/// node, in the order of the chains running them, or of the single point of
+/// the parameter space if there is no sweep.
+pub fn run_with_config<S, F>(
+    name: &str,
+    reg: MeasureRegistry,
+    termination: Termination,
+    codec: Option<Codec<S>>,
+    mut config: RunConfig,
+    mut exporter: Box<dyn Exporter>,
+    measure_fn: F,
+) -> Result<Vec<RunResult>, Error>
+where
+    S: ::simulation::Sample,
+    F: Fn(&S, &mut Measures) + Sync,
+{
+    // Every grid point gets measures of its own, sized by its parameters. The
+    // parameters of all grid points are validated before any of them runs, in
+    // the same way as `config_params` resolves them.
+    let mut groups = Vec::new();
+    for (threads, params) in grid_assignment(&config)? {
+        ::simulation::resolve_params::<S::Params>(params.clone())?;
+        let measures = reg.freeze_at(&params)?;
+        groups.push((threads, params, measures));
+    }
+    if groups.len() == 1 {
+        let (_, params, measures) = groups.swap_remove(0);
+        config.params = params;
+        let parameters =
+            construct_parameters(name.to_string(), measures, termination, config, exporter)?;
+        return ::simulation::run(parameters, codec, measure_fn).map(|result| vec![result]);
+    }
+    if config.checkpoint_path.is_some() || config.resume.is_some() {
+        return Err(Error::Config(
+            "Checkpoints require all threads of the node to run the same grid point of the \
+             sweep, give each node a grid point of its own with --node_index."
+                .to_string(),
+        ));
+    }
+
+    // The chains running each grid point are run as a separate simulation, in
+    // a thread of its own. Their data points are exported from this thread.
+    let host = config.host.take().unwrap_or_else(host_id);
+    let seed = resolve_seed(config.seed, config.node_index, &host)?;
+    let (requests, served) = ::std::sync::mpsc::channel();
+    let measure_fn = &measure_fn;
+    let results: Vec<Result<RunResult, Error>> = ::std::thread::scope(|scope| {
+        let runs: Vec<_> = groups
+            .into_iter()
+            .enumerate()
+            .map(|(position, (threads, params, measures))| {
+                // The node index is already mixed into the seed.
+                let config = RunConfig {
+                    threads,
+                    host: Some(host.clone()),
+                    seed: Some(seed.wrapping_add(position as u64) & i64::MAX as u64),
+                    params,
+                    node_index: 0,
+                    ..config.clone()
+                };
+                let termination = termination.clone();
+                let exporter = ForwardingExporter::new(requests.clone());
+                scope.spawn(move || {
+                    let parameters = construct_parameters(
+                        name.to_string(),
+                        measures,
+                        termination,
+                        config,
+                        Box::new(exporter),
+                    )?;
+                    ::simulation::run::<S, _>(parameters, None, measure_fn)
+                })
+            })
+            .collect();
+        // Serve the exports until all runs have finished.
+        drop(requests);
+        for (data_point, reply) in served {
+            let _ = reply.send(exporter.export(&data_point));
+        }
+        runs.into_iter()
+            .map(|run| run.join().unwrap_or_else(|panic| Err(panic_error(panic))))
+            .collect()
+    });
+    // The first error fails the whole run.
+    results.into_iter().collect()
+}
+
+/// Converts the payload of the panic of a grid point into an error, keeping the
+/// panic message.
+fn panic_error(panic: Box<dyn Any + Send>) -> Error {
+    let message = match panic.downcast::<String>() {
+        Ok(message) => *message,
+        Err(panic) => match panic.downcast_ref::<&str>() {
+            Some(message) => message.to_string(),
+            None => "unknown panic".to_string(),
+        },
+    };
+    Error::Panic(message)
+}
+
+/// Makes SIGTERM, SIGINT and SIGQUIT stop the simulation gracefully by raising the
+/// `stop` flag. Receiving a second signal while stopping terminates the process
+/// immediately.
+fn install_signal_handlers(stop: &Arc<AtomicBool>) -> Result<(), Error> {
+    use signal_hook::consts::TERM_SIGNALS;
+    let failed = |err| Error::Config(format!("Failed to install a signal handler: {}", err));
+    for signal in TERM_SIGNALS {
+        // The order matters: the conditional shutdown only fires if the flag
+        // has been raised by a previous signal.
+        ::signal_hook::flag::register_conditional_shutdown(*signal, 1, stop.clone())
+            .map_err(failed)?;
+        ::signal_hook::flag::register(*signal, stop.clone()).map_err(failed)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::atomic::Ordering;
+
+    /// Parameters of a lattice simulation.
+    #[derive(Debug, Deserialize, PartialEq, Serialize)]
+    #[serde(deny_unknown_fields)]
+    struct Lattice {
+        size: usize,
+        #[serde(default)]
+        beta: f64,
+    }
+
+    #[test]
+    fn config_params_resolves_grid_points_of_node() {
+        let mut config = RunConfig::default();
+        config
+            .params
+            .insert("size".to_string(), ::serde_json::json!(16));
+        assert_eq!(
+            config_params::<Lattice>(&config).unwrap(),
+            vec![Lattice {
+                size: 16,
+                beta: 0.0
+            }]
+        );
+
+        config.sweep.insert(
+            "beta".to_string(),
+            vec![
+                ::serde_json::json!(5.6),
+                ::serde_json::json!(5.7),
+                ::serde_json::json!(5.8),
+            ],
+        );
+        config.threads = 2;
+        config.node_index = 1;
+        let params = config_params::<Lattice>(&config).unwrap();
+        let betas: Vec<f64> = params.iter().map(|params| params.beta).collect();
+        assert_eq!(betas, vec![5.8, 5.6]);
+    }
+
+    #[test]
+    fn config_params_rejects_invalid_params() {
+        let mut config = RunConfig::default();
+        config
+            .params
+            .insert("size".to_string(), ::serde_json::json!(16));
+        config
+            .params
+            .insert("sise".to_string(), ::serde_json::json!(8));
+        match config_params::<Lattice>(&config) {
+            Err(Error::Config(err)) => assert!(err.contains("sise"), "{}", err),
+            _ => panic!("Accepted an unknown parameter."),
+        }
+        config.sweep.insert("beta".to_string(), Vec::new());
+        assert!(config_params::<Lattice>(&config).is_err());
+    }
+
+    /// Parses the command line arguments `args` with the configuration file
+    /// `contents` given with `--config`.
+    fn args_with_file(contents: &str, args: &[&str]) -> (CmdArgs, BTreeMap<String, Value>) {
+        static FILES: AtomicUsize = AtomicUsize::new(0);
+        let path = ::std::env::temp_dir().join(format!(
+            "ergothic-args-{}-{}.toml",
+            ::std::process::id(),
+            FILES.fetch_add(1, Ordering::Relaxed)
+        ));
+        ::std::fs::write(&path, contents).unwrap();
+        let path = path.to_str().unwrap();
+        let mut args =
+            CmdArgs::from_iter(["simulation", "--config", path].iter().chain(args.iter()));
+        let (params, _) = args.apply_config_file().unwrap();
+        let params = args.collect_params(params).unwrap();
+        (args, params)
+    }
+
+    #[test]
+    fn command_line_overrides_config_file() {
+        let file = "threads = 4\nseed = 1\nmongo = \"mongodb://localhost\"\n\
+                    mongo_db = \"db\"\n\n[params]\nsize = 16\nbeta = 5.6\n";
+        let (args, params) = args_with_file(file, &[]);
+        assert_eq!((args.threads, args.seed), (Some(4), Some(1)));
+        assert_eq!(args.mongo.as_ref().unwrap(), "mongodb://localhost");
+        assert_eq!(params["beta"], ::serde_json::json!(5.6));
+
+        let (args, params) = args_with_file(
+            file,
+            &[
+                "--threads",
+                "8",
+                "--output_file",
+                "out.json",
+                "--param",
+                "beta=5.7",
+            ],
+        );
+        assert_eq!((args.threads, args.seed), (Some(8), Some(1)));
+        // The exporter of the file is replaced as a whole.
+        assert_eq!((args.mongo, args.mongo_db), (None, None));
+        assert_eq!(args.output_file.unwrap(), "out.json");
+        assert_eq!(params["size"], ::serde_json::json!(16));
+        assert_eq!(params["beta"], ::serde_json::json!(5.7));
+    }
+
+    #[test]
+    fn production_false_overrides_config_file() {
+        let production = |file: &str, args: &[&str]| args_with_file(file, args).0.production_mode();
+        assert!(production("production = true\n", &[]));
+        assert!(!production(
+            "production = true\n",
+            &["--production", "false"]
+        ));
+        assert!(production("production = false\n", &["--production"]));
+        assert!(production("", &["--production", "true"]));
+        assert!(!production("", &[]));
+    }
+
+    #[test]
+    fn signals_raise_stop_flag() {
+        let stop = Arc::new(AtomicBool::new(false));
+        install_signal_handlers(&stop).unwrap();
+        ::signal_hook::low_level::raise(::signal_hook::consts::SIGTERM).unwrap();
+        assert!(stop.load(Ordering::Relaxed));
     }
-    let parameters = construct_parameters(name.to_string(), reg.freeze(), cmd_args);
-    ::simulation::run(parameters, measure_fn);
 }
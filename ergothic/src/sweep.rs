@@ -0,0 +1,110 @@
+use error::Error;
+use serde_json::Value;
+use std::collections::BTreeMap;
+
+/// Grid points of a sweep: the cartesian product of the values of the swept
+/// parameters. The parameters are taken in the alphabetical order, the last one
+/// changing the fastest. Without swept parameters, the grid consists of a
+/// single empty point.
+pub fn grid_points(
+    sweep: &BTreeMap<String, Vec<Value>>,
+) -> Result<Vec<BTreeMap<String, Value>>, Error> {
+    let mut points = vec![BTreeMap::new()];
+    for (name, values) in sweep.iter() {
+        if values.is_empty() {
+            return Err(Error::Config(format!(
+                "The swept parameter '{}' (--sweep) should have at least one value.",
+                name
+            )));
+        }
+        points = points
+            .into_iter()
+            .flat_map(|point| {
+                values.iter().map(move |value| {
+                    let mut point = point.clone();
+                    point.insert(name.clone(), value.clone());
+                    point
+                })
+            })
+            .collect();
+    }
+    Ok(points)
+}
+
+/// Assigns grid points to the chains of a node round-robin, continuing from the
+/// chains of the nodes with lower indices: chain `c` of node `node_index` runs
+/// grid point `(node_index * threads + c) % num_of_points`. Returns the grid
+/// points run by the node, in the order of their first chains, together with
+/// the number of chains running each of them.
+pub fn assign(num_of_points: usize, node_index: usize, threads: usize) -> Vec<(usize, usize)> {
+    let mut groups: Vec<(usize, usize)> = Vec::new();
+    for chain in 0..threads {
+        let point = (node_index.wrapping_mul(threads).wrapping_add(chain)) % num_of_points;
+        match groups.iter_mut().find(|group| group.0 == point) {
+            Some(group) => group.1 += 1,
+            None => groups.push((point, 1)),
+        }
+    }
+    groups
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn grid_points_are_cartesian_product() {
+        assert_eq!(
+            grid_points(&BTreeMap::new()).unwrap(),
+            vec![BTreeMap::new()]
+        );
+
+        let mut sweep = BTreeMap::new();
+        sweep.insert("size".to_string(), vec![json!(8), json!(16)]);
+        sweep.insert("beta".to_string(), vec![json!(5.6), json!(5.7), json!(5.8)]);
+        let points: Vec<(Value, Value)> = grid_points(&sweep)
+            .unwrap()
+            .into_iter()
+            .map(|point| (point["beta"].clone(), point["size"].clone()))
+            .collect();
+        // The last parameter in the alphabetical order changes the fastest.
+        assert_eq!(
+            points,
+            vec![
+                (json!(5.6), json!(8)),
+                (json!(5.6), json!(16)),
+                (json!(5.7), json!(8)),
+                (json!(5.7), json!(16)),
+                (json!(5.8), json!(8)),
+                (json!(5.8), json!(16)),
+            ]
+        );
+
+        sweep.insert("mass".to_string(), Vec::new());
+        match grid_points(&sweep) {
+            Err(Error::Config(err)) => assert!(err.contains("'mass'"), "{}", err),
+            _ => panic!("Accepted a swept parameter without values."),
+        }
+    }
+
+    #[test]
+    fn assigns_grid_points_round_robin() {
+        // A grid point per node.
+        for node_index in 0..4 {
+            assert_eq!(assign(4, node_index, 1), vec![(node_index, 1)]);
+        }
+        // The chains of a node continue from the chains of the previous nodes.
+        assert_eq!(assign(5, 0, 3), vec![(0, 1), (1, 1), (2, 1)]);
+        assert_eq!(assign(5, 1, 3), vec![(3, 1), (4, 1), (0, 1)]);
+        // More chains than grid points.
+        assert_eq!(assign(2, 0, 5), vec![(0, 3), (1, 2)]);
+        assert_eq!(assign(1, 3, 4), vec![(0, 4)]);
+        // More nodes than grid points: the nodes wrap around.
+        let points: Vec<usize> = (0..7)
+            .map(|node_index| assign(3, node_index, 1)[0].0)
+            .collect();
+        assert_eq!(points, vec![0, 1, 2, 0, 1, 2, 0]);
+        assert_eq!(assign(3, 5, 2), vec![(1, 1), (2, 1)]);
+    }
+}
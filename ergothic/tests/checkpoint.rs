@@ -0,0 +1,392 @@
+extern crate ergothic;
+extern crate serde_json;
+#[macro_use]
+extern crate serde_derive;
+
+use ergothic::rand::Rng;
+use ergothic::DataPoint;
+use ergothic::ExportError;
+use ergothic::Exporter;
+use ergothic::RunConfig;
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::sync::Mutex;
+
+/// Parameters of the walk.
+#[derive(Serialize, Deserialize)]
+struct WalkParams {
+    /// Length of a step.
+    #[serde(default = "default_step")]
+    step: i64,
+}
+
+fn default_step() -> i64 {
+    1
+}
+
+/// A random walk, counting its steps.
+#[derive(Serialize, Deserialize)]
+struct Walker {
+    x: i64,
+    step: i64,
+    steps: u64,
+
+    /// Whether the walker has been prepared in this run, rather than restored
+    /// from a checkpoint.
+    #[serde(skip)]
+    prepared: bool,
+}
+
+impl ergothic::Sample for Walker {
+    type Params = WalkParams;
+
+    fn prepare<R: Rng>(params: &WalkParams, _rng: &mut R) -> Walker {
+        Walker {
+            x: 0,
+            step: params.step,
+            steps: 0,
+            prepared: true,
+        }
+    }
+
+    fn mutate<R: Rng>(&mut self, rng: &mut R) {
+        self.x += if rng.gen::<bool>() {
+            self.step
+        } else {
+            -self.step
+        };
+        self.steps += 1;
+    }
+}
+
+impl ergothic::Checkpointable for Walker {}
+
+/// Keeps the exported data points.
+#[derive(Clone, Default)]
+struct Collector(Arc<Mutex<Vec<DataPoint>>>);
+
+impl Exporter for Collector {
+    fn export(&mut self, data_point: &DataPoint) -> Result<(), ExportError> {
+        self.0.lock().unwrap().push(data_point.clone());
+        Ok(())
+    }
+}
+
+/// Path of a checkpoint file of a test.
+fn checkpoint_path(name: &str) -> PathBuf {
+    let path = ::std::env::temp_dir().join(format!(
+        "ergothic-checkpoint-{}-{}.json",
+        name,
+        ::std::process::id()
+    ));
+    let _ = ::std::fs::remove_file(&path);
+    path
+}
+
+/// Runs the walk for `max_samples` samples with a given configuration.
+/// Returns the number of samples drawn by walkers prepared in this run, the
+/// number of samples drawn by restored walkers, and the smallest number of
+/// steps of a restored walker.
+fn walk(
+    name: &str,
+    max_samples: u64,
+    config: RunConfig,
+) -> Result<(u64, u64, f64), ergothic::Error> {
+    let mut sim = ergothic::Simulation::new(name);
+    let prepared = sim.add_rate_measure("Prepared");
+    sim.stop_after_samples(max_samples);
+    let min_steps = Mutex::new(f64::INFINITY);
+    let results =
+        sim.run_checkpointable_with(config, Box::new(Collector::default()), |s: &Walker, ms| {
+            ms.accumulate_outcome(prepared, s.prepared);
+            if !s.prepared {
+                let mut min_steps = min_steps.lock().unwrap();
+                *min_steps = min_steps.min(s.steps as f64);
+            }
+        })?;
+    let acc = &results[0].measures.get_rate(prepared).acc;
+    Ok((
+        acc.num_of_successes(),
+        acc.num_of_trials() - acc.num_of_successes(),
+        min_steps.into_inner().unwrap(),
+    ))
+}
+
+#[test]
+fn resumes_from_checkpoint() {
+    let path = checkpoint_path("resume");
+    let config = RunConfig {
+        threads: 2,
+        seed: Some(1),
+        checkpoint_path: Some(path.clone()),
+        ..RunConfig::default()
+    };
+    let (prepared, restored, _) = walk("Walk", 1000, config.clone()).unwrap();
+    assert_eq!((prepared, restored), (1000, 0));
+
+    let checkpoint: serde_json::Value =
+        serde_json::from_str(&::std::fs::read_to_string(&path).unwrap()).unwrap();
+    assert_eq!(checkpoint["simulation"], "Walk");
+    let samples = checkpoint["samples"].as_array().unwrap();
+    assert_eq!(samples.len(), 2);
+    let saved_steps: u64 = samples.iter().map(|s| s["steps"].as_u64().unwrap()).sum();
+    // Each walker has also been thermalized.
+    assert!(saved_steps >= 1000, "{}", saved_steps);
+
+    let resumed = RunConfig {
+        seed: Some(2),
+        resume: Some(path.clone()),
+        ..config
+    };
+    let (prepared, restored, min_steps) = walk("Walk", 1000, resumed).unwrap();
+    assert_eq!((prepared, restored), (0, 1000));
+    assert!(min_steps > 1.0, "{}", min_steps);
+}
+
+#[test]
+fn prepares_chains_missing_from_checkpoint() {
+    let path = checkpoint_path("null");
+    let config = RunConfig {
+        seed: Some(1),
+        checkpoint_path: Some(path.clone()),
+        ..RunConfig::default()
+    };
+    walk("Walk", 100, config.clone()).unwrap();
+
+    // A chain that hadn't finished thermalizing is saved as null.
+    let mut checkpoint: serde_json::Value =
+        serde_json::from_str(&::std::fs::read_to_string(&path).unwrap()).unwrap();
+    checkpoint["samples"] = serde_json::json!([null]);
+    ::std::fs::write(&path, checkpoint.to_string()).unwrap();
+
+    let resumed = RunConfig {
+        seed: Some(2),
+        resume: Some(path.clone()),
+        ..config
+    };
+    let (prepared, restored, _) = walk("Walk", 100, resumed).unwrap();
+    assert_eq!((prepared, restored), (100, 0));
+}
+
+#[test]
+fn rejects_checkpoint_of_another_simulation() {
+    let path = checkpoint_path("mismatch");
+    let config = RunConfig {
+        checkpoint_path: Some(path.clone()),
+        ..RunConfig::default()
+    };
+    walk("Walk", 100, config).unwrap();
+
+    let resumed = RunConfig {
+        resume: Some(path.clone()),
+        ..RunConfig::default()
+    };
+    match walk("Run", 100, resumed) {
+        Err(ergothic::Error::Config(err)) => {
+            assert!(err.contains("different simulation"), "{}", err)
+        }
+        Err(err) => panic!("Unexpected error: {}", err),
+        Ok(_) => panic!("Resumed from a checkpoint of another simulation."),
+    }
+}
+
+#[test]
+fn rejects_checkpoint_with_other_params() {
+    let path = checkpoint_path("params");
+    let config = RunConfig {
+        checkpoint_path: Some(path.clone()),
+        ..RunConfig::default()
+    };
+    walk("Walk", 100, config).unwrap();
+    let checkpoint: serde_json::Value =
+        serde_json::from_str(&::std::fs::read_to_string(&path).unwrap()).unwrap();
+    // The defaults are recorded as well.
+    assert_eq!(checkpoint["params"], serde_json::json!({"step": 1}));
+
+    let mut params = BTreeMap::new();
+    params.insert("step".to_string(), serde_json::json!(1));
+    let same = RunConfig {
+        resume: Some(path.clone()),
+        params: params.clone(),
+        ..RunConfig::default()
+    };
+    walk("Walk", 100, same).unwrap();
+
+    params.insert("step".to_string(), serde_json::json!(2));
+    let other = RunConfig {
+        resume: Some(path.clone()),
+        params,
+        ..RunConfig::default()
+    };
+    match walk("Walk", 100, other) {
+        Err(ergothic::Error::Config(err)) => {
+            assert!(err.contains("different parameters"), "{}", err)
+        }
+        Err(err) => panic!("Unexpected error: {}", err),
+        Ok(_) => panic!("Resumed from a checkpoint with other parameters."),
+    }
+}
+
+#[test]
+fn continues_random_numbers_of_chains() {
+    // Reads the walker saved in a checkpoint.
+    let saved_walker = |path: &PathBuf| {
+        let checkpoint: serde_json::Value =
+            serde_json::from_str(&::std::fs::read_to_string(path).unwrap()).unwrap();
+        assert!(!checkpoint["rngs"][0].is_null());
+        assert!(!checkpoint["seeder"].is_null());
+        checkpoint["samples"][0].clone()
+    };
+    let config = |path: &PathBuf| RunConfig {
+        threads: 1,
+        seed: Some(1),
+        checkpoint_path: Some(path.clone()),
+        ..RunConfig::default()
+    };
+
+    let path = checkpoint_path("straight");
+    walk("Walk", 2000, config(&path)).unwrap();
+    let straight = saved_walker(&path);
+
+    let path = checkpoint_path("continued");
+    walk("Walk", 1000, config(&path)).unwrap();
+    saved_walker(&path);
+    let resumed = RunConfig {
+        resume: Some(path.clone()),
+        ..config(&path)
+    };
+    walk("Walk", 1000, resumed).unwrap();
+    let continued = saved_walker(&path);
+
+    // Resumed with the same seed, the walk doesn't replay the first half, but
+    // ends where the uninterrupted walk does.
+    assert_eq!(continued, straight);
+}
+
+#[test]
+fn returns_measures_of_every_grid_point() {
+    let mut sim = ergothic::Simulation::new("Walk");
+    let long_steps = sim.add_rate_measure("Long steps");
+    sim.stop_after_samples(100);
+    let mut sweep = BTreeMap::new();
+    sweep.insert(
+        "step".to_string(),
+        vec![serde_json::json!(1), serde_json::json!(3)],
+    );
+    let config = RunConfig {
+        threads: 2,
+        seed: Some(1),
+        sweep,
+        ..RunConfig::default()
+    };
+    let results = sim
+        .run_checkpointable_with(config, Box::new(Collector::default()), |s: &Walker, ms| {
+            ms.accumulate_outcome(long_steps, s.step > 1);
+        })
+        .unwrap();
+    assert_eq!(results.len(), 2);
+    for (result, &step) in results.iter().zip(&[1, 3]) {
+        assert_eq!(result.params["step"], serde_json::json!(step));
+        let acc = &result.measures.get_rate(long_steps).acc;
+        assert_eq!(acc.num_of_trials(), 100);
+        assert_eq!(acc.num_of_successes(), if step > 1 { 100 } else { 0 });
+    }
+}
+
+#[test]
+fn reports_panicking_grid_points() {
+    let mut sim = ergothic::Simulation::new("Walk");
+    sim.stop_after_samples(100);
+    let mut sweep = BTreeMap::new();
+    sweep.insert(
+        "step".to_string(),
+        vec![serde_json::json!(1), serde_json::json!(3)],
+    );
+    let config = RunConfig {
+        threads: 2,
+        seed: Some(1),
+        sweep,
+        ..RunConfig::default()
+    };
+    let result =
+        sim.run_checkpointable_with(config, Box::new(Collector::default()), |s: &Walker, _ms| {
+            if s.step > 1 {
+                panic!("Step too long");
+            }
+        });
+    match result {
+        Err(ergothic::Error::Panic(err)) => assert!(err.contains("Step too long"), "{}", err),
+        Err(err) => panic!("Unexpected error: {}", err),
+        Ok(_) => panic!("Ignored a panicking grid point."),
+    }
+}
+
+#[test]
+fn validates_params_of_every_grid_point_before_running() {
+    let mut sim = ergothic::Simulation::new("Walk");
+    sim.stop_after_samples(100);
+    let mut sweep = BTreeMap::new();
+    sweep.insert(
+        "step".to_string(),
+        vec![serde_json::json!(1), serde_json::json!("long")],
+    );
+    let config = RunConfig {
+        threads: 2,
+        sweep,
+        ..RunConfig::default()
+    };
+    match sim.params_with::<WalkParams>(&config) {
+        Err(ergothic::Error::Config(err)) => assert!(err.contains("long"), "{}", err),
+        Err(err) => panic!("Unexpected error: {}", err),
+        Ok(_) => panic!("Resolved invalid parameters."),
+    }
+    let collector = Collector::default();
+    let result =
+        sim.run_checkpointable_with(config, Box::new(collector.clone()), |_: &Walker, _| {});
+    match result {
+        Err(ergothic::Error::Config(err)) => assert!(err.contains("long"), "{}", err),
+        Err(err) => panic!("Unexpected error: {}", err),
+        Ok(_) => panic!("Ran with invalid parameters."),
+    }
+    assert!(collector.0.lock().unwrap().is_empty());
+}
+
+#[test]
+fn sizes_vector_measures_at_every_grid_point() {
+    let mut sim = ergothic::Simulation::new("Walk");
+    let steps = sim.add_vector_measure_with("Steps", |params: &WalkParams| params.step as usize);
+    sim.stop_after_samples(10);
+    let mut sweep = BTreeMap::new();
+    sweep.insert(
+        "step".to_string(),
+        vec![
+            serde_json::json!(1),
+            serde_json::json!(3),
+            serde_json::json!(2),
+        ],
+    );
+    let config = RunConfig {
+        threads: 2,
+        node_index: 1,
+        sweep,
+        ..RunConfig::default()
+    };
+    // Chains 2 and 3 of the sweep run the grid points 2 and 0.
+    let params: Vec<WalkParams> = sim.params_with(&config).unwrap();
+    let params: Vec<i64> = params.iter().map(|params| params.step).collect();
+    assert_eq!(params, vec![2, 1]);
+    let results = sim
+        .run_checkpointable_with(config, Box::new(Collector::default()), |s: &Walker, ms| {
+            let components = vec![1.0; s.step as usize];
+            ms.accumulate_slice(steps, &components);
+        })
+        .unwrap();
+    assert_eq!(results.len(), 2);
+    for (result, &step) in results.iter().zip(&params) {
+        assert_eq!(result.params["step"], serde_json::json!(step));
+        let acc = &result.measures.get_vector(steps).acc;
+        assert_eq!(acc.len(), step as usize);
+        assert_eq!(acc.num_of_samples(), 10.0);
+    }
+}
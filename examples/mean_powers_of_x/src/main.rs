@@ -19,21 +19,26 @@ extern crate ergothic;
 extern crate pretty_env_logger;
 extern crate rand;
 
+use rand::Rng;
+
 // MySample is a configuration sample describing the system under consideration.
 // Here it only has a single value `x`.
 struct MySample {
   x: f64,
-  rng: rand::rngs::ThreadRng,
   unif: rand::distributions::Uniform<f64>,
 }
 
 impl ergothic::Sample for MySample {
+  // The simulation has no physics parameters.
+  type Params = ergothic::NoParams;
+
   // Prepare a randomized configuration. In our simple case, setting initial `x`
   // to zero is enough.
-  fn prepare() -> MySample {
+  // All random numbers are drawn from `rng`, which is seeded by the simulation
+  // engine. Running with the same `--seed` reproduces the simulation exactly.
+  fn prepare<R: Rng>(_params: &ergothic::NoParams, _rng: &mut R) -> MySample {
     MySample {
       x: 0.0,
-      rng: rand::thread_rng(),
       unif: rand::distributions::Uniform::new_inclusive(0.0, 1.0),
     }
   }
@@ -44,18 +49,18 @@ impl ergothic::Sample for MySample {
   // Thermalization tries to get rid of this bias. Typically, this function
   // usually calls mutate ~10-20 times. Here, it is only necessary to call it
   // once.
-  fn thermalize(&mut self) {
-    self.mutate();
+  fn thermalize<R: Rng>(&mut self, rng: &mut R) {
+    self.mutate(rng);
   }
 
   // The main function which drives the simulation engine. Applies a randomized
   // mutation to the sample, thus making a single "step" in the configuration
   // spaces. The walk is assumed to be ergodic (in simple words, mutate is
   // assumed to not have any consistent bias.
-  fn mutate(&mut self) {
+  fn mutate<R: Rng>(&mut self, rng: &mut R) {
     use rand::distributions::Distribution;
     // Set x to a random value in range [0.0, 1.0].
-    self.x = self.unif.sample(&mut self.rng);
+    self.x = self.unif.sample(rng);
   }
 }
 
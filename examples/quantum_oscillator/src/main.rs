@@ -1,57 +1,75 @@
 extern crate ergothic;
 extern crate rand;
+#[macro_use]
+extern crate serde_derive;
 
-const N: usize = 30;  // Lattice size.
-const A: f64 = 0.5;   // Lattice spacing.
-const M: f64 = 1.0;   // Oscillator mass.
-const K: f64 = 1.0;   // Oscillator spring tension.
+use rand::Rng;
 
-// Potential energy V(x).
-fn potential_v(x: f64) -> f64 {
-  K * x.powi(2) / 2.0
+// Physics parameters of the simulation. Each of them can be set with e.g.
+// `--param n=60`, or in the `params` table of a `--config` file.
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct Params {
+  n: usize,  // Lattice size.
+  a: f64,    // Lattice spacing.
+  m: f64,    // Oscillator mass.
+  k: f64,    // Oscillator spring tension.
+}
+
+impl Default for Params {
+  fn default() -> Params {
+    Params { n: 30, a: 0.5, m: 1.0, k: 1.0 }
+  }
 }
 
 // Trajectory of the oscillator: X(t) function.
 struct Trajectory {
+  params: Params,
   x: Vec<f64>,
 }
 
 impl Trajectory {
+  // Potential energy V(x).
+  fn potential_v(&self, x: f64) -> f64 {
+    self.params.k * x.powi(2) / 2.0
+  }
+
   // Euclidean Lagrangian function for i-th link (between nodes i and i+1).
   fn lagrangian(&self, i: usize) -> f64 {
-    assert!(i < N,
+    let Params { n, a, m, .. } = self.params;
+    assert!(i < n,
             "Trajectory::lagrangian(..): index {} out of range [0, {}).",
-            i, N);
-    let j = (i + 1) % N;
-    let kinetic = M * (self.x[j] - self.x[i]).powi(2) / (2.0 * A);
-    let potential = A * potential_v((self.x[i] + self.x[j]) / 2.0);
+            i, n);
+    let j = (i + 1) % n;
+    let kinetic = m * (self.x[j] - self.x[i]).powi(2) / (2.0 * a);
+    let potential = a * self.potential_v((self.x[i] + self.x[j]) / 2.0);
     // Euclidean signature, thus "+".
     kinetic + potential
   }
 
   // The part of the action that changes when the i-th node is mutated.
   fn contact_action(&self, i: usize) -> f64 {
-    self.lagrangian(i) + self.lagrangian((i + N - 1) % N)
+    let n = self.params.n;
+    self.lagrangian(i) + self.lagrangian((i + n - 1) % n)
   }
 
-  fn randomize(&mut self, n_times: usize) {
+  fn randomize<R: Rng>(&mut self, n_times: usize, rng: &mut R) {
     use rand::distributions::Distribution;
-    let mut rng = rand::prelude::thread_rng();
     let epsilon = 15.0;
     let uniform = rand::distributions::Uniform::<f64>
                       ::new_inclusive(-epsilon, epsilon);
     let uniform_prob = rand::distributions::Uniform::<f64>
                            ::new_inclusive(0.0, 1.0);
     for _ in 0..n_times {
-      for i in 0..N {
+      for i in 0..self.params.n {
         let old_x = self.x[i];
         let old_s = self.contact_action(i);
-        self.x[i] = uniform.sample(&mut rng);
+        self.x[i] = uniform.sample(rng);
         let new_s = self.contact_action(i);
         let ds = new_s - old_s;
         if ds > 0.0 {
           // Metropolis-Hastings probabilistic step.
-          let eta = uniform_prob.sample(&mut rng);
+          let eta = uniform_prob.sample(rng);
           if (-ds).exp() <= eta {
             // Restore the old value.
             self.x[i] = old_x;
@@ -63,37 +81,41 @@ impl Trajectory {
 }
 
 impl ergothic::Sample for Trajectory {
-  fn prepare() -> Trajectory {
+  type Params = Params;
+
+  fn prepare<R: Rng>(params: &Params, _rng: &mut R) -> Trajectory {
     Trajectory {
-      x: vec![0.0; N],
+      params: params.clone(),
+      x: vec![0.0; params.n],
     }
   }
 
-  fn thermalize(&mut self) {
-    self.randomize(500);
+  fn thermalize<R: Rng>(&mut self, rng: &mut R) {
+    self.randomize(500, rng);
   }
 
-  fn mutate(&mut self) {
-    self.randomize(20);
+  fn mutate<R: Rng>(&mut self, rng: &mut R) {
+    self.randomize(20, rng);
   }
 }
 
 fn main() {
   let mut sim = ergothic::Simulation::new("Oscillator");
-  // g[k] is the mean value of <X_i X_(i+k)> over i and over samples.
-  let mut g = Vec::new();
-  for i in 0..N {
-    g.push(sim.add_measure(format!("G({})", i)));
-  }
-  sim.run(|s: &Trajectory, ms| {
-    for k in 0..N {
-      let mut g_k = 0.0;
+  // g[k] is the mean value of <X_i X_(i+k)> over i and over samples. All
+  // components are measured on the same samples and are strongly correlated,
+  // so they are recorded as a single vector measure keeping their covariance.
+  // The number of components depends on the lattice size.
+  let g = sim.add_vector_measure_with("G", |params: &Params| params.n);
+  sim.run(move |s: &Trajectory, ms| {
+    let n = s.params.n;
+    let mut g_k = vec![0.0; n];
+    for (k, g_k) in g_k.iter_mut().enumerate() {
       // Computing correlator $g[k] = N^{-1} \sum_i \left< X_i X_{i+k} \right>$.
-      for i in 0..N {
-        g_k += s.x[i] * s.x[(i + k) % N];
+      for i in 0..n {
+        *g_k += s.x[i] * s.x[(i + k) % n];
       }
-      g_k /= N as f64;
-      ms.accumulate(g[k], g_k);
+      *g_k /= n as f64;
     }
+    ms.accumulate_slice(g, &g_k);
   });
 }